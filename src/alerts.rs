@@ -0,0 +1,247 @@
+use chrono::Utc;
+use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    io::Write,
+    process::{Command, Stdio},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    db::entity::{prelude::*, *},
+    metrics::Metrics,
+    stats::StatsConnect,
+};
+
+const EVALUATE_INTERVAL: u64 = 30;
+const VIR_DOMAIN_SHUTOFF: u32 = 5;
+const VIR_DOMAIN_CRASHED: u32 = 6;
+const VIR_DOMAIN_SHUTOFF_CRASHED: u32 = 3;
+
+pub const ALERT_KINDS: [&str; 4] = [
+    "domain-crashed",
+    "host-disk-usage",
+    "host-memory-usage",
+    "sched-job-failed",
+];
+pub const WEBHOOK_FORMATS: [&str; 2] = ["json", "slack"];
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Alert {
+    pub rule_id: i32,
+    pub rule_name: String,
+    pub kind: String,
+    // the domain or mount point the rule matched
+    pub subject: String,
+    pub value: f64,
+    pub threshold: f64,
+    // unix seconds the condition was first seen
+    pub since: i64,
+    // notified once the condition held for the rule's duration
+    pub firing: bool,
+}
+
+// a default one holds no alerts and evaluates nothing until run
+#[derive(Clone, Default)]
+pub struct AlertManager {
+    // keyed by rule id and subject, so every alert is notified once until it resolves
+    alerts: Arc<Mutex<HashMap<(i32, String), Alert>>>,
+}
+
+// subjects currently breaking the rule, with the value that broke it
+fn evaluate(
+    rule: &alert_rules::Model,
+    stats: &StatsConnect,
+    metrics: &Metrics,
+) -> Vec<(String, f64)> {
+    let matches_target = |subject: &str| rule.target.as_deref().is_none_or(|it| it == subject);
+    match rule.kind.as_str() {
+        // qemu usually exits on a guest crash, leaving the domain shut off for that reason
+        "domain-crashed" => stats
+            .domain_state_reasons()
+            .into_iter()
+            .filter(|(dom_name, (state, reason))| {
+                (*state == VIR_DOMAIN_CRASHED
+                    || (*state == VIR_DOMAIN_SHUTOFF && *reason == VIR_DOMAIN_SHUTOFF_CRASHED))
+                    && matches_target(dom_name)
+            })
+            .map(|(dom_name, (state, _))| (dom_name, state as f64))
+            .collect(),
+        "host-disk-usage" => stats
+            .host_latest()
+            .map(|host| {
+                host.filesystems
+                    .iter()
+                    .filter(|it| it.total_bytes > 0 && matches_target(&it.mount_point))
+                    .map(|it| {
+                        let used = it.total_bytes - it.available_bytes;
+                        (
+                            it.mount_point.clone(),
+                            used as f64 * 100.0 / it.total_bytes as f64,
+                        )
+                    })
+                    .filter(|(_, usage)| *usage >= rule.threshold)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        "host-memory-usage" => stats
+            .host_latest()
+            .filter(|host| host.total_memory > 0)
+            .map(|host| host.used_memory as f64 * 100.0 / host.total_memory as f64)
+            .filter(|usage| *usage >= rule.threshold)
+            .map(|usage| vec![("host".to_string(), usage)])
+            .unwrap_or_default(),
+        "sched-job-failed" => metrics
+            .failing_jobs()
+            .into_iter()
+            .filter(|dom_name| matches_target(dom_name))
+            .map(|dom_name| (dom_name, 1.0))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn payload(rule: &alert_rules::Model, alert: &Alert, status: &str) -> String {
+    match rule.webhook_format.as_str() {
+        "slack" => json!({
+            "text": format!(
+                "[{}] {}: {} on {} is {:.1} (threshold {})",
+                status.to_uppercase(),
+                rule.name,
+                rule.kind,
+                alert.subject,
+                alert.value,
+                rule.threshold
+            )
+        })
+        .to_string(),
+        _ => {
+            let mut body = serde_json::to_value(alert).unwrap();
+            body["status"] = json!(status);
+            body["timestamp"] = json!(Utc::now().timestamp());
+            body.to_string()
+        }
+    }
+}
+
+// curl is already on every host we run on and handles https
+pub fn post_webhook(url: &str, body: &str) -> Result<String, std::io::Error> {
+    let mut child = Command::new("curl")
+        .args(["-sS", "--fail", "--max-time", "10", "-X", "POST"])
+        .args(["-H", "Content-Type: application/json"])
+        .args(["--data-binary", "@-", url])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(body.as_bytes())?;
+    let output = child.wait_with_output()?;
+    match output.status.code() {
+        Some(0) => Ok(String::from_utf8(output.stdout).unwrap()),
+        _ => Err(std::io::Error::other(
+            String::from_utf8(output.stderr).unwrap().trim(),
+        )),
+    }
+}
+
+fn notify(rule: &alert_rules::Model, alert: &Alert, status: &str) {
+    let url = rule.webhook_url.clone();
+    let body = payload(rule, alert, status);
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = post_webhook(&url, &body) {
+            println!("webhook {} failed: {}", url, e);
+        }
+    });
+}
+
+impl AlertManager {
+    pub fn new(db: DatabaseConnection, stats: StatsConnect, metrics: Metrics) -> Self {
+        let alerts = AlertManager {
+            alerts: Arc::new(Mutex::new(HashMap::new())),
+        };
+        tokio::spawn(alerts.clone().run(db, stats, metrics));
+        alerts
+    }
+
+    pub fn active(&self) -> Vec<Alert> {
+        self.alerts.lock().unwrap().values().cloned().collect()
+    }
+
+    // a test delivery, so webhook urls can be checked without breaking anything
+    pub fn test_payload(rule: &alert_rules::Model) -> String {
+        let alert = Alert {
+            rule_id: rule.id,
+            rule_name: rule.name.clone(),
+            kind: rule.kind.clone(),
+            subject: "test".to_string(),
+            value: rule.threshold,
+            threshold: rule.threshold,
+            since: Utc::now().timestamp(),
+            firing: true,
+        };
+        payload(rule, &alert, "test")
+    }
+
+    pub(crate) fn update(
+        &self,
+        rules: &[alert_rules::Model],
+        stats: &StatsConnect,
+        metrics: &Metrics,
+    ) {
+        let now = Utc::now().timestamp();
+        let mut alerts = self.alerts.lock().unwrap();
+        let mut seen = Vec::new();
+        for rule in rules {
+            for (subject, value) in evaluate(rule, stats, metrics) {
+                let key = (rule.id, subject.clone());
+                let alert = alerts.entry(key.clone()).or_insert(Alert {
+                    rule_id: rule.id,
+                    rule_name: rule.name.clone(),
+                    kind: rule.kind.clone(),
+                    subject,
+                    value,
+                    threshold: rule.threshold,
+                    since: now,
+                    firing: false,
+                });
+                alert.value = value;
+                if !alert.firing && now - alert.since >= rule.duration as i64 {
+                    alert.firing = true;
+                    notify(rule, alert, "firing");
+                }
+                seen.push(key);
+            }
+        }
+        // conditions that cleared resolve, alerts of deleted or disabled rules just go away
+        alerts.retain(|key, alert| {
+            if seen.contains(key) {
+                return true;
+            }
+            if let Some(rule) = rules.iter().find(|it| it.id == alert.rule_id) {
+                if alert.firing {
+                    notify(rule, alert, "resolved");
+                }
+            }
+            false
+        });
+    }
+
+    async fn run(self, db: DatabaseConnection, stats: StatsConnect, metrics: Metrics) {
+        let mut interval = tokio::time::interval(Duration::from_secs(EVALUATE_INTERVAL));
+        loop {
+            interval.tick().await;
+            match AlertRules::find()
+                .filter(alert_rules::Column::Enabled.eq(true))
+                .all(&db)
+                .await
+            {
+                Ok(rules) => self.update(&rules, &stats, &metrics),
+                Err(e) => println!("loading alert rules failed: {}", e),
+            }
+        }
+    }
+}
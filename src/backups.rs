@@ -0,0 +1,222 @@
+use chrono::Utc;
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, ModelTrait,
+    QueryFilter, QueryOrder,
+};
+use serde::{Deserialize, Serialize};
+use std::{env, io, path::PathBuf, time::Instant};
+use thiserror::Error;
+use tokio::runtime::Handle;
+
+use crate::{
+    db::entity::{prelude::*, *},
+    virt::backup::{self, BackupBase, BackupDisk},
+};
+
+const DEFAULT_BACKUP_DIR: &str = "/var/lib/libvirt/backups";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupConfig {
+    pub dom_name: String,
+    // on top of the domain's last backup, full when there's nothing to build on
+    pub incremental: Option<bool>,
+    // directory the backup goes to, BACKUP_DIR when unset
+    pub target: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RestoreConfig {
+    pub backup_id: i32,
+    // domain to restore into, the backed up one when unset
+    pub dom_name: Option<String>,
+    // an existing domain only has its disks written over when set
+    pub overwrite: Option<bool>,
+    // pool for the disks of a new domain, defaults to DEFAULT_POOL
+    pub pool: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error("Backup {0} not found")]
+    NotFound(i32),
+    #[error("Backup {0} is the base of backup {1}")]
+    InUse(i32, i32),
+    #[error(transparent)]
+    Db(#[from] DbErr),
+}
+
+// the catalog of backups, their files live under BACKUP_DIR or the target given
+#[derive(Clone)]
+pub struct BackupManager {
+    db: DatabaseConnection,
+    dir: PathBuf,
+}
+
+// a backup ready to run on a blocking thread
+pub struct PendingBackup {
+    db: DatabaseConnection,
+    handle: Handle,
+    dom_name: String,
+    dir: PathBuf,
+    base: Option<backups::Model>,
+}
+
+fn other(e: DbErr) -> io::Error {
+    io::Error::other(e)
+}
+
+impl BackupManager {
+    pub fn new(db: DatabaseConnection) -> Self {
+        BackupManager {
+            db,
+            dir: PathBuf::from(env::var("BACKUP_DIR").unwrap_or(DEFAULT_BACKUP_DIR.to_string())),
+        }
+    }
+
+    pub async fn prepare(
+        &self,
+        dom_name: &str,
+        incremental: bool,
+        target: Option<String>,
+    ) -> Result<PendingBackup, DbErr> {
+        // only the newest backup keeps a checkpoint, see PendingBackup::run
+        let base = match incremental {
+            true => {
+                Backups::find()
+                    .filter(backups::Column::DomName.eq(dom_name))
+                    .filter(backups::Column::Checkpoint.is_not_null())
+                    .order_by_desc(backups::Column::Id)
+                    .one(&self.db)
+                    .await?
+            }
+            false => None,
+        };
+        let root = target.map_or(self.dir.clone(), PathBuf::from);
+        Ok(PendingBackup {
+            db: self.db.clone(),
+            handle: Handle::current(),
+            dom_name: dom_name.to_string(),
+            dir: root
+                .join(dom_name)
+                .join(Utc::now().format("%Y%m%d-%H%M%S").to_string()),
+            base,
+        })
+    }
+
+    // newest first
+    pub async fn list(&self, dom_name: Option<String>) -> Result<Vec<backups::Model>, DbErr> {
+        let mut query = Backups::find().order_by_desc(backups::Column::Id);
+        if let Some(dom_name) = dom_name {
+            query = query.filter(backups::Column::DomName.eq(dom_name));
+        }
+        query.all(&self.db).await
+    }
+
+    pub async fn get(&self, id: i32) -> Result<backups::Model, BackupError> {
+        Backups::find_by_id(id)
+            .one(&self.db)
+            .await?
+            .ok_or(BackupError::NotFound(id))
+    }
+
+    // the backup followed by the ones it's incremental on, down to the full one
+    pub async fn chain(&self, id: i32) -> Result<Vec<backups::Model>, BackupError> {
+        let mut chain = vec![self.get(id).await?];
+        while let Some(parent_id) = chain.last().and_then(|it| it.parent_id) {
+            chain.push(self.get(parent_id).await?);
+        }
+        Ok(chain)
+    }
+
+    pub async fn delete(&self, id: i32) -> Result<String, BackupError> {
+        let backup = self.get(id).await?;
+        if let Some(child) = Backups::find()
+            .filter(backups::Column::ParentId.eq(id))
+            .one(&self.db)
+            .await?
+        {
+            return Err(BackupError::InUse(id, child.id));
+        }
+        if let Some(checkpoint) = &backup.checkpoint {
+            let _ = backup::drop_checkpoint(&backup.dom_name, checkpoint);
+        }
+        let _ = tokio::fs::remove_dir_all(&backup.path).await;
+        backup.delete(&self.db).await?;
+        Ok(format!("Backup {} deleted", id))
+    }
+}
+
+impl PendingBackup {
+    // takes the backup and records it, the catalog entry is the result
+    pub fn run(self) -> Result<String, io::Error> {
+        let started = Instant::now();
+        let created_at = Utc::now().timestamp();
+        let base = self.base.as_ref().map(|it| BackupBase {
+            dir: PathBuf::from(&it.path),
+            checkpoint: it.checkpoint.clone().unwrap_or_default(),
+            disks: serde_json::from_str::<Vec<BackupDisk>>(&it.disks).unwrap_or_default(),
+        });
+        let outcome = backup::backup(&self.dom_name, &self.dir, base.as_ref())?;
+        let model = backups::ActiveModel {
+            dom_name: ActiveValue::set(self.dom_name.clone()),
+            kind: ActiveValue::set(
+                match outcome.incremental {
+                    true => "incremental",
+                    false => "full",
+                }
+                .to_string(),
+            ),
+            method: ActiveValue::set(outcome.method.to_string()),
+            parent_id: ActiveValue::set(match outcome.incremental {
+                true => self.base.as_ref().map(|it| it.id),
+                false => None,
+            }),
+            checkpoint: ActiveValue::set(outcome.checkpoint.clone()),
+            path: ActiveValue::set(self.dir.to_string_lossy().into_owned()),
+            disks: ActiveValue::set(serde_json::to_string(&outcome.disks).unwrap()),
+            checksums: ActiveValue::set(serde_json::to_string(&outcome.checksums).unwrap()),
+            size_bytes: ActiveValue::set(outcome.size_bytes as i64),
+            duration_ms: ActiveValue::set(started.elapsed().as_millis() as i64),
+            created_at: ActiveValue::set(created_at),
+            ..Default::default()
+        };
+        let saved = match self.handle.block_on(model.insert(&self.db)) {
+            Ok(saved) => saved,
+            Err(e) => {
+                if let Some(checkpoint) = &outcome.checkpoint {
+                    let _ = backup::drop_checkpoint(&self.dom_name, checkpoint);
+                }
+                let _ = std::fs::remove_dir_all(&self.dir);
+                return Err(other(e));
+            }
+        };
+        // the next incremental one only needs the newest checkpoint, older ones
+        // would just keep dirty bitmaps growing on the disks
+        if saved.checkpoint.is_some() {
+            let older = self
+                .handle
+                .block_on(
+                    Backups::find()
+                        .filter(backups::Column::DomName.eq(&self.dom_name))
+                        .filter(backups::Column::Checkpoint.is_not_null())
+                        .filter(backups::Column::Id.ne(saved.id))
+                        .all(&self.db),
+                )
+                .map_err(other)?;
+            for old in older {
+                let _ = backup::drop_checkpoint(&self.dom_name, old.checkpoint.as_ref().unwrap());
+                self.handle
+                    .block_on(
+                        backups::ActiveModel {
+                            id: ActiveValue::unchanged(old.id),
+                            checkpoint: ActiveValue::set(None),
+                            ..Default::default()
+                        }
+                        .update(&self.db),
+                    )
+                    .map_err(other)?;
+            }
+        }
+        Ok(serde_json::to_string(&saved).unwrap())
+    }
+}
@@ -1,5 +1,16 @@
 pub mod account;
+pub mod alerts;
+pub mod archive;
+pub mod backups;
+pub mod bulk;
+pub mod disk;
+pub mod events;
+pub mod metrics;
+pub mod network;
 pub mod virt;
 pub mod sys;
 pub mod snapshot;
+pub mod stats;
+pub mod storage;
+pub mod tasks;
 pub mod vnc;
\ No newline at end of file
@@ -0,0 +1,151 @@
+use rocket::{http::Status, response::content, serde::json::Json, State};
+use sea_orm::{ActiveValue, DatabaseConnection, EntityTrait, ModelTrait};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    alerts::{post_webhook, AlertManager, ALERT_KINDS, WEBHOOK_FORMATS},
+    db::entity::{prelude::*, *},
+    middleware::authenticate::JWT,
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AlertRuleConfig {
+    pub name: String,
+    pub kind: String,
+    // percent for usage rules, ignored by the others
+    pub threshold: Option<f64>,
+    // seconds the condition must hold before notifying
+    pub duration: Option<i32>,
+    // limit the rule to one domain or mount point
+    pub target: Option<String>,
+    pub webhook_url: String,
+    // json or slack
+    pub webhook_format: Option<String>,
+}
+
+#[get("/rules/list")]
+pub async fn list_alert_rules(
+    _jwt: JWT,
+    db: &State<DatabaseConnection>,
+) -> (Status, content::RawJson<String>) {
+    match AlertRules::find().all(db as &DatabaseConnection).await {
+        Ok(rules) => (
+            Status::Ok,
+            content::RawJson(serde_json::to_string(&rules).unwrap()),
+        ),
+        Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
+    }
+}
+
+#[post("/rules/create", format = "application/json", data = "<config>")]
+pub async fn create_alert_rule(
+    _jwt: JWT,
+    db: &State<DatabaseConnection>,
+    config: Json<AlertRuleConfig>,
+) -> (Status, content::RawJson<String>) {
+    let config = config.0;
+    if !ALERT_KINDS.contains(&config.kind.as_str()) {
+        return (
+            Status::BadRequest,
+            content::RawJson(format!(
+                "unknown alert kind {}, expected one of {:?}",
+                config.kind, ALERT_KINDS
+            )),
+        );
+    }
+    let webhook_format = config.webhook_format.unwrap_or("json".to_string());
+    if !WEBHOOK_FORMATS.contains(&webhook_format.as_str()) {
+        return (
+            Status::BadRequest,
+            content::RawJson(format!(
+                "unknown webhook format {}, expected one of {:?}",
+                webhook_format, WEBHOOK_FORMATS
+            )),
+        );
+    }
+    if !config.webhook_url.starts_with("http://") && !config.webhook_url.starts_with("https://") {
+        return (
+            Status::BadRequest,
+            content::RawJson("webhook_url must be an http or https url".to_string()),
+        );
+    }
+    match AlertRules::insert(alert_rules::ActiveModel {
+        name: ActiveValue::set(config.name),
+        kind: ActiveValue::set(config.kind),
+        threshold: ActiveValue::set(config.threshold.unwrap_or(90.0)),
+        duration: ActiveValue::set(config.duration.unwrap_or(0).max(0)),
+        target: ActiveValue::set(config.target),
+        webhook_url: ActiveValue::set(config.webhook_url),
+        webhook_format: ActiveValue::set(webhook_format),
+        enabled: ActiveValue::set(true),
+        ..Default::default()
+    })
+    .exec(db as &DatabaseConnection)
+    .await
+    {
+        Ok(res) => (Status::Ok, content::RawJson(res.last_insert_id.to_string())),
+        Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
+    }
+}
+
+#[post("/rules/delete", format = "application/json", data = "<id>")]
+pub async fn delete_alert_rule(
+    _jwt: JWT,
+    db: &State<DatabaseConnection>,
+    id: Json<i32>,
+) -> (Status, content::RawJson<String>) {
+    let db = db as &DatabaseConnection;
+    match AlertRules::find_by_id(id.0).one(db).await {
+        Ok(Some(rule)) => match rule.delete(db).await {
+            Ok(_) => (
+                Status::Ok,
+                content::RawJson("Delete alert rule successfully".to_string()),
+            ),
+            Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
+        },
+        Ok(None) => (
+            Status::NotFound,
+            content::RawJson(format!("Alert rule {} not found", id.0)),
+        ),
+        Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
+    }
+}
+
+// sends a sample notification to the rule's webhook
+#[post("/rules/test", format = "application/json", data = "<id>")]
+pub async fn test_alert_rule(
+    _jwt: JWT,
+    db: &State<DatabaseConnection>,
+    id: Json<i32>,
+) -> (Status, content::RawJson<String>) {
+    let rule = match AlertRules::find_by_id(id.0)
+        .one(db as &DatabaseConnection)
+        .await
+    {
+        Ok(Some(rule)) => rule,
+        Ok(None) => {
+            return (
+                Status::NotFound,
+                content::RawJson(format!("Alert rule {} not found", id.0)),
+            )
+        }
+        Err(e) => return (Status::InternalServerError, content::RawJson(e.to_string())),
+    };
+    let body = AlertManager::test_payload(&rule);
+    match tokio::task::spawn_blocking(move || post_webhook(&rule.webhook_url, &body)).await {
+        Ok(Ok(output)) => (Status::Ok, content::RawJson(output)),
+        Ok(Err(e)) => (Status::BadGateway, content::RawJson(e.to_string())),
+        Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
+    }
+}
+
+#[get("/active")]
+pub fn list_active_alerts(
+    _jwt: JWT,
+    alerts: &State<AlertManager>,
+) -> (Status, content::RawJson<String>) {
+    (
+        Status::Ok,
+        content::RawJson(serde_json::to_string(&alerts.active()).unwrap()),
+    )
+}
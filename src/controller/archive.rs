@@ -0,0 +1,198 @@
+use chrono::Utc;
+use rocket::{
+    data::{Data, ToByteUnit},
+    http::{ContentType, Status},
+    response::{
+        self, content,
+        stream::{One, ReaderStream},
+        Responder,
+    },
+    Request, Response, State,
+};
+use std::{env, path::Path, process::Stdio};
+use tokio::process::{ChildStdout, Command};
+
+use crate::{
+    controller::{
+        tasks::task_accepted,
+        virt::{lock_domain, run_virt_command},
+    },
+    locks::DomainLocks,
+    middleware::authenticate::JWT,
+    tasks::TaskManager,
+    virt::{
+        archive::{self, ExportPlan},
+        VirtCommandType, VirtConnect, DEFAULT_POOL,
+    },
+};
+
+pub struct ArchiveDownload {
+    body: ReaderStream<One<ChildStdout>>,
+    file_name: String,
+}
+
+impl<'r> Responder<'r, 'r> for ArchiveDownload {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'r> {
+        Response::build_from(self.body.respond_to(req)?)
+            .header(ContentType::new("application", "x-tar"))
+            .raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", self.file_name),
+            )
+            .ok()
+    }
+}
+
+// the domain with its snapshots and disks as one tar, streamed while tar writes it;
+// the domain stays locked until the download is done
+#[get("/export/<dom_name>")]
+pub async fn export_domain(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    locks: &State<DomainLocks>,
+    dom_name: &str,
+) -> Result<ArchiveDownload, (Status, content::RawJson<String>)> {
+    let lock = lock_domain(locks, dom_name, "export").await?;
+    let (status, plan) = run_virt_command(
+        conn,
+        VirtCommandType::ExportDomain,
+        vec![dom_name.to_string()],
+    );
+    if status != Status::Ok {
+        return Err((status, plan));
+    }
+    let plan: ExportPlan = serde_json::from_str(&plan.0).unwrap();
+    let staging = env::temp_dir().join(format!(
+        "export-{}-{}",
+        dom_name,
+        Utc::now().timestamp_millis()
+    ));
+    let stage = {
+        let staging = staging.clone();
+        tokio::task::spawn_blocking(move || archive::stage_export(&plan, &staging)).await
+    };
+    let child = match stage {
+        Ok(Ok(_)) => Command::new("tar")
+            .arg("-chSf")
+            .arg("-")
+            .arg("-C")
+            .arg(&staging)
+            .args(archive::archive_members())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn(),
+        Ok(Err(e)) => Err(e),
+        Err(e) => Err(std::io::Error::other(e)),
+    };
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            let _ = tokio::fs::remove_dir_all(&staging).await;
+            let status = match e.kind() {
+                std::io::ErrorKind::InvalidInput => Status::BadRequest,
+                _ => Status::InternalServerError,
+            };
+            return Err((status, content::RawJson(e.to_string())));
+        }
+    };
+    let stdout = child.stdout.take().unwrap();
+    tokio::spawn(async move {
+        let _lock = lock;
+        let _ = child.wait().await;
+        let _ = tokio::fs::remove_dir_all(&staging).await;
+    });
+    Ok(ArchiveDownload {
+        body: ReaderStream::one(stdout),
+        file_name: format!("{}.tar", dom_name),
+    })
+}
+
+// the archive is unpacked, checked and defined in a background task; the domain
+// keeps its archived name unless `name` says otherwise, an existing one is refused
+#[post("/import?<pool>&<name>", data = "<archive>")]
+pub async fn import_domain(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    locks: &State<DomainLocks>,
+    tasks: &State<TaskManager>,
+    pool: Option<String>,
+    name: Option<String>,
+    archive: Data<'_>,
+) -> (Status, content::RawJson<String>) {
+    let pool = pool.unwrap_or(DEFAULT_POOL.to_string());
+    let (status, pool_path) =
+        run_virt_command(conn, VirtCommandType::GetPoolPath, vec![pool.clone()]);
+    if status != Status::Ok {
+        return (status, pool_path);
+    }
+    let path = env::temp_dir().join(format!("import-{}.tar", Utc::now().timestamp_millis()));
+    let discard = |path: &Path| {
+        let _ = std::fs::remove_file(path);
+    };
+    match archive.open(256.gibibytes()).into_file(&path).await {
+        Ok(file) if file.is_complete() => (),
+        Ok(_) => {
+            discard(&path);
+            return (
+                Status::PayloadTooLarge,
+                content::RawJson("archive exceeds the upload limit".to_string()),
+            );
+        }
+        Err(e) => {
+            discard(&path);
+            return (Status::InsufficientStorage, content::RawJson(e.to_string()));
+        }
+    }
+    let manifest = {
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || archive::read_manifest(&path)).await
+    };
+    let manifest = match manifest {
+        Ok(Ok(manifest)) => manifest,
+        Ok(Err(e)) => {
+            discard(&path);
+            return (Status::BadRequest, content::RawJson(e.to_string()));
+        }
+        Err(e) => {
+            discard(&path);
+            return (Status::InternalServerError, content::RawJson(e.to_string()));
+        }
+    };
+    let name = name.unwrap_or(manifest.dom_name);
+    if name.is_empty() || name.contains('/') {
+        discard(&path);
+        return (
+            Status::BadRequest,
+            content::RawJson(format!("invalid domain name {}", name)),
+        );
+    }
+    let (status, output) =
+        run_virt_command(conn, VirtCommandType::GetDomainState, vec![name.clone()]);
+    if status == Status::Ok {
+        discard(&path);
+        return (
+            Status::Conflict,
+            content::RawJson(format!(
+                "Domain {} already exists, pass name to import it under another one",
+                name
+            )),
+        );
+    }
+    if status != Status::NotFound {
+        discard(&path);
+        return (status, output);
+    }
+    let lock = match lock_domain(locks, &name, "import").await {
+        Ok(lock) => lock,
+        Err(res) => {
+            discard(&path);
+            return res;
+        }
+    };
+    let res = tasks
+        .submit("import", &name.clone(), lock, move || {
+            archive::import(&path, &pool, Path::new(&pool_path.0), &name)
+        })
+        .await;
+    task_accepted(res)
+}
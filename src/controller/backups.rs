@@ -0,0 +1,165 @@
+use rocket::{http::Status, response::content, serde::json::Json, State};
+use std::{collections::BTreeMap, path::Path};
+
+use crate::{
+    backups::{BackupConfig, BackupError, BackupManager, RestoreConfig},
+    controller::{
+        tasks::task_accepted,
+        virt::{lock_domain, run_virt_command},
+    },
+    locks::DomainLocks,
+    middleware::authenticate::JWT,
+    tasks::TaskManager,
+    virt::{
+        backup::{self, BackupDisk},
+        VirtCommandType, VirtConnect, DEFAULT_POOL,
+    },
+};
+
+fn backup_error(e: BackupError) -> (Status, content::RawJson<String>) {
+    match e {
+        BackupError::NotFound(_) => (Status::NotFound, content::RawJson(e.to_string())),
+        BackupError::InUse(..) => (Status::Conflict, content::RawJson(e.to_string())),
+        BackupError::Db(_) => (Status::InternalServerError, content::RawJson(e.to_string())),
+    }
+}
+
+#[post("/create", format = "application/json", data = "<configure>")]
+pub async fn create_backup(
+    _jwt: JWT,
+    locks: &State<DomainLocks>,
+    tasks: &State<TaskManager>,
+    backups: &State<BackupManager>,
+    configure: Json<BackupConfig>,
+) -> (Status, content::RawJson<String>) {
+    let configure = configure.0;
+    let lock = match lock_domain(locks, &configure.dom_name, "backup").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    let pending = match backups
+        .prepare(
+            &configure.dom_name,
+            configure.incremental == Some(true),
+            configure.target,
+        )
+        .await
+    {
+        Ok(pending) => pending,
+        Err(e) => return (Status::InternalServerError, content::RawJson(e.to_string())),
+    };
+    let res = tasks
+        .submit("backup", &configure.dom_name, lock, move || pending.run())
+        .await;
+    task_accepted(res)
+}
+
+#[get("/list?<dom_name>")]
+pub async fn list_backups(
+    _jwt: JWT,
+    backups: &State<BackupManager>,
+    dom_name: Option<String>,
+) -> (Status, content::RawJson<String>) {
+    match backups.list(dom_name).await {
+        Ok(backups) => (
+            Status::Ok,
+            content::RawJson(serde_json::to_string(&backups).unwrap()),
+        ),
+        Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
+    }
+}
+
+#[post("/get", format = "application/json", data = "<id>")]
+pub async fn get_backup(
+    _jwt: JWT,
+    backups: &State<BackupManager>,
+    id: Json<i32>,
+) -> (Status, content::RawJson<String>) {
+    match backups.get(id.0).await {
+        Ok(backup) => (
+            Status::Ok,
+            content::RawJson(serde_json::to_string(&backup).unwrap()),
+        ),
+        Err(e) => backup_error(e),
+    }
+}
+
+#[post("/delete", format = "application/json", data = "<id>")]
+pub async fn delete_backup(
+    _jwt: JWT,
+    backups: &State<BackupManager>,
+    id: Json<i32>,
+) -> (Status, content::RawJson<String>) {
+    match backups.delete(id.0).await {
+        Ok(output) => (Status::Ok, content::RawJson(output)),
+        Err(e) => backup_error(e),
+    }
+}
+
+// into a new domain, or over the disks of an existing one when `overwrite` says
+// so; the whole chain of the backup is checked against the catalog first
+#[post("/restore", format = "application/json", data = "<configure>")]
+pub async fn restore_backup(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    locks: &State<DomainLocks>,
+    tasks: &State<TaskManager>,
+    backups: &State<BackupManager>,
+    configure: Json<RestoreConfig>,
+) -> (Status, content::RawJson<String>) {
+    let configure = configure.0;
+    let chain = match backups.chain(configure.backup_id).await {
+        Ok(chain) => chain,
+        Err(e) => return backup_error(e),
+    };
+    let dom_name = chain[0].dom_name.clone();
+    let name = configure.dom_name.unwrap_or(dom_name.clone());
+    let (status, output) =
+        run_virt_command(conn, VirtCommandType::GetDomainState, vec![name.clone()]);
+    let exists = status == Status::Ok;
+    if !exists && status != Status::NotFound {
+        return (status, output);
+    }
+    if exists && configure.overwrite != Some(true) {
+        return (
+            Status::Conflict,
+            content::RawJson(format!(
+                "Domain {} already exists, pass overwrite to restore over its disks",
+                name
+            )),
+        );
+    }
+    let pool = configure.pool.unwrap_or(DEFAULT_POOL.to_string());
+    let pool_path = match exists {
+        true => String::new(),
+        false => {
+            let (status, pool_path) =
+                run_virt_command(conn, VirtCommandType::GetPoolPath, vec![pool.clone()]);
+            if status != Status::Ok {
+                return (status, pool_path);
+            }
+            pool_path.0
+        }
+    };
+    let lock = match lock_domain(locks, &name, "restore").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    let res = tasks
+        .submit("restore", &name.clone(), lock, move || {
+            for backup in &chain {
+                let checksums: BTreeMap<String, String> = serde_json::from_str(&backup.checksums)?;
+                backup::verify(Path::new(&backup.path), &checksums)?;
+            }
+            let dir = Path::new(&chain[0].path);
+            let disks: Vec<BackupDisk> = serde_json::from_str(&chain[0].disks)?;
+            match exists {
+                true => backup::restore_existing(dir, &disks, &name),
+                false => {
+                    backup::restore_new(dir, &disks, &dom_name, &pool, Path::new(&pool_path), &name)
+                }
+            }
+        })
+        .await;
+    task_accepted(res)
+}
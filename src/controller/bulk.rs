@@ -0,0 +1,200 @@
+use futures::{stream, StreamExt};
+use rocket::{http::Status, response::content, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    controller::virt::{change_domain_state, run_virt_command},
+    events::{DomainEventKind, EventConnect},
+    locks::DomainLocks,
+    middleware::authenticate::JWT,
+    virt::{
+        shell, AltDomStateCommand, DomainAction, RevertConfig, SnapShotConfig, VirtCommandType,
+        VirtConnect,
+    },
+};
+
+const DEFAULT_CONCURRENCY: usize = 4;
+const MAX_CONCURRENCY: usize = 16;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum BulkAction {
+    Power {
+        state: DomainAction,
+        timeout: Option<u64>,
+    },
+    CreateSnapshot {
+        snapshot_name: String,
+        description: Option<String>,
+    },
+    RevertSnapshot {
+        snapshot_name: String,
+        // save the state being left as a pre-revert snapshot first, on when unset
+        save_current: Option<bool>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BulkConfig {
+    #[serde(default)]
+    pub dom_names: Vec<String>,
+    // label selectors, matching domains are added to dom_names
+    pub selector: Option<Vec<String>>,
+    pub action: BulkAction,
+    pub concurrency: Option<usize>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BulkResult {
+    dom_name: String,
+    success: bool,
+    output: String,
+}
+
+fn snapshot_config(
+    dom_name: &str,
+    snapshot_name: &str,
+    description: Option<String>,
+) -> SnapShotConfig {
+    SnapShotConfig {
+        dom_name: dom_name.to_string(),
+        snapshot_name: snapshot_name.to_string(),
+        description,
+        parent: None,
+        is_live: None,
+    }
+}
+
+async fn run_action(
+    conn: &VirtConnect,
+    events: &EventConnect,
+    locks: &DomainLocks,
+    dom_name: String,
+    action: BulkAction,
+) -> BulkResult {
+    let operation = match &action {
+        BulkAction::Power { .. } => "set-state",
+        BulkAction::CreateSnapshot { .. } => "create-snapshot",
+        BulkAction::RevertSnapshot { .. } => "revert-snapshot",
+    };
+    // a busy domain is reported like any other failure
+    let _lock = match locks.acquire(&dom_name, operation).await {
+        Ok(lock) => lock,
+        Err(holder) => {
+            return BulkResult {
+                output: format!("Domain {} is busy with {}", dom_name, holder.operation),
+                dom_name,
+                success: false,
+            }
+        }
+    };
+    // libvirt has no snapshot events, announce them ourselves
+    let event = match &action {
+        BulkAction::CreateSnapshot { snapshot_name, .. } => {
+            Some((DomainEventKind::SnapshotCreated, snapshot_name.clone()))
+        }
+        BulkAction::RevertSnapshot { snapshot_name, .. } => {
+            Some((DomainEventKind::SnapshotReverted, snapshot_name.clone()))
+        }
+        BulkAction::Power { .. } => None,
+    };
+    let res = match action {
+        BulkAction::Power { state, timeout } => {
+            let config = AltDomStateCommand {
+                dom_name: dom_name.clone(),
+                state,
+                timeout,
+            };
+            match change_domain_state(conn, config).await {
+                (status, output) if status == Status::Ok => Ok(output.0),
+                (_, output) => Err(output.0),
+            }
+        }
+        // virsh runs outside the libvirt thread, so these really run side by side
+        BulkAction::CreateSnapshot {
+            snapshot_name,
+            description,
+        } => {
+            let config = snapshot_config(&dom_name, &snapshot_name, description);
+            tokio::task::spawn_blocking(move || shell::create_snapshot(config))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|res| res.map_err(|e| e.to_string()))
+        }
+        BulkAction::RevertSnapshot {
+            snapshot_name,
+            save_current,
+        } => {
+            let config = RevertConfig {
+                dom_name: dom_name.clone(),
+                snapshot_name,
+                save_current,
+                dry_run: None,
+            };
+            tokio::task::spawn_blocking(move || shell::revert_snapshot(config))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|res| res.map_err(|e| e.to_string()))
+        }
+    };
+    if let (Ok(_), Some((kind, snapshot_name))) = (&res, event) {
+        events.publish(&dom_name, kind, &snapshot_name);
+    }
+    match res {
+        Ok(output) => BulkResult {
+            dom_name,
+            success: true,
+            output,
+        },
+        Err(output) => BulkResult {
+            dom_name,
+            success: false,
+            output,
+        },
+    }
+}
+
+#[post("/run", format = "application/json", data = "<config>")]
+pub async fn run_bulk(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    events: &State<EventConnect>,
+    locks: &State<DomainLocks>,
+    config: String,
+) -> (Status, content::RawJson<String>) {
+    // parsed here so a bad action is a 400; there's no undefine power action, domains
+    // are only deleted one at a time through /api/v1/virt/delete with a confirmation
+    let mut config = match serde_json::from_str::<BulkConfig>(&config) {
+        Ok(config) => config,
+        Err(e) => return (Status::BadRequest, content::RawJson(e.to_string())),
+    };
+    let conn = conn as &VirtConnect;
+    let events = events as &EventConnect;
+    let locks = locks as &DomainLocks;
+    if let Some(selector) = config.selector.take() {
+        let (status, output) = run_virt_command(conn, VirtCommandType::SelectDomains, selector);
+        if status != Status::Ok {
+            return (status, output);
+        }
+        for dom_name in serde_json::from_str::<Vec<String>>(&output.0).unwrap() {
+            if !config.dom_names.contains(&dom_name) {
+                config.dom_names.push(dom_name);
+            }
+        }
+    }
+    let concurrency = config
+        .concurrency
+        .unwrap_or(DEFAULT_CONCURRENCY)
+        .clamp(1, MAX_CONCURRENCY);
+    // every domain gets a result, one failure doesn't stop the others
+    let results: Vec<BulkResult> = stream::iter(config.dom_names)
+        .map(|dom_name| run_action(conn, events, locks, dom_name, config.action.clone()))
+        .buffered(concurrency)
+        .collect()
+        .await;
+    (
+        Status::Ok,
+        content::RawJson(serde_json::to_string(&results).unwrap()),
+    )
+}
@@ -0,0 +1,140 @@
+use rocket::{http::Status, response::content, serde::json::Json, State};
+
+use crate::{
+    controller::{
+        tasks::task_accepted,
+        virt::{config_dom_name, lock_domain, run_virt_command},
+    },
+    locks::DomainLocks,
+    middleware::authenticate::JWT,
+    tasks::TaskManager,
+    virt::{shell, BlockJobConfig, VirtCommandType, VirtConnect},
+};
+
+#[post("/list", format = "application/json", data = "<dom_name>")]
+pub fn list_disks(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    dom_name: Json<String>,
+) -> (Status, content::RawJson<String>) {
+    run_virt_command(conn, VirtCommandType::ListDisks, vec![dom_name.0])
+}
+
+#[post("/create", format = "application/json", data = "<configure>")]
+pub fn create_disk(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    configure: String,
+) -> (Status, content::RawJson<String>) {
+    run_virt_command(conn, VirtCommandType::CreateVolume, vec![configure])
+}
+
+#[post("/attach", format = "application/json", data = "<configure>")]
+pub async fn attach_disk(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    locks: &State<DomainLocks>,
+    configure: String,
+) -> (Status, content::RawJson<String>) {
+    let _lock = match lock_domain(locks, &config_dom_name(&configure), "attach-disk").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    run_virt_command(conn, VirtCommandType::AttachDisk, vec![configure])
+}
+
+#[post("/detach", format = "application/json", data = "<configure>")]
+pub async fn detach_disk(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    locks: &State<DomainLocks>,
+    configure: String,
+) -> (Status, content::RawJson<String>) {
+    let _lock = match lock_domain(locks, &config_dom_name(&configure), "detach-disk").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    run_virt_command(conn, VirtCommandType::DetachDisk, vec![configure])
+}
+
+#[post("/resize", format = "application/json", data = "<configure>")]
+pub async fn resize_disk(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    locks: &State<DomainLocks>,
+    configure: String,
+) -> (Status, content::RawJson<String>) {
+    let _lock = match lock_domain(locks, &config_dom_name(&configure), "resize-disk").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    run_virt_command(conn, VirtCommandType::ResizeDisk, vec![configure])
+}
+
+#[post("/chain", format = "application/json", data = "<dom_name>")]
+pub fn list_backing_chains(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    dom_name: Json<String>,
+) -> (Status, content::RawJson<String>) {
+    run_virt_command(conn, VirtCommandType::ListBackingChains, vec![dom_name.0])
+}
+
+#[post("/commit", format = "application/json", data = "<configure>")]
+pub async fn block_commit(
+    _jwt: JWT,
+    locks: &State<DomainLocks>,
+    tasks: &State<TaskManager>,
+    configure: Json<BlockJobConfig>,
+) -> (Status, content::RawJson<String>) {
+    let lock = match lock_domain(locks, &configure.dom_name, "block-commit").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    let active = {
+        let dom_name = configure.dom_name.clone();
+        tokio::task::spawn_blocking(move || shell::is_active(&dom_name)).await
+    };
+    match active {
+        Ok(Ok(true)) => {}
+        Ok(Ok(false)) => {
+            return (
+                Status::Conflict,
+                content::RawJson(format!(
+                    "{} is shut off, start it before committing its disks",
+                    configure.dom_name
+                )),
+            )
+        }
+        Ok(Err(e)) => return (Status::BadRequest, content::RawJson(e.to_string())),
+        Err(e) => return (Status::InternalServerError, content::RawJson(e.to_string())),
+    }
+    let res = tasks
+        .submit(
+            "block-commit",
+            &configure.dom_name.clone(),
+            lock,
+            move || shell::block_commit(configure.0),
+        )
+        .await;
+    task_accepted(res)
+}
+
+#[post("/pull", format = "application/json", data = "<configure>")]
+pub async fn block_pull(
+    _jwt: JWT,
+    locks: &State<DomainLocks>,
+    tasks: &State<TaskManager>,
+    configure: Json<BlockJobConfig>,
+) -> (Status, content::RawJson<String>) {
+    let lock = match lock_domain(locks, &configure.dom_name, "block-pull").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    let res = tasks
+        .submit("block-pull", &configure.dom_name.clone(), lock, move || {
+            shell::block_pull(configure.0)
+        })
+        .await;
+    task_accepted(res)
+}
@@ -0,0 +1,57 @@
+use rocket::{
+    request::{FromRequest, Outcome},
+    response::stream::{Event, EventStream},
+    tokio::{select, sync::broadcast::error::RecvError},
+    Request, Shutdown, State,
+};
+
+use crate::{events::EventConnect, middleware::authenticate::JWT};
+
+// browsers send this header when an EventSource reconnects
+pub struct LastEventId(Option<u64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(LastEventId(
+            req.headers()
+                .get_one("Last-Event-ID")
+                .and_then(|it| it.parse().ok()),
+        ))
+    }
+}
+
+// every user sees every domain, `dom` narrows the stream down to some of them
+#[get("/stream?<dom>&<last_id>")]
+pub fn stream_events(
+    _jwt: JWT,
+    events: &State<EventConnect>,
+    dom: Vec<String>,
+    last_id: Option<u64>,
+    last_event_id: LastEventId,
+    mut end: Shutdown,
+) -> EventStream![] {
+    let (backlog, mut rx) = events.subscribe(last_event_id.0.or(last_id));
+    EventStream! {
+        for event in backlog {
+            if dom.is_empty() || dom.contains(&event.dom_name) {
+                yield Event::json(&event).id(event.id.to_string());
+            }
+        }
+        loop {
+            let event = select! {
+                msg = rx.recv() => match msg {
+                    Ok(event) => event,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = &mut end => break,
+            };
+            if dom.is_empty() || dom.contains(&event.dom_name) {
+                yield Event::json(&event).id(event.id.to_string());
+            }
+        }
+    }
+}
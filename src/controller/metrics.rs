@@ -0,0 +1,332 @@
+use rocket::{http::Status, State};
+use std::{collections::HashMap, fmt::Write};
+
+use crate::{
+    controller::virt::run_virt_command,
+    metrics::{escape_label, family, Metrics},
+    middleware::authenticate::MetricsToken,
+    stats::StatsConnect,
+    virt::{VirtCommandType, VirtConnect},
+};
+
+fn render_host(out: &mut String, stats: &StatsConnect) {
+    let Some(host) = stats.host_latest() else {
+        return;
+    };
+    family(
+        out,
+        "virt_host_cpu_usage_percent",
+        "gauge",
+        "Usage of each host cpu.",
+    );
+    for (i, usage) in host.cpu_usage.iter().enumerate() {
+        writeln!(
+            out,
+            "virt_host_cpu_usage_percent{{cpu=\"{}\"}} {}",
+            i, usage
+        )
+        .unwrap();
+    }
+    let gauges = [
+        (
+            "virt_host_memory_total_bytes",
+            "Host memory.",
+            host.total_memory as f64,
+        ),
+        (
+            "virt_host_memory_used_bytes",
+            "Host memory in use.",
+            host.used_memory as f64,
+        ),
+        (
+            "virt_host_swap_total_bytes",
+            "Host swap.",
+            host.total_swap as f64,
+        ),
+        (
+            "virt_host_swap_used_bytes",
+            "Host swap in use.",
+            host.used_swap as f64,
+        ),
+        (
+            "virt_host_load1",
+            "1 minute load average.",
+            host.load_average.one,
+        ),
+        (
+            "virt_host_load5",
+            "5 minute load average.",
+            host.load_average.five,
+        ),
+        (
+            "virt_host_load15",
+            "15 minute load average.",
+            host.load_average.fifteen,
+        ),
+        (
+            "virt_host_uptime_seconds",
+            "Host uptime.",
+            host.uptime as f64,
+        ),
+        (
+            "virt_host_processes",
+            "Running processes.",
+            host.process_count as f64,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        family(out, name, "gauge", help);
+        writeln!(out, "{} {}", name, value).unwrap();
+    }
+    family(
+        out,
+        "virt_host_filesystem_size_bytes",
+        "gauge",
+        "Filesystem size.",
+    );
+    for fs in &host.filesystems {
+        writeln!(
+            out,
+            "virt_host_filesystem_size_bytes{{mountpoint=\"{}\",device=\"{}\"}} {}",
+            escape_label(&fs.mount_point),
+            escape_label(&fs.device),
+            fs.total_bytes
+        )
+        .unwrap();
+    }
+    family(
+        out,
+        "virt_host_filesystem_avail_bytes",
+        "gauge",
+        "Filesystem space left.",
+    );
+    for fs in &host.filesystems {
+        writeln!(
+            out,
+            "virt_host_filesystem_avail_bytes{{mountpoint=\"{}\",device=\"{}\"}} {}",
+            escape_label(&fs.mount_point),
+            escape_label(&fs.device),
+            fs.available_bytes
+        )
+        .unwrap();
+    }
+    family(
+        out,
+        "virt_host_network_receive_bytes_per_second",
+        "gauge",
+        "Host nic receive rate.",
+    );
+    for nic in &host.networks {
+        writeln!(
+            out,
+            "virt_host_network_receive_bytes_per_second{{device=\"{}\"}} {}",
+            escape_label(&nic.name),
+            nic.rx_bps
+        )
+        .unwrap();
+    }
+    family(
+        out,
+        "virt_host_network_transmit_bytes_per_second",
+        "gauge",
+        "Host nic transmit rate.",
+    );
+    for nic in &host.networks {
+        writeln!(
+            out,
+            "virt_host_network_transmit_bytes_per_second{{device=\"{}\"}} {}",
+            escape_label(&nic.name),
+            nic.tx_bps
+        )
+        .unwrap();
+    }
+    family(
+        out,
+        "virt_host_temperature_celsius",
+        "gauge",
+        "Host sensor temperature.",
+    );
+    for temp in &host.temperatures {
+        writeln!(
+            out,
+            "virt_host_temperature_celsius{{sensor=\"{}\"}} {}",
+            escape_label(&temp.label),
+            temp.celsius
+        )
+        .unwrap();
+    }
+}
+
+fn render_domains(out: &mut String, stats: &StatsConnect, snapshots: &HashMap<String, usize>) {
+    family(
+        out,
+        "virt_domain_state",
+        "gauge",
+        "libvirt domain state, 1 running, 3 paused, 5 shutoff, 6 crashed.",
+    );
+    for (dom_name, state) in stats.domain_states() {
+        writeln!(
+            out,
+            "virt_domain_state{{domain=\"{}\"}} {}",
+            escape_label(&dom_name),
+            state
+        )
+        .unwrap();
+    }
+    family(
+        out,
+        "virt_domain_snapshots",
+        "gauge",
+        "Snapshots per domain.",
+    );
+    for (dom_name, count) in snapshots {
+        writeln!(
+            out,
+            "virt_domain_snapshots{{domain=\"{}\"}} {}",
+            escape_label(dom_name),
+            count
+        )
+        .unwrap();
+    }
+
+    // stopped domains keep their last sample for graphs, don't export it as live
+    let states = stats.domain_states();
+    let samples: Vec<_> = stats
+        .latest_samples()
+        .into_iter()
+        .filter(|(dom_name, _)| matches!(states.get(dom_name), Some(1..=3)))
+        .collect();
+    family(
+        out,
+        "virt_domain_cpu_seconds_total",
+        "counter",
+        "Cpu time used by the domain.",
+    );
+    for (dom_name, sample) in &samples {
+        writeln!(
+            out,
+            "virt_domain_cpu_seconds_total{{domain=\"{}\"}} {}",
+            escape_label(dom_name),
+            sample.cpu_time as f64 / 1e9
+        )
+        .unwrap();
+    }
+    family(
+        out,
+        "virt_domain_vcpus",
+        "gauge",
+        "Online vcpus of the domain.",
+    );
+    for (dom_name, sample) in &samples {
+        writeln!(
+            out,
+            "virt_domain_vcpus{{domain=\"{}\"}} {}",
+            escape_label(dom_name),
+            sample.vcpus
+        )
+        .unwrap();
+    }
+    family(
+        out,
+        "virt_domain_memory_bytes",
+        "gauge",
+        "Current balloon size of the domain.",
+    );
+    for (dom_name, sample) in &samples {
+        writeln!(
+            out,
+            "virt_domain_memory_bytes{{domain=\"{}\"}} {}",
+            escape_label(dom_name),
+            sample.memory * 1024
+        )
+        .unwrap();
+    }
+
+    let disk_counters = [
+        (
+            "virt_domain_disk_read_bytes_total",
+            "Bytes read from the disk.",
+        ),
+        (
+            "virt_domain_disk_write_bytes_total",
+            "Bytes written to the disk.",
+        ),
+        (
+            "virt_domain_disk_read_requests_total",
+            "Read requests to the disk.",
+        ),
+        (
+            "virt_domain_disk_write_requests_total",
+            "Write requests to the disk.",
+        ),
+    ];
+    for (i, (name, help)) in disk_counters.iter().enumerate() {
+        family(out, name, "counter", help);
+        for (dom_name, sample) in &samples {
+            for disk in &sample.disks {
+                let value = [
+                    disk.read_bytes,
+                    disk.write_bytes,
+                    disk.read_reqs,
+                    disk.write_reqs,
+                ][i];
+                writeln!(
+                    out,
+                    "{}{{domain=\"{}\",device=\"{}\"}} {}",
+                    name,
+                    escape_label(dom_name),
+                    escape_label(&disk.name),
+                    value
+                )
+                .unwrap();
+            }
+        }
+    }
+    let nic_counters = [
+        (
+            "virt_domain_network_receive_bytes_total",
+            "Bytes received by the interface.",
+        ),
+        (
+            "virt_domain_network_transmit_bytes_total",
+            "Bytes sent by the interface.",
+        ),
+    ];
+    for (i, (name, help)) in nic_counters.iter().enumerate() {
+        family(out, name, "counter", help);
+        for (dom_name, sample) in &samples {
+            for nic in &sample.interfaces {
+                let value = [nic.read_bytes, nic.write_bytes][i];
+                writeln!(
+                    out,
+                    "{}{{domain=\"{}\",interface=\"{}\"}} {}",
+                    name,
+                    escape_label(dom_name),
+                    escape_label(&nic.name),
+                    value
+                )
+                .unwrap();
+            }
+        }
+    }
+}
+
+// prometheus text exposition format
+#[get("/metrics")]
+pub fn get_metrics(
+    _token: MetricsToken,
+    conn: &State<VirtConnect>,
+    stats: &State<StatsConnect>,
+    metrics: &State<Metrics>,
+) -> (Status, String) {
+    let (status, snapshots) = run_virt_command(conn, VirtCommandType::CountSnapshots, Vec::new());
+    if status != Status::Ok {
+        return (status, snapshots.0);
+    }
+    let snapshots: HashMap<String, usize> = serde_json::from_str(&snapshots.0).unwrap();
+    let mut out = String::new();
+    render_host(&mut out, stats);
+    render_domains(&mut out, stats, &snapshots);
+    metrics.render(&mut out);
+    (Status::Ok, out)
+}
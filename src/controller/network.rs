@@ -0,0 +1,47 @@
+use rocket::{http::Status, response::content, serde::json::Json, State};
+
+use crate::{
+    controller::virt::run_virt_command,
+    middleware::authenticate::JWT,
+    virt::{shell, VirtCommandType, VirtConnect},
+};
+
+#[get("/list")]
+pub fn list_networks(_jwt: JWT, conn: &State<VirtConnect>) -> (Status, content::RawJson<String>) {
+    run_virt_command(conn, VirtCommandType::ListNetworks, Vec::new())
+}
+
+#[post("/create", format = "application/json", data = "<configure>")]
+pub fn create_network(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    configure: String,
+) -> (Status, content::RawJson<String>) {
+    run_virt_command(conn, VirtCommandType::CreateNetwork, vec![configure])
+}
+
+#[post("/destroy", format = "application/json", data = "<net_name>")]
+pub fn destroy_network(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    net_name: Json<String>,
+) -> (Status, content::RawJson<String>) {
+    run_virt_command(conn, VirtCommandType::DestroyNetwork, vec![net_name.0])
+}
+
+#[post("/undefine", format = "application/json", data = "<net_name>")]
+pub fn undefine_network(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    net_name: Json<String>,
+) -> (Status, content::RawJson<String>) {
+    run_virt_command(conn, VirtCommandType::UndefineNetwork, vec![net_name.0])
+}
+
+#[post("/dhcp-leases", format = "application/json", data = "<net_name>")]
+pub fn list_dhcp_leases(_jwt: JWT, net_name: Json<String>) -> (Status, content::RawJson<String>) {
+    match shell::list_dhcp_leases(&net_name.0) {
+        Ok(output) => (Status::Ok, content::RawJson(output)),
+        Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
+    }
+}
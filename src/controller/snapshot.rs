@@ -1,25 +1,72 @@
 use rocket::{http::Status, response::content, serde::json::Json, State};
+use serde_json::{json, Value};
 
 use crate::{
+    controller::{
+        tasks::task_accepted,
+        virt::{lock_domain, run_virt_command},
+    },
     db::entity::{prelude::*, *},
+    events::{DomainEventKind, EventConnect},
+    locks::DomainLocks,
     middleware::authenticate::JWT,
     scheduler::{SchedCommand, SchedConnect, SchedTaskConfig},
-    virt::{shell, SnapShotConfig, VirtCommand, VirtCommandType, VirtConnect},
+    tasks::TaskManager,
+    virt::{
+        pending_operations, shell, ExternalSnapshotConfig, RevertConfig, SnapShotConfig,
+        SnapShotEditConfig, VirtCommand, VirtCommandType, VirtConnect,
+    },
 };
 
-use sea_orm::{ActiveValue, ColumnTrait, DatabaseConnection, EntityTrait, ModelTrait, QueryFilter};
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, ModelTrait,
+    QueryFilter,
+};
+
+// display names and labels are ours, libvirt has no place for them
+async fn snapshot_meta(
+    db: &DatabaseConnection,
+    dom_names: Vec<String>,
+) -> Result<Vec<snapshot_meta::Model>, DbErr> {
+    SnapshotMeta::find()
+        .filter(snapshot_meta::Column::DomName.is_in(dom_names))
+        .all(db)
+        .await
+}
+
+// adds displayName and labels to every snapshot object of a listing, found by
+// its name and looking into its children too
+fn add_snapshot_meta(snapshots: &mut Value, dom_name: &str, meta: &[snapshot_meta::Model]) {
+    let Some(snapshots) = snapshots.as_array_mut() else {
+        return;
+    };
+    for snapshot in snapshots {
+        let found = snapshot["name"].as_str().and_then(|name| {
+            meta.iter()
+                .find(|it| it.dom_name == dom_name && it.snapshot_name == name)
+        });
+        snapshot["displayName"] = json!(found.and_then(|it| it.display_name.clone()));
+        snapshot["labels"] = found
+            .and_then(|it| serde_json::from_str(&it.labels).ok())
+            .unwrap_or(json!({}));
+        if let Some(children) = snapshot.get_mut("children") {
+            add_snapshot_meta(children, dom_name, meta);
+        }
+    }
+}
 
 #[post("/list", format = "application/json", data = "<dom_names>")]
-pub fn list_snapshot(
+pub async fn list_snapshot(
     _jwt: JWT,
     conn: &State<VirtConnect>,
+    db: &State<DatabaseConnection>,
     dom_names: Json<Vec<String>>,
 ) -> (Status, content::RawJson<String>) {
     let conn = conn as &VirtConnect;
     let dom_names: Vec<String> = dom_names.0.into_iter().collect();
     if let Err(e) = conn.tx.send(VirtCommand::create_with_params(
         VirtCommandType::ListSnapshot,
-        dom_names,
+        dom_names.clone(),
     )) {
         return (
             Status::InternalServerError,
@@ -28,9 +75,24 @@ pub fn list_snapshot(
             ),
         );
     }
-    match conn.rx.lock().unwrap().recv() {
+    let output = conn.rx.lock().unwrap().recv();
+    match output {
         Ok(output) => match output {
-            Ok(res) => (Status::Ok, content::RawJson(res)),
+            Ok(res) => {
+                let meta = match snapshot_meta(db, dom_names).await {
+                    Ok(meta) => meta,
+                    Err(e) => {
+                        return (Status::InternalServerError, content::RawJson(e.to_string()))
+                    }
+                };
+                let mut listing: Value = serde_json::from_str(&res).unwrap();
+                if let Some(doms) = listing.as_object_mut() {
+                    for (dom_name, snapshots) in doms.iter_mut() {
+                        add_snapshot_meta(snapshots, dom_name, &meta);
+                    }
+                }
+                (Status::Ok, content::RawJson(listing.to_string()))
+            }
             Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
         },
         Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
@@ -38,99 +100,287 @@ pub fn list_snapshot(
 }
 
 #[post("/list-tree", format = "application/json", data = "<dom_name>")]
-pub fn list_snapshot_tree(
+pub async fn list_snapshot_tree(
     _jwt: JWT,
     conn: &State<VirtConnect>,
+    db: &State<DatabaseConnection>,
     dom_name: Json<String>,
 ) -> (Status, content::RawJson<String>) {
-    let conn = conn as &VirtConnect;
-    if let Err(e) = conn.tx.send(VirtCommand::create_with_params(
+    let (status, output) = run_virt_command(
+        conn,
         VirtCommandType::ListSnapshotTree,
-        vec![dom_name.0],
-    )) {
-        return (
-            Status::InternalServerError,
-            content::RawJson(
-                String::from("Error sending VirtCommand to LibVirt Thread:") + &e.to_string(),
-            ),
-        );
-    }
-    match conn.rx.lock().unwrap().recv() {
-        Ok(output) => match output {
-            Ok(res) => (Status::Ok, content::RawJson(res)),
-            Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
-        },
-        Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
+        vec![dom_name.0.clone()],
+    );
+    if status != Status::Ok {
+        return (status, output);
     }
+    let meta = match snapshot_meta(db, vec![dom_name.0.clone()]).await {
+        Ok(meta) => meta,
+        Err(e) => return (Status::InternalServerError, content::RawJson(e.to_string())),
+    };
+    let mut tree: Value = serde_json::from_str(&output.0).unwrap();
+    add_snapshot_meta(&mut tree["tree"], &dom_name, &meta);
+    (Status::Ok, content::RawJson(tree.to_string()))
 }
 
 #[post("/create", format = "application/json", data = "<configure>")]
-pub fn create_snapshot(
+pub async fn create_snapshot(
     _jwt: JWT,
+    events: &State<EventConnect>,
+    locks: &State<DomainLocks>,
+    tasks: &State<TaskManager>,
     configure: Json<SnapShotConfig>,
 ) -> (Status, content::RawJson<String>) {
-    match shell::create_snapshot(configure.0) {
-        Ok(output) => (Status::Ok, content::RawJson(output)),
-        Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
-    }
+    let lock = match lock_domain(locks, &configure.dom_name, "create-snapshot").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    let events = (events as &EventConnect).clone();
+    let (dom_name, snapshot_name) = (configure.dom_name.clone(), configure.snapshot_name.clone());
+    let res = tasks
+        .submit("create-snapshot", &dom_name.clone(), lock, move || {
+            let output = shell::create_snapshot(configure.0)?;
+            events.publish(&dom_name, DomainEventKind::SnapshotCreated, &snapshot_name);
+            Ok(output)
+        })
+        .await;
+    task_accepted(res)
+}
+
+#[post("/create-external", format = "application/json", data = "<configure>")]
+pub async fn create_external_snapshot(
+    _jwt: JWT,
+    events: &State<EventConnect>,
+    locks: &State<DomainLocks>,
+    tasks: &State<TaskManager>,
+    configure: Json<ExternalSnapshotConfig>,
+) -> (Status, content::RawJson<String>) {
+    let lock = match lock_domain(locks, &configure.dom_name, "create-snapshot").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    let events = (events as &EventConnect).clone();
+    let (dom_name, snapshot_name) = (configure.dom_name.clone(), configure.snapshot_name.clone());
+    let res = tasks
+        .submit(
+            "create-external-snapshot",
+            &dom_name.clone(),
+            lock,
+            move || {
+                let output = shell::create_external_snapshot(configure.0)?;
+                events.publish(&dom_name, DomainEventKind::SnapshotCreated, &snapshot_name);
+                Ok(output)
+            },
+        )
+        .await;
+    task_accepted(res)
 }
 
 #[post("/delete", format = "application/json", data = "<configure>")]
-pub fn delete_snapshot(
+pub async fn delete_snapshot(
     _jwt: JWT,
+    db: &State<DatabaseConnection>,
+    locks: &State<DomainLocks>,
     configure: Json<SnapShotConfig>,
 ) -> (Status, content::RawJson<String>) {
+    let _lock = match lock_domain(locks, &configure.dom_name, "delete-snapshot").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    let (dom_name, snapshot_name) = (configure.dom_name.clone(), configure.snapshot_name.clone());
     match shell::delete_snapshot(configure.0) {
-        Ok(output) => (Status::Ok, content::RawJson(output)),
+        Ok(output) => {
+            let _ = SnapshotMeta::delete_many()
+                .filter(snapshot_meta::Column::DomName.eq(dom_name))
+                .filter(snapshot_meta::Column::SnapshotName.eq(snapshot_name))
+                .exec(db as &DatabaseConnection)
+                .await;
+            (Status::Ok, content::RawJson(output))
+        }
         Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
     }
 }
 
+// description and parent are redefined in libvirt, which reports what it won't
+// take; display name and labels are stored next to the snapshot
 #[post("/edit", format = "application/json", data = "<configure>")]
-pub fn edit_snapshot(
+pub async fn edit_snapshot(
     _jwt: JWT,
     conn: &State<VirtConnect>,
-    configure: String,
+    db: &State<DatabaseConnection>,
+    locks: &State<DomainLocks>,
+    configure: Json<SnapShotEditConfig>,
 ) -> (Status, content::RawJson<String>) {
-    let conn = conn as &VirtConnect;
-    if let Err(e) = conn.tx.send(VirtCommand::create_with_params(
+    let _lock = match lock_domain(locks, &configure.dom_name, "edit-snapshot").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    let (status, output) = run_virt_command(
+        conn,
         VirtCommandType::EditSnapshot,
-        vec![configure],
-    )) {
-        return (
-            Status::InternalServerError,
-            content::RawJson(
-                String::from("Error sending VirtCommand to LibVirt Thread:") + &e.to_string(),
-            ),
-        );
-    }
-    match conn.rx.lock().unwrap().recv() {
-        Ok(output) => match output {
-            Ok(res) => (Status::Ok, content::RawJson(res)),
-            Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
-        },
-        Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
+        vec![serde_json::to_string(&configure.0).unwrap()],
+    );
+    if status != Status::Ok {
+        return (status, output);
     }
+    let db = db as &DatabaseConnection;
+    let existing = match SnapshotMeta::find()
+        .filter(snapshot_meta::Column::DomName.eq(&configure.dom_name))
+        .filter(snapshot_meta::Column::SnapshotName.eq(&configure.snapshot_name))
+        .one(db)
+        .await
+    {
+        Ok(existing) => existing,
+        Err(e) => return (Status::InternalServerError, content::RawJson(e.to_string())),
+    };
+    let meta = match (&configure.display_name, &configure.labels) {
+        (None, None) => Ok(existing),
+        _ => {
+            let display_name = configure
+                .display_name
+                .clone()
+                .map(|it| Some(it).filter(|it| !it.is_empty()));
+            let labels = configure
+                .labels
+                .as_ref()
+                .map(|it| serde_json::to_string(it).unwrap());
+            let saved = match existing {
+                Some(existing) => {
+                    let mut model: snapshot_meta::ActiveModel = existing.into();
+                    if let Some(display_name) = display_name {
+                        model.display_name = ActiveValue::set(display_name);
+                    }
+                    if let Some(labels) = labels {
+                        model.labels = ActiveValue::set(labels);
+                    }
+                    model.update(db).await
+                }
+                None => {
+                    snapshot_meta::ActiveModel {
+                        dom_name: ActiveValue::set(configure.dom_name.clone()),
+                        snapshot_name: ActiveValue::set(configure.snapshot_name.clone()),
+                        display_name: ActiveValue::set(display_name.flatten()),
+                        labels: ActiveValue::set(labels.unwrap_or("{}".to_string())),
+                        ..Default::default()
+                    }
+                    .insert(db)
+                    .await
+                }
+            };
+            saved.map(Some)
+        }
+    };
+    let meta = match meta {
+        Ok(meta) => meta,
+        Err(e) => return (Status::InternalServerError, content::RawJson(e.to_string())),
+    };
+    let mut edited: Value = serde_json::from_str(&output.0).unwrap();
+    edited["displayName"] = json!(meta.as_ref().and_then(|it| it.display_name.clone()));
+    edited["labels"] = meta
+        .and_then(|it| serde_json::from_str(&it.labels).ok())
+        .unwrap_or(json!({}));
+    (Status::Ok, content::RawJson(edited.to_string()))
+}
+
+// what changed between two snapshots of a domain, see virt::snapshot::SnapshotDiff
+#[post("/diff", format = "application/json", data = "<configure>")]
+pub fn diff_snapshots(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    configure: String,
+) -> (Status, content::RawJson<String>) {
+    run_virt_command(conn, VirtCommandType::DiffSnapshots, vec![configure])
 }
 
 #[post("/clone-as-vm", format = "application/json", data = "<configure>")]
-pub fn clone_snapshot_as_vm(
+pub async fn clone_snapshot_as_vm(
     _jwt: JWT,
+    locks: &State<DomainLocks>,
+    tasks: &State<TaskManager>,
     configure: Json<SnapShotConfig>,
 ) -> (Status, content::RawJson<String>) {
-    match shell::clone_snapshot_as_vm(configure.0) {
-        Ok(output) => (Status::Ok, content::RawJson(output)),
+    let lock = match lock_domain(locks, &configure.dom_name, "clone-snapshot").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    let dom_name = configure.dom_name.clone();
+    let res = tasks
+        .submit("clone-snapshot", &dom_name, lock, move || {
+            shell::clone_snapshot_as_vm(configure.0)
+        })
+        .await;
+    task_accepted(res)
+}
+
+// workflows still holding a temp snapshot, normally empty
+#[get("/operations")]
+pub fn list_snapshot_operations(_jwt: JWT) -> (Status, content::RawJson<String>) {
+    (
+        Status::Ok,
+        content::RawJson(serde_json::to_string(&pending_operations()).unwrap()),
+    )
+}
+
+// saves the current state as a pre-revert snapshot before reverting unless
+// save_current is off; dry_run only reports what would be lost
+#[post("/set-current", format = "application/json", data = "<configure>")]
+pub async fn set_current_snapshot(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    events: &State<EventConnect>,
+    locks: &State<DomainLocks>,
+    configure: Json<RevertConfig>,
+) -> (Status, content::RawJson<String>) {
+    if configure.dry_run == Some(true) {
+        return run_virt_command(
+            conn,
+            VirtCommandType::PreviewRevert,
+            vec![serde_json::to_string(&configure.0).unwrap()],
+        );
+    }
+    let _lock = match lock_domain(locks, &configure.dom_name, "revert-snapshot").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    let (dom_name, snapshot_name) = (configure.dom_name.clone(), configure.snapshot_name.clone());
+    // virsh blocks for the whole revert, keep it off the async workers
+    match tokio::task::spawn_blocking(move || shell::revert_snapshot(configure.0)).await {
+        Ok(Ok(output)) => {
+            events.publish(&dom_name, DomainEventKind::SnapshotReverted, &snapshot_name);
+            (Status::Ok, content::RawJson(output))
+        }
+        Ok(Err(e)) => (Status::InternalServerError, content::RawJson(e.to_string())),
         Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
     }
 }
 
-#[post("/set-current", format = "application/json", data = "<configure>")]
-pub fn set_current_snapshot(
+#[post("/undo-revert", format = "application/json", data = "<dom_name>")]
+pub async fn undo_revert(
     _jwt: JWT,
-    configure: Json<SnapShotConfig>,
+    events: &State<EventConnect>,
+    locks: &State<DomainLocks>,
+    dom_name: Json<String>,
 ) -> (Status, content::RawJson<String>) {
-    match shell::set_current_snapshot(configure.0) {
-        Ok(output) => (Status::Ok, content::RawJson(output)),
+    let _lock = match lock_domain(locks, &dom_name, "undo-revert").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    let undo = {
+        let dom_name = dom_name.0.clone();
+        tokio::task::spawn_blocking(move || shell::undo_revert(&dom_name)).await
+    };
+    match undo {
+        Ok(Ok(saved)) => {
+            events.publish(&dom_name, DomainEventKind::SnapshotReverted, &saved);
+            (
+                Status::Ok,
+                content::RawJson(format!("Back at the state saved as {}", saved)),
+            )
+        }
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            (Status::NotFound, content::RawJson(e.to_string()))
+        }
+        Ok(Err(e)) => (Status::InternalServerError, content::RawJson(e.to_string())),
         Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
     }
 }
@@ -0,0 +1,30 @@
+use rocket::{http::Status, response::content, State};
+
+use crate::{middleware::authenticate::JWT, stats::StatsConnect};
+
+const DEFAULT_WINDOW: u64 = 3600;
+
+// window and resolution are in seconds, resolution defaults to the sample interval
+#[get("/domain?<dom_name>&<window>&<resolution>")]
+pub fn get_domain_stats(
+    _jwt: JWT,
+    stats: &State<StatsConnect>,
+    dom_name: String,
+    window: Option<u64>,
+    resolution: Option<u64>,
+) -> (Status, content::RawJson<String>) {
+    match stats.series(
+        &dom_name,
+        window.unwrap_or(DEFAULT_WINDOW),
+        resolution.unwrap_or(0),
+    ) {
+        Some(points) => (
+            Status::Ok,
+            content::RawJson(serde_json::to_string(&points).unwrap()),
+        ),
+        None => (
+            Status::NotFound,
+            content::RawJson(format!("No stats collected for domain {}", dom_name)),
+        ),
+    }
+}
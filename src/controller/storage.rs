@@ -0,0 +1,120 @@
+use rocket::{
+    data::{Data, ToByteUnit},
+    fs::NamedFile,
+    http::Status,
+    response::content,
+    serde::json::Json,
+    State,
+};
+
+use crate::{
+    controller::virt::run_virt_command,
+    middleware::authenticate::JWT,
+    virt::{StorageVolumeConfig, VirtCommandType, VirtConnect},
+};
+
+#[get("/pool/list")]
+pub fn list_pools(_jwt: JWT, conn: &State<VirtConnect>) -> (Status, content::RawJson<String>) {
+    run_virt_command(conn, VirtCommandType::ListPools, Vec::new())
+}
+
+#[post("/pool/create", format = "application/json", data = "<configure>")]
+pub fn create_pool(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    configure: String,
+) -> (Status, content::RawJson<String>) {
+    run_virt_command(conn, VirtCommandType::CreatePool, vec![configure])
+}
+
+#[post("/pool/refresh", format = "application/json", data = "<pool_name>")]
+pub fn refresh_pool(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    pool_name: Json<String>,
+) -> (Status, content::RawJson<String>) {
+    run_virt_command(conn, VirtCommandType::RefreshPool, vec![pool_name.0])
+}
+
+#[post("/volume/list", format = "application/json", data = "<pool_name>")]
+pub fn list_volumes(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    pool_name: Json<String>,
+) -> (Status, content::RawJson<String>) {
+    run_virt_command(conn, VirtCommandType::ListVolumes, vec![pool_name.0])
+}
+
+#[post("/volume/delete", format = "application/json", data = "<configure>")]
+pub fn delete_volume(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    configure: Json<StorageVolumeConfig>,
+) -> (Status, content::RawJson<String>) {
+    let configure = configure.0;
+    run_virt_command(
+        conn,
+        VirtCommandType::DeleteVolume,
+        vec![configure.pool, configure.name],
+    )
+}
+
+#[get("/volume/download/<pool>/<name>")]
+pub async fn download_volume(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    pool: &str,
+    name: &str,
+) -> Result<NamedFile, (Status, String)> {
+    let (status, path) = run_virt_command(
+        conn,
+        VirtCommandType::GetVolumePath,
+        vec![pool.to_string(), name.to_string()],
+    );
+    if status != Status::Ok {
+        return Err((status, path.0));
+    }
+    NamedFile::open(path.0)
+        .await
+        .map_err(|e| (Status::InternalServerError, e.to_string()))
+}
+
+#[post("/volume/upload/<pool>/<name>", data = "<volume>")]
+pub async fn upload_volume(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    pool: &str,
+    name: &str,
+    volume: Data<'_>,
+) -> (Status, String) {
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        return (Status::BadRequest, format!("invalid volume name {}", name));
+    }
+    let (status, pool_path) =
+        run_virt_command(conn, VirtCommandType::GetPoolPath, vec![pool.to_string()]);
+    if status != Status::Ok {
+        return (status, pool_path.0);
+    }
+    let path = std::path::Path::new(&pool_path.0).join(name);
+    if path.exists() {
+        return (
+            Status::BadRequest,
+            format!("volume {} already exists", name),
+        );
+    }
+    match volume.open(64.gibibytes()).into_file(&path).await {
+        Ok(file) if file.is_complete() => (),
+        Ok(_) => {
+            let _ = std::fs::remove_file(&path);
+            return (
+                Status::PayloadTooLarge,
+                "volume exceeds the upload limit".to_string(),
+            );
+        }
+        Err(e) => return (Status::InsufficientStorage, e.to_string()),
+    }
+    // make libvirt pick up the new file as a volume
+    let (status, output) =
+        run_virt_command(conn, VirtCommandType::RefreshPool, vec![pool.to_string()]);
+    (status, output.0)
+}
@@ -1,20 +1,63 @@
 use crate::{
+    controller::virt::run_virt_command,
     middleware::authenticate::JWT,
-    virt::{VirtCommand, VirtCommandType, VirtConnect},
+    stats::StatsConnect,
+    virt::{VirtCommandType, VirtConnect},
 };
 use rocket::{http::Status, response::content, State};
+use serde_json::Value;
+use std::path::Path;
+
+const DEFAULT_WINDOW: u64 = 3600;
 
 #[get("/utilization/get")]
-pub fn get_sys_utilization(_jwt: JWT, conn: &State<VirtConnect>) -> (Status, content::RawJson<String>) {
-    let conn = conn as &VirtConnect;
-    conn.tx
-        .send(VirtCommand::create(VirtCommandType::SysInfo))
-        .unwrap();
-    match conn.rx.lock().unwrap().recv() {
-        Ok(output) => match output {
-            Ok(res) => (Status::Ok, content::RawJson(res)),
-            Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
-        },
-        Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
+pub fn get_sys_utilization(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    stats: &State<StatsConnect>,
+) -> (Status, content::RawJson<String>) {
+    let Some(status) = stats.host_latest() else {
+        return (
+            Status::ServiceUnavailable,
+            content::RawJson("Host not sampled yet".to_string()),
+        );
+    };
+    let (code, versions) = run_virt_command(conn, VirtCommandType::HostVersions, Vec::new());
+    if code != Status::Ok {
+        return (code, versions);
+    }
+    let mut t = serde_json::to_value(&status).unwrap();
+    let versions: Value = serde_json::from_str(&versions.0).unwrap();
+    // the filesystem holding the vm images is the one users care about most;
+    // compared by component so /data doesn't claim /database
+    let storage_filesystem = versions["storagePath"].as_str().and_then(|path| {
+        status
+            .filesystems
+            .iter()
+            .filter(|it| Path::new(path).starts_with(&it.mount_point))
+            .max_by_key(|it| it.mount_point.len())
+            .map(|it| it.mount_point.clone())
+    });
+    if let (Value::Object(t), Value::Object(versions)) = (&mut t, versions) {
+        t.extend(versions);
+        t.insert(
+            "storageFilesystem".to_string(),
+            serde_json::json!(storage_filesystem),
+        );
     }
+    (Status::Ok, content::RawJson(t.to_string()))
+}
+
+// sampled in the background, so graphs don't depend on how often clients poll
+#[get("/utilization/history?<window>")]
+pub fn get_sys_history(
+    _jwt: JWT,
+    stats: &State<StatsConnect>,
+    window: Option<u64>,
+) -> (Status, content::RawJson<String>) {
+    let series = stats.host_series(window.unwrap_or(DEFAULT_WINDOW));
+    (
+        Status::Ok,
+        content::RawJson(serde_json::to_string(&series).unwrap()),
+    )
 }
@@ -0,0 +1,72 @@
+use rocket::{http::Status, response::content, serde::json::Json, State};
+use sea_orm::DbErr;
+use serde_json::json;
+
+use crate::{
+    middleware::authenticate::JWT,
+    tasks::{TaskError, TaskManager},
+};
+
+// what routes that start a task answer with, the caller polls /api/v1/task/get
+pub fn task_accepted(res: Result<i32, DbErr>) -> (Status, content::RawJson<String>) {
+    match res {
+        Ok(id) => (
+            Status::Accepted,
+            content::RawJson(json!({ "taskId": id }).to_string()),
+        ),
+        Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
+    }
+}
+
+fn task_error(e: TaskError) -> (Status, content::RawJson<String>) {
+    match e {
+        TaskError::NotFound(_) => (Status::NotFound, content::RawJson(e.to_string())),
+        TaskError::Finished(..) | TaskError::NotCancellable(..) => {
+            (Status::Conflict, content::RawJson(e.to_string()))
+        }
+        TaskError::Db(_) => (Status::InternalServerError, content::RawJson(e.to_string())),
+    }
+}
+
+#[get("/list?<state>&<dom_name>")]
+pub async fn list_tasks(
+    _jwt: JWT,
+    tasks: &State<TaskManager>,
+    state: Option<String>,
+    dom_name: Option<String>,
+) -> (Status, content::RawJson<String>) {
+    match tasks.list(state, dom_name).await {
+        Ok(tasks) => (
+            Status::Ok,
+            content::RawJson(serde_json::to_string(&tasks).unwrap()),
+        ),
+        Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
+    }
+}
+
+#[post("/get", format = "application/json", data = "<id>")]
+pub async fn get_task(
+    _jwt: JWT,
+    tasks: &State<TaskManager>,
+    id: Json<i32>,
+) -> (Status, content::RawJson<String>) {
+    match tasks.get(id.0).await {
+        Ok(task) => (
+            Status::Ok,
+            content::RawJson(serde_json::to_string(&task).unwrap()),
+        ),
+        Err(e) => task_error(e),
+    }
+}
+
+#[post("/cancel", format = "application/json", data = "<id>")]
+pub async fn cancel_task(
+    _jwt: JWT,
+    tasks: &State<TaskManager>,
+    id: Json<i32>,
+) -> (Status, content::RawJson<String>) {
+    match tasks.cancel(id.0).await {
+        Ok(output) => (Status::Ok, content::RawJson(output)),
+        Err(e) => task_error(e),
+    }
+}
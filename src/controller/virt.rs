@@ -2,19 +2,41 @@ use rocket::{
     data::{Data, ToByteUnit},
     http::Status,
     response::content,
-    serde::json::Json,
+    serde::json::{self, Json},
     State,
 };
+use serde_json::json;
+use std::time::{Duration, Instant};
 
 use crate::{
+    controller::tasks::task_accepted,
+    db::entity::{prelude::*, *},
+    locks::{DomainLock, DomainLocks},
     middleware::authenticate::JWT,
-    virt::{shell, AltDomStateCommand, VirtCommand, VirtCommandType, VirtConnect},
+    scheduler::{SchedCommand, SchedConnect},
+    tasks::TaskManager,
+    virt::{
+        shell, AltDomStateCommand, CreateVirtConfig, DeleteDomainConfig, DomStateResult,
+        DomainAction, RenameDomainConfig, VirtCommand, VirtCommandType, VirtConnect, VirtError,
+    },
 };
 
-#[get("/list")]
-pub fn list_domains(_jwt: JWT, conn: &State<VirtConnect>) -> (Status, content::RawJson<String>) {
+use sea_orm::{
+    sea_query::Expr, ColumnTrait, DatabaseConnection, EntityTrait, ModelTrait, QueryFilter,
+};
+
+// label selectors are "key=value" or "key", e.g. /list?label=env=prod&label=team
+#[get("/list?<label>")]
+pub fn list_domains(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    label: Vec<String>,
+) -> (Status, content::RawJson<String>) {
     let conn = conn as &VirtConnect;
-    if let Err(e) = conn.tx.send(VirtCommand::create(VirtCommandType::ListAll)) {
+    if let Err(e) = conn.tx.send(VirtCommand::create_with_params(
+        VirtCommandType::ListAll,
+        label,
+    )) {
         return (
             Status::InternalServerError,
             content::RawJson(
@@ -42,10 +64,397 @@ pub async fn upload_iso(isofile: Data<'_>) -> (Status, String) {
 #[post("/set-state", data = "<config>")]
 pub async fn set_domain_state(
     _jwt: JWT,
-    config: Json<AltDomStateCommand>,
+    conn: &State<VirtConnect>,
+    locks: &State<DomainLocks>,
+    config: Result<Json<AltDomStateCommand>, json::Error<'_>>,
+) -> (Status, content::RawJson<String>) {
+    // unknown actions fail to deserialize, report them as a bad request
+    let config = match config {
+        Ok(config) => config.0,
+        Err(e) => return (Status::BadRequest, content::RawJson(e.to_string())),
+    };
+    let _lock = match lock_domain(locks, &config.dom_name, "set-state").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    change_domain_state(conn, config).await
+}
+
+// shared with bulk operations, a shutdown with a timeout escalates to destroy
+pub async fn change_domain_state(
+    conn: &VirtConnect,
+    config: AltDomStateCommand,
+) -> (Status, content::RawJson<String>) {
+    let res = run_virt_command(
+        conn,
+        VirtCommandType::SetDomainState,
+        vec![serde_json::to_string(&config).unwrap()],
+    );
+    let timeout = match (config.state, config.timeout) {
+        (DomainAction::Shutdown, Some(timeout)) if res.0 == Status::Ok => {
+            Duration::from_secs(timeout)
+        }
+        _ => return res,
+    };
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let (status, state) = run_virt_command(
+            conn,
+            VirtCommandType::GetDomainState,
+            vec![config.dom_name.clone()],
+        );
+        if status != Status::Ok {
+            return (status, state);
+        }
+        if state.0 == "shutoff" {
+            let result = DomStateResult {
+                state: state.0,
+                forced: false,
+            };
+            return (
+                Status::Ok,
+                content::RawJson(serde_json::to_string(&result).unwrap()),
+            );
+        }
+    }
+    // the guest didn't stop in time, pull the plug
+    let destroy = AltDomStateCommand {
+        dom_name: config.dom_name,
+        state: DomainAction::Destroy,
+        timeout: None,
+    };
+    let (status, output) = run_virt_command(
+        conn,
+        VirtCommandType::SetDomainState,
+        vec![serde_json::to_string(&destroy).unwrap()],
+    );
+    if status != Status::Ok {
+        return (status, output);
+    }
+    let result = DomStateResult {
+        state: "shutoff".to_string(),
+        forced: true,
+    };
+    (
+        Status::Ok,
+        content::RawJson(serde_json::to_string(&result).unwrap()),
+    )
+}
+
+#[post("/delete", format = "application/json", data = "<config>")]
+pub async fn delete_domain(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    db: &State<DatabaseConnection>,
+    sched: &State<SchedConnect>,
+    locks: &State<DomainLocks>,
+    config: Json<DeleteDomainConfig>,
+) -> (Status, content::RawJson<String>) {
+    let config = config.0;
+    let sched = sched as &SchedConnect;
+    let db = db as &DatabaseConnection;
+    let _lock = match lock_domain(locks, &config.dom_name, "delete").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    let res = run_virt_command(
+        conn,
+        VirtCommandType::DeleteDomain,
+        vec![serde_json::to_string(&config).unwrap()],
+    );
+    if res.0 != Status::Ok {
+        return res;
+    }
+    // the domain is gone from libvirt, drop everything we keyed on its name
+    let jobs = match ScheduleJobs::find()
+        .filter(schedule_jobs::Column::Domain.eq(&config.dom_name))
+        .all(db)
+        .await
+    {
+        Ok(jobs) => jobs,
+        Err(e) => return (Status::InternalServerError, content::RawJson(e.to_string())),
+    };
+    for job in jobs {
+        sched
+            .tx
+            .send(SchedCommand::Delete(job.uuid.clone()))
+            .await
+            .unwrap();
+        let _ = sched.rx.lock().await.recv().await.unwrap();
+        if let Err(e) = job.delete(db).await {
+            return (Status::InternalServerError, content::RawJson(e.to_string()));
+        }
+    }
+    if let Err(e) = Domains::delete_many()
+        .filter(domains::Column::Name.eq(&config.dom_name))
+        .exec(db)
+        .await
+    {
+        return (Status::InternalServerError, content::RawJson(e.to_string()));
+    }
+    if let Err(e) = SnapshotMeta::delete_many()
+        .filter(snapshot_meta::Column::DomName.eq(&config.dom_name))
+        .exec(db)
+        .await
+    {
+        return (Status::InternalServerError, content::RawJson(e.to_string()));
+    }
+    res
+}
+
+#[post("/rename", format = "application/json", data = "<config>")]
+pub async fn rename_domain(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    db: &State<DatabaseConnection>,
+    locks: &State<DomainLocks>,
+    config: Json<RenameDomainConfig>,
 ) -> (Status, content::RawJson<String>) {
-    match shell::alt_vm_state(config.0) {
-        Ok(output) => (Status::Ok, content::RawJson(output)),
+    let config = config.0;
+    let db = db as &DatabaseConnection;
+    let _lock = match lock_domain(locks, &config.dom_name, "rename").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    let res = run_virt_command(
+        conn,
+        VirtCommandType::RenameDomain,
+        vec![serde_json::to_string(&config).unwrap()],
+    );
+    if res.0 != Status::Ok {
+        return res;
+    }
+    // rows keyed on the domain name follow it to the new one
+    if let Err(e) = ScheduleJobs::update_many()
+        .col_expr(
+            schedule_jobs::Column::Domain,
+            Expr::value(config.new_name.clone()),
+        )
+        .filter(schedule_jobs::Column::Domain.eq(&config.dom_name))
+        .exec(db)
+        .await
+    {
+        return (Status::InternalServerError, content::RawJson(e.to_string()));
+    }
+    if let Err(e) = Domains::update_many()
+        .col_expr(domains::Column::Name, Expr::value(config.new_name.clone()))
+        .filter(domains::Column::Name.eq(&config.dom_name))
+        .exec(db)
+        .await
+    {
+        return (Status::InternalServerError, content::RawJson(e.to_string()));
+    }
+    // incremental backups build on the chain of the domain's name
+    if let Err(e) = Backups::update_many()
+        .col_expr(
+            backups::Column::DomName,
+            Expr::value(config.new_name.clone()),
+        )
+        .filter(backups::Column::DomName.eq(&config.dom_name))
+        .exec(db)
+        .await
+    {
+        return (Status::InternalServerError, content::RawJson(e.to_string()));
+    }
+    if let Err(e) = SnapshotMeta::update_many()
+        .col_expr(
+            snapshot_meta::Column::DomName,
+            Expr::value(config.new_name.clone()),
+        )
+        .filter(snapshot_meta::Column::DomName.eq(&config.dom_name))
+        .exec(db)
+        .await
+    {
+        return (Status::InternalServerError, content::RawJson(e.to_string()));
+    }
+    res
+}
+
+#[post("/autostart/get", format = "application/json", data = "<dom_name>")]
+pub fn get_autostart(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    dom_name: Json<String>,
+) -> (Status, content::RawJson<String>) {
+    run_virt_command(conn, VirtCommandType::GetAutostart, vec![dom_name.0])
+}
+
+#[post("/autostart/set", format = "application/json", data = "<configure>")]
+pub async fn set_autostart(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    locks: &State<DomainLocks>,
+    configure: String,
+) -> (Status, content::RawJson<String>) {
+    let _lock = match lock_domain(locks, &config_dom_name(&configure), "set-autostart").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    run_virt_command(conn, VirtCommandType::SetAutostart, vec![configure])
+}
+
+#[post("/create", format = "application/json", data = "<configure>")]
+pub async fn create_domain(
+    _jwt: JWT,
+    locks: &State<DomainLocks>,
+    tasks: &State<TaskManager>,
+    configure: Json<CreateVirtConfig>,
+) -> (Status, content::RawJson<String>) {
+    // held on the new name, so two creates of the same domain can't race
+    let lock = match lock_domain(locks, &configure.virt_name, "create").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    let dom_name = configure.virt_name.clone();
+    let res = tasks
+        .submit("create", &dom_name, lock, move || {
+            shell::create_virt(configure.0)
+        })
+        .await;
+    task_accepted(res)
+}
+
+pub fn run_virt_command(
+    conn: &VirtConnect,
+    cmd: VirtCommandType,
+    params: Vec<String>,
+) -> (Status, content::RawJson<String>) {
+    if let Err(e) = conn.tx.send(VirtCommand::create_with_params(cmd, params)) {
+        return (
+            Status::InternalServerError,
+            content::RawJson(
+                String::from("Error sending VirtCommand to LibVirt Thread:") + &e.to_string(),
+            ),
+        );
+    }
+    match conn.rx.lock().unwrap().recv() {
+        Ok(output) => match output {
+            Ok(res) => (Status::Ok, content::RawJson(res)),
+            Err(e @ (VirtError::InvalidInput | VirtError::InvalidConfig(_))) => {
+                (Status::BadRequest, content::RawJson(e.to_string()))
+            }
+            Err(e @ VirtError::InvalidState { .. }) => {
+                (Status::Conflict, content::RawJson(e.to_string()))
+            }
+            Err(
+                e @ (VirtError::DomainNotFound(_)
+                | VirtError::PoolNotFound(_)
+                | VirtError::VolumeNotFound { .. }
+                | VirtError::NetworkNotFound(_)),
+            ) => (Status::NotFound, content::RawJson(e.to_string())),
+            Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
+        },
         Err(e) => (Status::InternalServerError, content::RawJson(e.to_string())),
     }
 }
+
+// a busy domain answers 409 with whoever holds it, unless DOMAIN_LOCK_WAIT lets
+// the request queue for a while
+pub async fn lock_domain(
+    locks: &DomainLocks,
+    dom_name: &str,
+    operation: &str,
+) -> Result<DomainLock, (Status, content::RawJson<String>)> {
+    locks.acquire(dom_name, operation).await.map_err(|holder| {
+        let mut body = serde_json::to_value(&holder).unwrap();
+        body["message"] = json!(format!(
+            "Domain {} is busy with {}",
+            holder.dom_name, holder.operation
+        ));
+        (Status::Conflict, content::RawJson(body.to_string()))
+    })
+}
+
+// raw configure bodies are parsed on the libvirt thread, peek at the domain here
+pub fn config_dom_name(configure: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(configure)
+        .ok()
+        .and_then(|it| it["dom_name"].as_str().map(String::from))
+        .unwrap_or_default()
+}
+
+#[get("/locks")]
+pub fn list_domain_locks(
+    _jwt: JWT,
+    locks: &State<DomainLocks>,
+) -> (Status, content::RawJson<String>) {
+    (
+        Status::Ok,
+        content::RawJson(serde_json::to_string(&locks.held()).unwrap()),
+    )
+}
+
+#[post("/edit-hardware", format = "application/json", data = "<configure>")]
+pub async fn edit_hardware(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    locks: &State<DomainLocks>,
+    configure: String,
+) -> (Status, content::RawJson<String>) {
+    let _lock = match lock_domain(locks, &config_dom_name(&configure), "edit-hardware").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    run_virt_command(conn, VirtCommandType::EditHardware, vec![configure])
+}
+
+#[post("/interface/list", format = "application/json", data = "<dom_name>")]
+pub fn list_interfaces(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    dom_name: Json<String>,
+) -> (Status, content::RawJson<String>) {
+    run_virt_command(conn, VirtCommandType::ListInterfaces, vec![dom_name.0])
+}
+
+#[post("/interface/attach", format = "application/json", data = "<configure>")]
+pub async fn attach_interface(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    locks: &State<DomainLocks>,
+    configure: String,
+) -> (Status, content::RawJson<String>) {
+    let _lock = match lock_domain(locks, &config_dom_name(&configure), "attach-interface").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    run_virt_command(conn, VirtCommandType::AttachInterface, vec![configure])
+}
+
+#[post("/interface/detach", format = "application/json", data = "<configure>")]
+pub async fn detach_interface(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    locks: &State<DomainLocks>,
+    configure: String,
+) -> (Status, content::RawJson<String>) {
+    let _lock = match lock_domain(locks, &config_dom_name(&configure), "detach-interface").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    run_virt_command(conn, VirtCommandType::DetachInterface, vec![configure])
+}
+
+#[post("/metadata/get", format = "application/json", data = "<dom_name>")]
+pub fn get_domain_metadata(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    dom_name: Json<String>,
+) -> (Status, content::RawJson<String>) {
+    run_virt_command(conn, VirtCommandType::GetDomainMetadata, vec![dom_name.0])
+}
+
+#[post("/metadata/set", format = "application/json", data = "<configure>")]
+pub async fn set_domain_metadata(
+    _jwt: JWT,
+    conn: &State<VirtConnect>,
+    locks: &State<DomainLocks>,
+    configure: String,
+) -> (Status, content::RawJson<String>) {
+    let _lock = match lock_domain(locks, &config_dom_name(&configure), "set-metadata").await {
+        Ok(lock) => lock,
+        Err(res) => return res,
+    };
+    run_virt_command(conn, VirtCommandType::SetDomainMetadata, vec![configure])
+}
@@ -1,10 +1,23 @@
 pub mod entity;
 use entity::{prelude::*, *};
-use sea_orm::{Database, DbErr, EntityTrait, DatabaseConnection};
+use sea_orm::{ConnectionTrait, Database, DatabaseConnection, DbErr, EntityTrait, Schema};
 
 pub async fn init(database_url: &str) -> Result<DatabaseConnection, DbErr> {
     let db = Database::connect(database_url).await?;
     let user = User::find().all(&db).await?;
     println!("{:?}", user);
+    // alert rules, tasks, backups and snapshot meta are newer than the rest of the
+    // schema, create their tables on first start
+    let backend = db.get_database_backend();
+    let mut alert_rules = Schema::new(backend).create_table_from_entity(AlertRules);
+    db.execute(backend.build(alert_rules.if_not_exists()))
+        .await?;
+    let mut tasks = Schema::new(backend).create_table_from_entity(Tasks);
+    db.execute(backend.build(tasks.if_not_exists())).await?;
+    let mut backups = Schema::new(backend).create_table_from_entity(Backups);
+    db.execute(backend.build(backups.if_not_exists())).await?;
+    let mut snapshot_meta = Schema::new(backend).create_table_from_entity(SnapshotMeta);
+    db.execute(backend.build(snapshot_meta.if_not_exists()))
+        .await?;
     Ok(db)
 }
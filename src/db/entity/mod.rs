@@ -2,6 +2,10 @@
 
 pub mod prelude;
 
+pub mod alert_rules;
+pub mod backups;
 pub mod domains;
 pub mod schedule_jobs;
+pub mod snapshot_meta;
+pub mod tasks;
 pub mod user;
@@ -1,5 +1,9 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.12.15
 
+pub use super::alert_rules::Entity as AlertRules;
+pub use super::backups::Entity as Backups;
 pub use super::domains::Entity as Domains;
 pub use super::schedule_jobs::Entity as ScheduleJobs;
+pub use super::snapshot_meta::Entity as SnapshotMeta;
+pub use super::tasks::Entity as Tasks;
 pub use super::user::Entity as User;
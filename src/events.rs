@@ -0,0 +1,160 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::Command,
+    sync::broadcast::{self, Receiver, Sender},
+};
+
+// recent events kept around so reconnecting clients can resume
+const HISTORY_SIZE: usize = 1024;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DomainEventKind {
+    Defined,
+    Undefined,
+    Started,
+    Suspended,
+    Resumed,
+    Stopped,
+    Shutdown,
+    Crashed,
+    PmSuspended,
+    SnapshotCreated,
+    SnapshotReverted,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DomainEvent {
+    pub id: u64,
+    pub dom_name: String,
+    pub kind: DomainEventKind,
+    // libvirt's reason, e.g. Booted or Destroyed, or the snapshot name
+    pub detail: String,
+    pub timestamp: i64,
+}
+
+struct EventHistory {
+    next_id: u64,
+    events: VecDeque<DomainEvent>,
+}
+
+#[derive(Clone)]
+pub struct EventConnect {
+    tx: Sender<DomainEvent>,
+    history: Arc<Mutex<EventHistory>>,
+}
+
+impl EventConnect {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(256);
+        let events = EventConnect {
+            tx,
+            history: Arc::new(Mutex::new(EventHistory {
+                // ids start from the boot time, so ones a client kept from an earlier
+                // run are all older and it gets this run's whole history on resume
+                next_id: Utc::now().timestamp_millis() as u64 * 1000,
+                events: VecDeque::with_capacity(HISTORY_SIZE),
+            })),
+        };
+        tokio::spawn(watch_lifecycle(events.clone()));
+        events
+    }
+
+    pub fn publish(&self, dom_name: &str, kind: DomainEventKind, detail: &str) {
+        // ids are handed out under the lock so history and subscribers agree on order
+        let mut history = self.history.lock().unwrap();
+        let event = DomainEvent {
+            id: history.next_id,
+            dom_name: dom_name.to_string(),
+            kind,
+            detail: detail.to_string(),
+            timestamp: Utc::now().timestamp(),
+        };
+        history.next_id += 1;
+        if history.events.len() == HISTORY_SIZE {
+            history.events.pop_front();
+        }
+        history.events.push_back(event.clone());
+        // no subscribers is not an error
+        let _ = self.tx.send(event);
+    }
+
+    // events after `last_id` that are still in history, plus a receiver for new ones
+    pub fn subscribe(&self, last_id: Option<u64>) -> (Vec<DomainEvent>, Receiver<DomainEvent>) {
+        let history = self.history.lock().unwrap();
+        let backlog = match last_id {
+            Some(last_id) => history
+                .events
+                .iter()
+                .filter(|it| it.id > last_id)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        (backlog, self.tx.subscribe())
+    }
+}
+
+// the virt crate has no event api, so follow `virsh event` instead; lines look like
+// event 'lifecycle' for domain 'vm1': Started Booted
+fn parse_lifecycle(line: &str) -> Option<(String, DomainEventKind, String)> {
+    let rest = line.trim().strip_prefix("event 'lifecycle' for domain ")?;
+    let (dom_name, rest) = rest.rsplit_once(": ")?;
+    let (kind, detail) = rest.split_once(' ').unwrap_or((rest, ""));
+    let kind = match kind {
+        "Defined" => DomainEventKind::Defined,
+        "Undefined" => DomainEventKind::Undefined,
+        "Started" => DomainEventKind::Started,
+        "Suspended" => DomainEventKind::Suspended,
+        "Resumed" => DomainEventKind::Resumed,
+        "Stopped" => DomainEventKind::Stopped,
+        "Shutdown" => DomainEventKind::Shutdown,
+        "Crashed" => DomainEventKind::Crashed,
+        "PMSuspended" => DomainEventKind::PmSuspended,
+        _ => return None,
+    };
+    Some((
+        dom_name.trim_matches('\'').to_string(),
+        kind,
+        detail.to_string(),
+    ))
+}
+
+async fn watch_lifecycle(events: EventConnect) {
+    loop {
+        let child = Command::new("virsh")
+            .args(["event", "--loop", "--event", "lifecycle"])
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn();
+        match child {
+            Ok(mut child) => {
+                let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if let Some((dom_name, kind, detail)) = parse_lifecycle(&line) {
+                        events.publish(&dom_name, kind, &detail);
+                    }
+                }
+                match child.wait().await {
+                    Ok(status) if !status.success() => {
+                        println!("virsh event exited with {}", status)
+                    }
+                    Err(e) => println!("virsh event failed: {}", e),
+                    _ => (),
+                }
+            }
+            Err(e) => println!("starting virsh event failed: {}", e),
+        }
+        // virsh exits when libvirtd restarts, follow it back up
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
@@ -0,0 +1,98 @@
+use chrono::Utc;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::Notify, time::Instant};
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LockInfo {
+    pub dom_name: String,
+    pub operation: String,
+    // unix seconds
+    pub since: i64,
+}
+
+// one mutating operation per domain at a time; snapshot workflows juggle temp
+// snapshots and reverts and must never interleave
+#[derive(Clone)]
+pub struct DomainLocks {
+    held: Arc<Mutex<HashMap<String, LockInfo>>>,
+    released: Arc<Notify>,
+    // how long to queue for a busy domain, zero answers right away
+    wait: Duration,
+}
+
+// released when dropped
+pub struct DomainLock {
+    locks: DomainLocks,
+    dom_name: String,
+}
+
+impl Drop for DomainLock {
+    fn drop(&mut self) {
+        self.locks.held.lock().unwrap().remove(&self.dom_name);
+        self.locks.released.notify_waiters();
+    }
+}
+
+impl DomainLocks {
+    pub fn new() -> Self {
+        let wait = env::var("DOMAIN_LOCK_WAIT")
+            .ok()
+            .and_then(|it| it.parse().ok())
+            .unwrap_or(0);
+        DomainLocks::with_wait(Duration::from_secs(wait))
+    }
+
+    pub fn with_wait(wait: Duration) -> Self {
+        DomainLocks {
+            held: Arc::new(Mutex::new(HashMap::new())),
+            released: Arc::new(Notify::new()),
+            wait,
+        }
+    }
+
+    fn try_acquire(&self, dom_name: &str, operation: &str) -> Result<DomainLock, LockInfo> {
+        let mut held = self.held.lock().unwrap();
+        if let Some(holder) = held.get(dom_name) {
+            return Err(holder.clone());
+        }
+        held.insert(
+            dom_name.to_string(),
+            LockInfo {
+                dom_name: dom_name.to_string(),
+                operation: operation.to_string(),
+                since: Utc::now().timestamp(),
+            },
+        );
+        Ok(DomainLock {
+            locks: self.clone(),
+            dom_name: dom_name.to_string(),
+        })
+    }
+
+    // the error is whoever holds the domain when we give up
+    pub async fn acquire(&self, dom_name: &str, operation: &str) -> Result<DomainLock, LockInfo> {
+        let deadline = Instant::now() + self.wait;
+        loop {
+            // registered before trying, so a release in between isn't missed
+            let released = self.released.notified();
+            match self.try_acquire(dom_name, operation) {
+                Ok(lock) => return Ok(lock),
+                Err(holder) if Instant::now() >= deadline => return Err(holder),
+                Err(_) => {
+                    let _ = tokio::time::timeout_at(deadline, released).await;
+                }
+            }
+        }
+    }
+
+    pub fn held(&self) -> Vec<LockInfo> {
+        self.held.lock().unwrap().values().cloned().collect()
+    }
+}
@@ -1,20 +1,38 @@
 #[macro_use]
 extern crate rocket;
 
+mod alerts;
+mod backups;
 mod controller;
 mod db;
+mod events;
+mod locks;
+mod metrics;
 mod middleware;
 mod scheduler;
+mod stats;
+mod tasks;
 #[cfg(test)]
 mod test;
 mod virt;
 
-use controller::{account::*, snapshot::*, sys::get_sys_utilization, virt::*, vnc::*};
+use alerts::AlertManager;
+use backups::BackupManager;
+use controller::{
+    account::*, alerts::*, archive::*, backups::*, bulk::*, disk::*, events::*, metrics::*,
+    network::*, snapshot::*, stats::*, storage::*, sys::*, tasks::*, virt::*, vnc::*,
+};
 use db::init;
 use dotenvy::dotenv;
+use events::EventConnect;
 use futures::executor::block_on;
+use locks::DomainLocks;
+use metrics::Metrics;
+use middleware::metrics::RequestTimer;
 use scheduler::SchedConnect;
+use stats::StatsConnect;
 use std::env;
+use tasks::TaskManager;
 use virt::VirtConnect;
 
 async fn build() -> rocket::Rocket<rocket::Build> {
@@ -26,19 +44,67 @@ async fn build() -> rocket::Rocket<rocket::Build> {
         Err(err) => panic!("{}", err),
     };
 
+    // finish off snapshot workflows a crash interrupted before serving anything
+    for line in tokio::task::spawn_blocking(virt::shell::recover_operations)
+        .await
+        .unwrap()
+    {
+        println!("recovery: {}", line);
+    }
+
     let virt_conn = VirtConnect::new();
 
-    let sched_conn = SchedConnect::new().await;
+    let metrics = Metrics::default();
+
+    let locks = DomainLocks::new();
+
+    let backups = BackupManager::new(db.clone());
+
+    let sched_conn =
+        SchedConnect::new(db.clone(), metrics.clone(), locks.clone(), backups.clone()).await;
+
+    let events = EventConnect::new();
+
+    let stats = StatsConnect::new();
+
+    let alerts = AlertManager::new(db.clone(), stats.clone(), metrics.clone());
+
+    let tasks = TaskManager::new(db.clone()).await;
 
     rocket::build()
         .manage(db)
         .manage(virt_conn)
         .manage(sched_conn)
+        .manage(events)
+        .manage(stats)
+        .manage(metrics)
+        .manage(alerts)
+        .manage(locks)
+        .manage(tasks)
+        .manage(backups)
+        .attach(RequestTimer)
+        .mount("/", routes![get_metrics])
         .mount("/api/v1/account", routes![login_handler, regist_handler])
-        .mount("/api/v1/sys", routes![get_sys_utilization])
+        .mount("/api/v1/sys", routes![get_sys_utilization, get_sys_history])
         .mount(
             "/api/v1/virt",
-            routes![list_domains, set_domain_state, upload_iso],
+            routes![
+                list_domains,
+                create_domain,
+                delete_domain,
+                set_domain_state,
+                upload_iso,
+                edit_hardware,
+                list_interfaces,
+                attach_interface,
+                detach_interface,
+                get_domain_metadata,
+                set_domain_metadata,
+                rename_domain,
+                get_autostart,
+                set_autostart,
+                list_domain_locks,
+            ],
         )
         .mount(
             "/api/v1/snapshot",
@@ -46,14 +112,78 @@ async fn build() -> rocket::Rocket<rocket::Build> {
                 list_snapshot,
                 list_snapshot_tree,
                 edit_snapshot,
+                diff_snapshots,
+                create_external_snapshot,
                 set_current_snapshot,
+                undo_revert,
                 clone_snapshot_as_vm,
+                list_snapshot_operations,
                 create_snapshot,
                 delete_snapshot,
                 add_sched_task,
                 delete_sched_task,
             ],
         )
+        .mount(
+            "/api/v1/disk",
+            routes![
+                list_disks,
+                create_disk,
+                attach_disk,
+                detach_disk,
+                resize_disk,
+                list_backing_chains,
+                block_commit,
+                block_pull
+            ],
+        )
+        .mount(
+            "/api/v1/storage",
+            routes![
+                list_pools,
+                create_pool,
+                refresh_pool,
+                list_volumes,
+                delete_volume,
+                download_volume,
+                upload_volume,
+            ],
+        )
+        .mount(
+            "/api/v1/network",
+            routes![
+                list_networks,
+                create_network,
+                destroy_network,
+                undefine_network,
+                list_dhcp_leases,
+            ],
+        )
+        .mount("/api/v1/bulk", routes![run_bulk])
+        .mount("/api/v1/archive", routes![export_domain, import_domain])
+        .mount(
+            "/api/v1/backup",
+            routes![
+                create_backup,
+                list_backups,
+                get_backup,
+                delete_backup,
+                restore_backup
+            ],
+        )
+        .mount("/api/v1/task", routes![list_tasks, get_task, cancel_task])
+        .mount("/api/v1/events", routes![stream_events])
+        .mount("/api/v1/stats", routes![get_domain_stats])
+        .mount(
+            "/api/v1/alert",
+            routes![
+                list_alert_rules,
+                create_alert_rule,
+                delete_alert_rule,
+                test_alert_rule,
+                list_active_alerts,
+            ],
+        )
         .mount("/api/v1/vnc", routes![vnc_connect, get_vnc_display_config])
     // .attach(CORS)
 }
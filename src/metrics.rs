@@ -0,0 +1,158 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+// upper bounds in seconds
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+struct JobCounters {
+    runs: u64,
+    failures: u64,
+    last_failed: bool,
+}
+
+// counters that only exist inside this process, everything else is read from
+// libvirt and sysinfo at scrape time
+#[derive(Clone, Default)]
+pub struct Metrics {
+    jobs: Arc<Mutex<HashMap<String, JobCounters>>>,
+    // keyed by method and route
+    requests: Arc<Mutex<HashMap<(String, String), Histogram>>>,
+}
+
+pub fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// writes the HELP and TYPE lines that start every metric family
+pub fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+impl Metrics {
+    pub fn record_job_run(&self, dom_name: &str, success: bool) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let counters = jobs.entry(dom_name.to_string()).or_default();
+        counters.runs += 1;
+        if !success {
+            counters.failures += 1;
+        }
+        counters.last_failed = !success;
+    }
+
+    // domains whose latest scheduled job failed
+    pub fn failing_jobs(&self) -> Vec<String> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, counters)| counters.last_failed)
+            .map(|(dom_name, _)| dom_name.clone())
+            .collect()
+    }
+
+    pub fn observe_request(&self, method: &str, route: &str, secs: f64) {
+        let mut requests = self.requests.lock().unwrap();
+        let histogram = requests
+            .entry((method.to_string(), route.to_string()))
+            .or_default();
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            if secs <= *bound {
+                histogram.buckets[i] += 1;
+            }
+        }
+        histogram.sum += secs;
+        histogram.count += 1;
+    }
+
+    pub fn render(&self, out: &mut String) {
+        let jobs = self.jobs.lock().unwrap();
+        family(
+            out,
+            "virt_sched_job_runs_total",
+            "counter",
+            "Scheduled snapshot jobs run per domain.",
+        );
+        for (dom_name, counters) in jobs.iter() {
+            writeln!(
+                out,
+                "virt_sched_job_runs_total{{domain=\"{}\"}} {}",
+                escape_label(dom_name),
+                counters.runs
+            )
+            .unwrap();
+        }
+        family(
+            out,
+            "virt_sched_job_failures_total",
+            "counter",
+            "Scheduled snapshot jobs that failed per domain.",
+        );
+        for (dom_name, counters) in jobs.iter() {
+            writeln!(
+                out,
+                "virt_sched_job_failures_total{{domain=\"{}\"}} {}",
+                escape_label(dom_name),
+                counters.failures
+            )
+            .unwrap();
+        }
+
+        let requests = self.requests.lock().unwrap();
+        family(
+            out,
+            "http_request_duration_seconds",
+            "histogram",
+            "HTTP request latency per route.",
+        );
+        for ((method, route), histogram) in requests.iter() {
+            let labels = format!(
+                "method=\"{}\",route=\"{}\"",
+                escape_label(method),
+                escape_label(route)
+            );
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                writeln!(
+                    out,
+                    "http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, count
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "http_request_duration_seconds_sum{{{}}} {}",
+                labels, histogram.sum
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "http_request_duration_seconds_count{{{}}} {}",
+                labels, histogram.count
+            )
+            .unwrap();
+        }
+    }
+}
@@ -1 +1 @@
-pub mod authenticate;
\ No newline at end of file
+pub mod authenticate;pub mod metrics;
@@ -83,3 +83,30 @@ impl<'r> FromRequest<'r> for JWT {
         }
     }
 }
+
+// guards /metrics with METRICS_TOKEN as a bearer token, scrapers don't log in;
+// the endpoint is open when the variable is unset
+pub struct MetricsToken;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MetricsToken {
+    type Error = String;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let Ok(token) = env::var("METRICS_TOKEN") else {
+            return Outcome::Success(MetricsToken);
+        };
+        let provided = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|it| it.strip_prefix("Bearer "));
+        if provided == Some(token.as_str()) {
+            Outcome::Success(MetricsToken)
+        } else {
+            Outcome::Error((
+                Status::Unauthorized,
+                String::from("Error validating metrics token"),
+            ))
+        }
+    }
+}
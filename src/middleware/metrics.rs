@@ -0,0 +1,42 @@
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Data, Request, Response,
+};
+use std::time::Instant;
+
+use crate::metrics::Metrics;
+
+// times every request into the per route latency histogram
+pub struct RequestTimer;
+
+#[derive(Clone, Copy)]
+struct RequestStart(Option<Instant>);
+
+#[rocket::async_trait]
+impl Fairing for RequestTimer {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Timer",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        req.local_cache(|| RequestStart(Some(Instant::now())));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, _: &mut Response<'r>) {
+        let Some(start) = req.local_cache(|| RequestStart(None)).0 else {
+            return;
+        };
+        let Some(metrics) = req.rocket().state::<Metrics>() else {
+            return;
+        };
+        // group by route pattern, not by path, so ids don't blow up the label set
+        let route = req
+            .route()
+            .map(|it| it.uri.to_string())
+            .unwrap_or("unmatched".to_string());
+        metrics.observe_request(req.method().as_str(), &route, start.elapsed().as_secs_f64());
+    }
+}
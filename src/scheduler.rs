@@ -1,4 +1,4 @@
-use sea_orm::prelude::Uuid;
+use sea_orm::{prelude::Uuid, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
 use serde::{Deserialize, Serialize};
 use tokio::sync::{
     mpsc::{self, Receiver, Sender},
@@ -6,6 +6,13 @@ use tokio::sync::{
 };
 use tokio_cron_scheduler::{Job, JobScheduler, JobSchedulerError};
 
+use crate::{
+    backups::BackupManager,
+    db::entity::{prelude::*, *},
+    locks::DomainLocks,
+    metrics::Metrics,
+};
+
 pub struct SchedConnect {
     pub tx: Sender<SchedCommand>,
     pub rx: Mutex<Receiver<SchedResult>>,
@@ -19,32 +26,97 @@ pub enum SchedCommand {
     Delete(String),
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SchedKind {
+    #[default]
+    Snapshot,
+    FullBackup,
+    // full as well when the domain has no backup to build on yet
+    IncrementalBackup,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SchedTaskConfig {
     pub dom_name: String,
     pub cron: String,
+    #[serde(default)]
+    pub kind: SchedKind,
 }
 
 impl SchedConnect {
-    pub async fn new() -> Self {
+    pub async fn new(
+        db: DatabaseConnection,
+        metrics: Metrics,
+        locks: DomainLocks,
+        backups: BackupManager,
+    ) -> Self {
         let (sched_tx, mut sched_rx): (Sender<SchedCommand>, Receiver<SchedCommand>) =
             mpsc::channel(2);
-        let (result_tx, result_rx): (Sender<SchedResult>, Receiver<SchedResult>) =
-            mpsc::channel(2);
+        let (result_tx, result_rx): (Sender<SchedResult>, Receiver<SchedResult>) = mpsc::channel(2);
         tokio::spawn(async move {
             let scheduler = JobScheduler::new().await.unwrap();
             scheduler.start().await.unwrap();
             while let Some(recv) = sched_rx.recv().await {
                 match recv {
                     SchedCommand::Add(config) => {
-                        let res = scheduler
-                            .add(
-                                Job::new(config.cron.as_str(), |_uuid, _l| {
-                                    println!("sched run!");
-                                })
-                                .unwrap(),
-                            )
-                            .await;
+                        let db = db.clone();
+                        let metrics = metrics.clone();
+                        let locks = locks.clone();
+                        let backups = backups.clone();
+                        let (dom_name, kind) = (config.dom_name.clone(), config.kind);
+                        let job = match Job::new_async(config.cron.as_str(), move |uuid, _l| {
+                            let db = db.clone();
+                            let metrics = metrics.clone();
+                            let locks = locks.clone();
+                            let backups = backups.clone();
+                            let dom_name = dom_name.clone();
+                            Box::pin(async move {
+                                // the domain may have been renamed since the job was added
+                                let dom_name = match ScheduleJobs::find()
+                                    .filter(schedule_jobs::Column::Uuid.eq(uuid.to_string()))
+                                    .one(&db)
+                                    .await
+                                {
+                                    Ok(Some(job)) => job.domain,
+                                    _ => dom_name,
+                                };
+                                let operation = match kind {
+                                    SchedKind::Snapshot => "sched-snapshot",
+                                    _ => "sched-backup",
+                                };
+                                // a run that finds the domain busy counts as failed
+                                let Ok(_lock) = locks.acquire(&dom_name, operation).await else {
+                                    metrics.record_job_run(&dom_name, false);
+                                    return;
+                                };
+                                let succeeded = match kind {
+                                    SchedKind::Snapshot => {
+                                        println!("sched run!");
+                                        true
+                                    }
+                                    SchedKind::FullBackup | SchedKind::IncrementalBackup => {
+                                        let incremental = kind == SchedKind::IncrementalBackup;
+                                        match backups.prepare(&dom_name, incremental, None).await {
+                                            Ok(pending) => matches!(
+                                                tokio::task::spawn_blocking(move || pending.run())
+                                                    .await,
+                                                Ok(Ok(_))
+                                            ),
+                                            Err(_) => false,
+                                        }
+                                    }
+                                };
+                                metrics.record_job_run(&dom_name, succeeded);
+                            })
+                        }) {
+                            Ok(job) => job,
+                            Err(e) => {
+                                result_tx.send(Err(e)).await.unwrap();
+                                continue;
+                            }
+                        };
+                        let res = scheduler.add(job).await;
                         match res {
                             Ok(uuid) => result_tx.send(Ok(uuid.to_string())).await.unwrap(),
                             Err(e) => result_tx.send(Err(e)).await.unwrap(),
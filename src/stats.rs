@@ -0,0 +1,305 @@
+use chrono::Utc;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::virt::{shell, HostMonitor, HostStatus};
+
+pub const SAMPLE_INTERVAL: u64 = 10;
+// six hours of samples per domain and for the host
+const HISTORY_SIZE: usize = 2160;
+
+#[derive(Clone, Default)]
+pub struct DeviceCounters {
+    pub name: String,
+    // disks: read/write bytes and requests, nics: rx/tx bytes and packets
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    pub read_reqs: u64,
+    pub write_reqs: u64,
+}
+
+// cumulative counters as reported by libvirt, rates are derived when queried
+#[derive(Clone)]
+pub struct Sample {
+    pub timestamp: i64,
+    // nanoseconds
+    pub cpu_time: u64,
+    pub vcpus: u64,
+    // KiB
+    pub memory: u64,
+    pub memory_unused: Option<u64>,
+    pub disks: Vec<DeviceCounters>,
+    pub interfaces: Vec<DeviceCounters>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskPoint {
+    name: String,
+    read_bps: f64,
+    write_bps: f64,
+    read_iops: f64,
+    write_iops: f64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InterfacePoint {
+    name: String,
+    rx_bps: f64,
+    tx_bps: f64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatsPoint {
+    // unix millis
+    timestamp: i64,
+    // share of the domain's vcpus, 0 to 100
+    cpu_percent: f64,
+    // balloon size in KiB
+    memory: u64,
+    // KiB in use inside the guest, needs the balloon driver to report it
+    memory_used: Option<u64>,
+    disks: Vec<DiskPoint>,
+    interfaces: Vec<InterfacePoint>,
+}
+
+#[derive(Clone)]
+pub struct StatsConnect {
+    samples: Arc<Mutex<HashMap<String, VecDeque<Sample>>>>,
+    // libvirt state and reason of every domain, running or not
+    states: Arc<Mutex<HashMap<String, (u32, u32)>>>,
+    host: Arc<Mutex<VecDeque<HostStatus>>>,
+}
+
+// windows and resolutions come straight from the query, huge ones mean everything
+fn millis(secs: u64) -> i64 {
+    secs.saturating_mul(1000).min(i64::MAX as u64) as i64
+}
+
+fn counter(stats: &HashMap<String, String>, key: &str) -> u64 {
+    stats.get(key).and_then(|it| it.parse().ok()).unwrap_or(0)
+}
+
+fn devices(stats: &HashMap<String, String>, prefix: &str, keys: [&str; 4]) -> Vec<DeviceCounters> {
+    (0..counter(stats, &format!("{}.count", prefix)))
+        .map(|i| {
+            let field = |key: &str| counter(stats, &format!("{}.{}.{}", prefix, i, key));
+            DeviceCounters {
+                name: stats
+                    .get(&format!("{}.{}.name", prefix, i))
+                    .cloned()
+                    .unwrap_or_default(),
+                read_bytes: field(keys[0]),
+                write_bytes: field(keys[1]),
+                read_reqs: field(keys[2]),
+                write_reqs: field(keys[3]),
+            }
+        })
+        .collect()
+}
+
+fn to_sample(timestamp: i64, stats: &HashMap<String, String>) -> Sample {
+    Sample {
+        timestamp,
+        cpu_time: counter(stats, "cpu.time"),
+        vcpus: counter(stats, "vcpu.current").max(1),
+        memory: counter(stats, "balloon.current"),
+        memory_unused: stats.get("balloon.unused").and_then(|it| it.parse().ok()),
+        disks: devices(
+            stats,
+            "block",
+            ["rd.bytes", "wr.bytes", "rd.reqs", "wr.reqs"],
+        ),
+        interfaces: devices(stats, "net", ["rx.bytes", "tx.bytes", "rx.pkts", "tx.pkts"]),
+    }
+}
+
+// rates over the span between two samples, counters reset when the domain restarts
+fn to_point(prev: &Sample, cur: &Sample) -> StatsPoint {
+    let secs = (cur.timestamp - prev.timestamp).max(1) as f64 / 1000.0;
+    let rate = |prev: u64, cur: u64| cur.saturating_sub(prev) as f64 / secs;
+    let find = |devices: &[DeviceCounters], name: &str| {
+        devices
+            .iter()
+            .find(|it| it.name == name)
+            .cloned()
+            .unwrap_or_default()
+    };
+    StatsPoint {
+        timestamp: cur.timestamp,
+        cpu_percent: rate(prev.cpu_time, cur.cpu_time) / 1e7 / cur.vcpus as f64,
+        memory: cur.memory,
+        memory_used: cur.memory_unused.map(|it| cur.memory.saturating_sub(it)),
+        disks: cur
+            .disks
+            .iter()
+            .map(|disk| {
+                let prev = find(&prev.disks, &disk.name);
+                DiskPoint {
+                    name: disk.name.clone(),
+                    read_bps: rate(prev.read_bytes, disk.read_bytes),
+                    write_bps: rate(prev.write_bytes, disk.write_bytes),
+                    read_iops: rate(prev.read_reqs, disk.read_reqs),
+                    write_iops: rate(prev.write_reqs, disk.write_reqs),
+                }
+            })
+            .collect(),
+        interfaces: cur
+            .interfaces
+            .iter()
+            .map(|nic| {
+                let prev = find(&prev.interfaces, &nic.name);
+                InterfacePoint {
+                    name: nic.name.clone(),
+                    rx_bps: rate(prev.read_bytes, nic.read_bytes),
+                    tx_bps: rate(prev.write_bytes, nic.write_bytes),
+                }
+            })
+            .collect(),
+    }
+}
+
+impl StatsConnect {
+    pub fn new() -> Self {
+        let stats = StatsConnect {
+            samples: Arc::new(Mutex::new(HashMap::new())),
+            states: Arc::new(Mutex::new(HashMap::new())),
+            host: Arc::new(Mutex::new(VecDeque::with_capacity(HISTORY_SIZE))),
+        };
+        tokio::spawn(collect(stats.clone()));
+        stats
+    }
+
+    fn record(&self, all_stats: HashMap<String, HashMap<String, String>>) {
+        let timestamp = Utc::now().timestamp_millis();
+        let mut samples = self.samples.lock().unwrap();
+        // stopped or deleted domains keep their history until it ages out
+        samples.retain(|dom_name, history| {
+            all_stats.contains_key(dom_name)
+                || history.back().is_some_and(|it| {
+                    timestamp - it.timestamp < (HISTORY_SIZE as u64 * SAMPLE_INTERVAL * 1000) as i64
+                })
+        });
+        *self.states.lock().unwrap() = all_stats
+            .iter()
+            .map(|(dom_name, stats)| {
+                (
+                    dom_name.clone(),
+                    (
+                        counter(stats, "state.state") as u32,
+                        counter(stats, "state.reason") as u32,
+                    ),
+                )
+            })
+            .collect();
+        // inactive domains have no counters to sample
+        for (dom_name, stats) in all_stats
+            .into_iter()
+            .filter(|(_, stats)| stats.contains_key("cpu.time"))
+        {
+            let history = samples.entry(dom_name).or_default();
+            if history.len() == HISTORY_SIZE {
+                history.pop_front();
+            }
+            history.push_back(to_sample(timestamp, &stats));
+        }
+    }
+
+    pub fn domain_states(&self) -> HashMap<String, u32> {
+        self.states
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(dom_name, (state, _))| (dom_name.clone(), *state))
+            .collect()
+    }
+
+    pub fn domain_state_reasons(&self) -> HashMap<String, (u32, u32)> {
+        self.states.lock().unwrap().clone()
+    }
+
+    pub fn latest_samples(&self) -> Vec<(String, Sample)> {
+        self.samples
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(dom_name, history)| Some((dom_name.clone(), history.back()?.clone())))
+            .collect()
+    }
+
+    fn record_host(&self, status: HostStatus) {
+        let mut host = self.host.lock().unwrap();
+        if host.len() == HISTORY_SIZE {
+            host.pop_front();
+        }
+        host.push_back(status);
+    }
+
+    pub fn host_latest(&self) -> Option<HostStatus> {
+        self.host.lock().unwrap().back().cloned()
+    }
+
+    // host samples of the last `window` seconds
+    pub fn host_series(&self, window: u64) -> Vec<HostStatus> {
+        let since = Utc::now().timestamp_millis().saturating_sub(millis(window));
+        self.host
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|it| it.timestamp as i64 >= since)
+            .cloned()
+            .collect()
+    }
+
+    // one point per `resolution` seconds over the last `window` seconds
+    pub fn series(&self, dom_name: &str, window: u64, resolution: u64) -> Option<Vec<StatsPoint>> {
+        let samples = self.samples.lock().unwrap();
+        let history = samples.get(dom_name)?;
+        let since = Utc::now().timestamp_millis().saturating_sub(millis(window));
+        let resolution = millis(resolution.max(SAMPLE_INTERVAL));
+        // the last sample of each bucket stands for it, and the rates between
+        // consecutive picks average over the whole bucket
+        let mut picked: Vec<&Sample> = Vec::new();
+        for sample in history.iter().filter(|it| it.timestamp >= since) {
+            match picked.last_mut() {
+                Some(last) if last.timestamp / resolution == sample.timestamp / resolution => {
+                    *last = sample
+                }
+                _ => picked.push(sample),
+            }
+        }
+        Some(
+            picked
+                .windows(2)
+                .map(|pair| to_point(pair[0], pair[1]))
+                .collect(),
+        )
+    }
+}
+
+async fn collect(stats: StatsConnect) {
+    let mut interval = tokio::time::interval(Duration::from_secs(SAMPLE_INTERVAL));
+    let mut monitor = HostMonitor::new();
+    loop {
+        interval.tick().await;
+        // sysinfo reads /proc synchronously, the monitor goes along and comes back
+        let (returned, status) = tokio::task::spawn_blocking(move || {
+            let status = monitor.sample();
+            (monitor, status)
+        })
+        .await
+        .unwrap();
+        monitor = returned;
+        stats.record_host(status);
+        // a failed round just leaves a gap in the graphs
+        if let Ok(Ok(all_stats)) = tokio::task::spawn_blocking(shell::domain_stats).await {
+            stats.record(all_stats);
+        }
+    }
+}
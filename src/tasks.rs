@@ -0,0 +1,329 @@
+use chrono::Utc;
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ActiveValue, ColumnTrait, DatabaseConnection, DbErr,
+    EntityTrait, QueryFilter, QueryOrder, QuerySelect,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::sync::{Notify, Semaphore};
+
+use crate::{
+    db::entity::{prelude::*, *},
+    locks::DomainLock,
+    virt::shell,
+};
+
+// tasks running at once, the rest wait queued
+const TASK_WORKERS: usize = 4;
+const PROGRESS_INTERVAL: u64 = 2;
+const LIST_LIMIT: u64 = 200;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TaskState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl TaskState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskState::Queued => "queued",
+            TaskState::Running => "running",
+            TaskState::Succeeded => "succeeded",
+            TaskState::Failed => "failed",
+            TaskState::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TaskError {
+    #[error("Task {0} not found")]
+    NotFound(i32),
+    #[error("Task {0} is already {1}")]
+    Finished(i32, String),
+    #[error("Task {0} can't be cancelled: {1}")]
+    NotCancellable(i32, String),
+    #[error(transparent)]
+    Db(#[from] DbErr),
+}
+
+// queued or running, everything else only lives in the database
+struct LiveTask {
+    dom_name: String,
+    running: bool,
+    cancelled: bool,
+    // wakes a queued task so it gives its domain lock back right away
+    cancel: Arc<Notify>,
+    log: Vec<String>,
+}
+
+impl LiveTask {
+    fn log(&mut self, line: &str) {
+        self.log.push(format!(
+            "{} {}",
+            Utc::now().format("%Y-%m-%d %H:%M:%S"),
+            line
+        ));
+    }
+}
+
+#[derive(Clone)]
+pub struct TaskManager {
+    db: DatabaseConnection,
+    live: Arc<Mutex<HashMap<i32, LiveTask>>>,
+    workers: Arc<Semaphore>,
+}
+
+impl TaskManager {
+    pub async fn new(db: DatabaseConnection) -> Self {
+        // whatever was queued or running when we went down won't finish now
+        if let Err(e) = Tasks::update_many()
+            .col_expr(
+                tasks::Column::State,
+                Expr::value(TaskState::Failed.as_str()),
+            )
+            .col_expr(tasks::Column::Result, Expr::value("Interrupted by restart"))
+            .col_expr(
+                tasks::Column::FinishedAt,
+                Expr::value(Utc::now().timestamp()),
+            )
+            .filter(
+                tasks::Column::State
+                    .is_in([TaskState::Queued.as_str(), TaskState::Running.as_str()]),
+            )
+            .exec(&db)
+            .await
+        {
+            println!("marking interrupted tasks failed: {}", e);
+        }
+        TaskManager {
+            db,
+            live: Arc::new(Mutex::new(HashMap::new())),
+            workers: Arc::new(Semaphore::new(TASK_WORKERS)),
+        }
+    }
+
+    // records the task and hands back its id right away; `body` runs on a blocking
+    // thread once a worker is free, holding the domain lock until it's done
+    pub async fn submit<F>(
+        &self,
+        kind: &str,
+        dom_name: &str,
+        lock: DomainLock,
+        body: F,
+    ) -> Result<i32, DbErr>
+    where
+        F: FnOnce() -> Result<String, std::io::Error> + Send + 'static,
+    {
+        let mut task = LiveTask {
+            dom_name: dom_name.to_string(),
+            running: false,
+            cancelled: false,
+            cancel: Arc::new(Notify::new()),
+            log: Vec::new(),
+        };
+        task.log("queued");
+        let id = Tasks::insert(tasks::ActiveModel {
+            kind: ActiveValue::set(kind.to_string()),
+            dom_name: ActiveValue::set(dom_name.to_string()),
+            state: ActiveValue::set(TaskState::Queued.as_str().to_string()),
+            progress: ActiveValue::set(None),
+            log: ActiveValue::set(task.log.join("\n")),
+            result: ActiveValue::set(None),
+            created_at: ActiveValue::set(Utc::now().timestamp()),
+            started_at: ActiveValue::set(None),
+            finished_at: ActiveValue::set(None),
+            ..Default::default()
+        })
+        .exec(&self.db)
+        .await?
+        .last_insert_id;
+        let cancel = task.cancel.clone();
+        self.live.lock().unwrap().insert(id, task);
+        tokio::spawn(self.clone().run(id, lock, cancel, body));
+        Ok(id)
+    }
+
+    // logs the line and returns the whole log, for saving along with a state change
+    fn log(&self, id: i32, line: &str) -> ActiveValue<String> {
+        match self.live.lock().unwrap().get_mut(&id) {
+            Some(task) => {
+                task.log(line);
+                ActiveValue::set(task.log.join("\n"))
+            }
+            None => ActiveValue::not_set(),
+        }
+    }
+
+    async fn save(&self, model: tasks::ActiveModel) {
+        if let Err(e) = model.update(&self.db).await {
+            println!("saving task failed: {}", e);
+        }
+    }
+
+    async fn run<F>(self, id: i32, lock: DomainLock, cancel: Arc<Notify>, body: F)
+    where
+        F: FnOnce() -> Result<String, std::io::Error> + Send + 'static,
+    {
+        let _permit = tokio::select! {
+            permit = self.workers.clone().acquire_owned() => permit.unwrap(),
+            // cancel() already saved the task
+            _ = cancel.notified() => return,
+        };
+        let dom_name = {
+            let mut live = self.live.lock().unwrap();
+            // cancelled while the permit was being handed over
+            let Some(task) = live.get_mut(&id) else {
+                return;
+            };
+            if task.cancelled {
+                live.remove(&id);
+                return;
+            }
+            task.running = true;
+            task.dom_name.clone()
+        };
+        let log = self.log(id, "started");
+        self.save(tasks::ActiveModel {
+            id: ActiveValue::unchanged(id),
+            state: ActiveValue::set(TaskState::Running.as_str().to_string()),
+            started_at: ActiveValue::set(Some(Utc::now().timestamp())),
+            log,
+            ..Default::default()
+        })
+        .await;
+
+        let mut handle = tokio::task::spawn_blocking(body);
+        let mut interval = tokio::time::interval(Duration::from_secs(PROGRESS_INTERVAL));
+        let mut progress = None;
+        let res = loop {
+            tokio::select! {
+                res = &mut handle => break res,
+                _ = interval.tick() => {
+                    let dom_name = dom_name.clone();
+                    let current = tokio::task::spawn_blocking(move || shell::job_progress(&dom_name))
+                        .await
+                        .ok()
+                        .flatten();
+                    if current.is_some() && current != progress {
+                        progress = current;
+                        self.save(tasks::ActiveModel {
+                            id: ActiveValue::unchanged(id),
+                            progress: ActiveValue::set(progress.map(|it| it as i32)),
+                            ..Default::default()
+                        })
+                        .await;
+                    }
+                }
+            }
+        };
+
+        let cancelled = self
+            .live
+            .lock()
+            .unwrap()
+            .get(&id)
+            .is_some_and(|it| it.cancelled);
+        let (state, result) = match res {
+            Ok(Ok(output)) => (TaskState::Succeeded, output),
+            Ok(Err(e)) if cancelled => (TaskState::Cancelled, e.to_string()),
+            Ok(Err(e)) => (TaskState::Failed, e.to_string()),
+            Err(e) => (TaskState::Failed, e.to_string()),
+        };
+        let log = self.log(id, state.as_str());
+        self.live.lock().unwrap().remove(&id);
+        self.save(tasks::ActiveModel {
+            id: ActiveValue::unchanged(id),
+            state: ActiveValue::set(state.as_str().to_string()),
+            progress: match state {
+                TaskState::Succeeded => ActiveValue::set(Some(100)),
+                _ => ActiveValue::not_set(),
+            },
+            log,
+            result: ActiveValue::set(Some(result)),
+            finished_at: ActiveValue::set(Some(Utc::now().timestamp())),
+            ..Default::default()
+        })
+        .await;
+        drop(lock);
+    }
+
+    // a queued task is dropped on the spot; a running one gets its libvirt job
+    // aborted and ends up cancelled if that makes it fail, without a job to abort
+    // it can't be cancelled at all
+    pub async fn cancel(&self, id: i32) -> Result<String, TaskError> {
+        let task = self.live.lock().unwrap().get_mut(&id).map(|task| {
+            task.cancelled = true;
+            (task.running, task.dom_name.clone(), task.cancel.clone())
+        });
+        match task {
+            None => match Tasks::find_by_id(id).one(&self.db).await? {
+                Some(task) => Err(TaskError::Finished(id, task.state)),
+                None => Err(TaskError::NotFound(id)),
+            },
+            Some((false, _, cancel)) => {
+                let log = self.log(id, "cancelled while queued");
+                self.save(tasks::ActiveModel {
+                    id: ActiveValue::unchanged(id),
+                    state: ActiveValue::set(TaskState::Cancelled.as_str().to_string()),
+                    log,
+                    finished_at: ActiveValue::set(Some(Utc::now().timestamp())),
+                    ..Default::default()
+                })
+                .await;
+                self.live.lock().unwrap().remove(&id);
+                cancel.notify_one();
+                Ok("Task cancelled".to_string())
+            }
+            Some((true, dom_name, _)) => {
+                self.log(id, "cancel requested, aborting the domain's libvirt job");
+                let err =
+                    match tokio::task::spawn_blocking(move || shell::abort_job(&dom_name)).await {
+                        Ok(Ok(_)) => return Ok("Cancel requested".to_string()),
+                        Ok(Err(e)) => e.to_string(),
+                        Err(e) => e.to_string(),
+                    };
+                // nothing was aborted (e.g. virt-install or a qemu-img copy), so the
+                // task runs on and a failure later on is a real one
+                if let Some(task) = self.live.lock().unwrap().get_mut(&id) {
+                    task.cancelled = false;
+                }
+                self.log(id, &format!("abort failed: {}", err));
+                Err(TaskError::NotCancellable(id, err))
+            }
+        }
+    }
+
+    pub async fn get(&self, id: i32) -> Result<tasks::Model, TaskError> {
+        Tasks::find_by_id(id)
+            .one(&self.db)
+            .await?
+            .ok_or(TaskError::NotFound(id))
+    }
+
+    // newest first
+    pub async fn list(
+        &self,
+        state: Option<String>,
+        dom_name: Option<String>,
+    ) -> Result<Vec<tasks::Model>, DbErr> {
+        let mut query = Tasks::find().order_by_desc(tasks::Column::Id);
+        if let Some(state) = state {
+            query = query.filter(tasks::Column::State.eq(state));
+        }
+        if let Some(dom_name) = dom_name {
+            query = query.filter(tasks::Column::DomName.eq(dom_name));
+        }
+        query.limit(LIST_LIMIT).all(&self.db).await
+    }
+}
@@ -4,6 +4,8 @@ use rocket::http::ContentType;
 use rocket::local::asynchronous::Client;
 use serde_json::json;
 
+mod alerts;
+mod locks;
 mod virt;
 
 pub async fn get_auth(client: &Client) -> String {
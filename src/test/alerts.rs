@@ -0,0 +1,110 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    alerts::{post_webhook, AlertManager},
+    db::entity::alert_rules,
+    metrics::Metrics,
+    stats::StatsConnect,
+};
+
+// answers every post with 200 and hands its body over
+fn webhook_stub() -> (String, Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            tx.send(String::from_utf8(body).unwrap()).unwrap();
+        }
+    });
+    (url, rx)
+}
+
+fn rule(webhook_format: &str, webhook_url: &str) -> alert_rules::Model {
+    alert_rules::Model {
+        id: 1,
+        name: "backups".to_string(),
+        kind: "sched-job-failed".to_string(),
+        threshold: 0.0,
+        duration: 0,
+        target: None,
+        webhook_url: webhook_url.to_string(),
+        webhook_format: webhook_format.to_string(),
+        enabled: true,
+    }
+}
+
+fn received(bodies: &Receiver<String>) -> serde_json::Value {
+    serde_json::from_str(&bodies.recv_timeout(Duration::from_secs(10)).unwrap()).unwrap()
+}
+
+#[test]
+fn post_webhook_payloads() {
+    let (url, bodies) = webhook_stub();
+
+    post_webhook(&url, &AlertManager::test_payload(&rule("json", &url))).unwrap();
+    let body = received(&bodies);
+    assert_eq!(body["status"], "test");
+    assert_eq!(body["ruleName"], "backups");
+    assert_eq!(body["kind"], "sched-job-failed");
+
+    post_webhook(&url, &AlertManager::test_payload(&rule("slack", &url))).unwrap();
+    let body = received(&bodies);
+    assert!(body["text"]
+        .as_str()
+        .unwrap()
+        .starts_with("[TEST] backups: sched-job-failed on test"));
+}
+
+#[rocket::async_test]
+async fn update_notifies_once() {
+    let (url, bodies) = webhook_stub();
+    let alerts = AlertManager::default();
+    let stats = StatsConnect::new();
+    let metrics = Metrics::default();
+    let rules = vec![rule("json", &url)];
+
+    metrics.record_job_run("debian", false);
+    alerts.update(&rules, &stats, &metrics);
+    alerts.update(&rules, &stats, &metrics);
+    assert_eq!(alerts.active().len(), 1);
+    metrics.record_job_run("debian", true);
+    alerts.update(&rules, &stats, &metrics);
+    alerts.update(&rules, &stats, &metrics);
+    assert!(alerts.active().is_empty());
+
+    // each delivery runs on its own blocking thread, they may arrive in any order
+    let mut statuses = vec![
+        received(&bodies)["status"].to_string(),
+        received(&bodies)["status"].to_string(),
+    ];
+    statuses.sort();
+    assert_eq!(statuses, ["\"firing\"", "\"resolved\""]);
+    assert!(bodies.recv_timeout(Duration::from_secs(1)).is_err());
+}
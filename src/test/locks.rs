@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use tokio::time::{sleep, Instant};
+
+use crate::locks::DomainLocks;
+
+#[rocket::async_test]
+async fn busy_domain_conflicts() {
+    let locks = DomainLocks::with_wait(Duration::ZERO);
+    let _lock = locks.acquire("debian", "create-snapshot").await.unwrap();
+
+    let holder = locks
+        .acquire("debian", "revert-snapshot")
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(holder.dom_name, "debian");
+    assert_eq!(holder.operation, "create-snapshot");
+    // other domains aren't held up
+    assert!(locks.acquire("win11", "revert-snapshot").await.is_ok());
+}
+
+#[rocket::async_test]
+async fn queued_acquire_gets_released_lock() {
+    let locks = DomainLocks::with_wait(Duration::from_secs(10));
+    let lock = locks.acquire("debian", "create-snapshot").await.unwrap();
+    tokio::spawn(async move {
+        sleep(Duration::from_millis(100)).await;
+        drop(lock);
+    });
+
+    let _lock = locks.acquire("debian", "revert-snapshot").await.unwrap();
+    let held = locks.held();
+    assert_eq!(held.len(), 1);
+    assert_eq!(held[0].operation, "revert-snapshot");
+}
+
+#[rocket::async_test]
+async fn queued_acquire_times_out() {
+    let locks = DomainLocks::with_wait(Duration::from_millis(200));
+    let _lock = locks.acquire("debian", "create-snapshot").await.unwrap();
+
+    let start = Instant::now();
+    let holder = locks
+        .acquire("debian", "revert-snapshot")
+        .await
+        .err()
+        .unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    assert_eq!(holder.operation, "create-snapshot");
+}
@@ -37,3 +37,260 @@ async fn list_all() {
         serde_json::from_str::<Vec<Domain>>(result).unwrap()
     );
 }
+
+#[test]
+fn parse_domain_action() {
+    use crate::virt::{AltDomStateCommand, DomainAction};
+
+    let config: AltDomStateCommand =
+        serde_json::from_str(r#"{"dom_name": "debian", "state": "managed-save"}"#).unwrap();
+    assert_eq!(config.state, DomainAction::ManagedSave);
+    assert_eq!(config.timeout, None);
+    assert!(serde_json::from_str::<AltDomStateCommand>(
+        r#"{"dom_name": "debian", "state": "debian"}"#
+    )
+    .is_err());
+}
+
+#[test]
+fn relocate_domain_xml() {
+    use crate::virt::utils::relocate_domain_xml;
+    use roxmltree::Document;
+    use std::collections::HashMap;
+
+    let domain = r#"<domain type='kvm'>
+  <name>debian</name>
+  <uuid>6f6a3e2e-6c1b-4d5e-9d3a-0c2f1a7b8e01</uuid>
+  <devices>
+    <disk type='file' device='disk'>
+      <source file='/var/lib/libvirt/images/debian.qcow2'/>
+      <target dev='vda' bus='virtio'/>
+    </disk>
+    <interface type='network'>
+      <mac address='52:54:00:12:34:56'/>
+      <source network='default'/>
+    </interface>
+  </devices>
+</domain>"#;
+    let sources = HashMap::from([(
+        "/var/lib/libvirt/images/debian.qcow2".to_string(),
+        "/var/lib/libvirt/images/debian-copy.qcow2".to_string(),
+    )]);
+
+    let xml = relocate_domain_xml(domain, "debian-copy", None, &sources, true).unwrap();
+    let doc = Document::parse(&xml).unwrap();
+    let text = |tag: &str| {
+        doc.descendants()
+            .find(|it| it.has_tag_name(tag))
+            .and_then(|it| it.text())
+    };
+    assert_eq!(text("name"), Some("debian-copy"));
+    assert_eq!(text("uuid"), None);
+    assert!(doc.descendants().all(|it| !it.has_tag_name("mac")));
+    let found: Vec<(Option<&str>, Option<&str>)> = doc
+        .descendants()
+        .filter(|it| it.has_tag_name("source"))
+        .map(|it| (it.attribute("file"), it.attribute("network")))
+        .collect();
+    assert_eq!(
+        found,
+        [
+            (Some("/var/lib/libvirt/images/debian-copy.qcow2"), None),
+            (None, Some("default"))
+        ]
+    );
+
+    // in a snapshot only the captured domain moves, the snapshot keeps its name
+    let snapshot = format!(
+        "<domainsnapshot>\n  <name>snap1</name>\n  {}\n</domainsnapshot>",
+        domain
+    );
+    let uuid = "0d9c4a55-2f0e-4b8b-a1f4-3c5d6e7f8a90";
+    let xml = relocate_domain_xml(&snapshot, "debian-copy", Some(uuid), &sources, false).unwrap();
+    let doc = Document::parse(&xml).unwrap();
+    let root = doc.root_element();
+    let child = |node: roxmltree::Node<'_, '_>, tag: &str| {
+        node.children()
+            .find(|it| it.has_tag_name(tag))
+            .and_then(|it| it.text())
+            .map(String::from)
+    };
+    let domain = root
+        .children()
+        .find(|it| it.has_tag_name("domain"))
+        .unwrap();
+    assert_eq!(child(root, "name").as_deref(), Some("snap1"));
+    assert_eq!(child(domain, "name").as_deref(), Some("debian-copy"));
+    assert_eq!(child(domain, "uuid").as_deref(), Some(uuid));
+    assert!(doc.descendants().any(|it| it.has_tag_name("mac")));
+}
+
+#[test]
+fn edit_snapshot_xml() {
+    use crate::virt::utils::edit_snapshot_xml;
+    use roxmltree::Document;
+
+    let snapshot = r#"<domainsnapshot>
+  <name>snap2</name>
+  <description>before upgrade</description>
+  <parent>
+    <name>snap1</name>
+  </parent>
+  <domain type='kvm'>
+    <name>debian</name>
+    <description>web server</description>
+  </domain>
+</domainsnapshot>"#;
+    // texts of the elements at `path` below the root
+    let texts = |xml: &str, path: &[&str]| -> Vec<String> {
+        let doc = Document::parse(xml).unwrap();
+        let mut nodes = vec![doc.root_element()];
+        for tag in path {
+            nodes = nodes
+                .into_iter()
+                .flat_map(|it| it.children().filter(|it| it.has_tag_name(*tag)))
+                .collect();
+        }
+        nodes
+            .iter()
+            .filter_map(|it| it.text().map(String::from))
+            .collect()
+    };
+
+    let xml = edit_snapshot_xml(snapshot, Some("after upgrade"), None).unwrap();
+    assert_eq!(texts(&xml, &["description"]), ["after upgrade"]);
+    assert_eq!(texts(&xml, &["parent", "name"]), ["snap1"]);
+    assert_eq!(texts(&xml, &["domain", "description"]), ["web server"]);
+
+    let xml = edit_snapshot_xml(snapshot, None, Some(Some("snap0"))).unwrap();
+    assert_eq!(texts(&xml, &["description"]), ["before upgrade"]);
+    assert_eq!(texts(&xml, &["parent", "name"]), ["snap0"]);
+
+    let xml = edit_snapshot_xml(snapshot, None, Some(None)).unwrap();
+    assert!(texts(&xml, &["parent", "name"]).is_empty());
+    assert_eq!(texts(&xml, &["name"]), ["snap2"]);
+    assert_eq!(texts(&xml, &["domain", "name"]), ["debian"]);
+    assert_eq!(texts(&xml, &["domain", "description"]), ["web server"]);
+
+    // a root snapshot gets a parent it didn't have
+    let root = edit_snapshot_xml(snapshot, None, Some(None)).unwrap();
+    let xml = edit_snapshot_xml(&root, Some(""), Some(Some("snap1"))).unwrap();
+    assert_eq!(texts(&xml, &["parent", "name"]), ["snap1"]);
+    assert_eq!(texts(&xml, &["domain", "description"]), ["web server"]);
+}
+
+#[test]
+fn canonical_device() {
+    use crate::virt::snapshot::canonical;
+    use roxmltree::Document;
+
+    let live = Document::parse(
+        r#"<disk type='file' device='disk'>
+  <source file='/a.qcow2'/>
+  <target dev='vda' bus='virtio'/>
+  <alias name='virtio-disk0'/>
+</disk>"#,
+    )
+    .unwrap();
+    let inactive = Document::parse(
+        r#"<disk device='disk' type='file'><source file='/a.qcow2'/><target bus='virtio' dev='vda'/></disk>"#,
+    )
+    .unwrap();
+    assert_eq!(
+        canonical(live.root_element()),
+        "disk[device=disk,type=file](source[file=/a.qcow2] target[bus=virtio,dev=vda])"
+    );
+    assert_eq!(
+        canonical(live.root_element()),
+        canonical(inactive.root_element())
+    );
+}
+
+#[test]
+fn compare_devices() {
+    use crate::virt::snapshot::compare_devices;
+    use roxmltree::Document;
+
+    let from = Document::parse(
+        r#"<domain>
+  <devices>
+    <disk type='file' device='disk'><source file='/a.qcow2'/><target dev='vda'/></disk>
+    <interface type='network'><mac address='52:54:00:00:00:01'/><source network='default'/></interface>
+  </devices>
+</domain>"#,
+    )
+    .unwrap();
+    let to = Document::parse(
+        r#"<domain>
+  <devices>
+    <disk type='file' device='disk'><source file='/b.qcow2'/><target dev='vda'/></disk>
+    <disk type='file' device='disk'><source file='/c.qcow2'/><target dev='vdb'/></disk>
+  </devices>
+</domain>"#,
+    )
+    .unwrap();
+    let changes = compare_devices(Some(from.root_element()), Some(to.root_element()));
+    let summary: Vec<(&str, &str, &str)> = changes
+        .iter()
+        .map(|it| (it.change.as_str(), it.device.as_str(), it.key.as_str()))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            ("changed", "disk", "vda"),
+            ("removed", "interface", "52:54:00:00:00:01"),
+            ("added", "disk", "vdb"),
+        ]
+    );
+    assert!(changes[0].from.as_deref().unwrap().contains("/a.qcow2"));
+    assert!(changes[0].to.as_deref().unwrap().contains("/b.qcow2"));
+
+    assert!(compare_devices(Some(to.root_element()), Some(to.root_element())).is_empty());
+    assert_eq!(compare_devices(None, Some(to.root_element())).len(), 2);
+}
+
+#[test]
+fn replace_boot_devices() {
+    use crate::virt::utils::replace_boot_devices;
+    use roxmltree::Document;
+
+    let xml = replace_boot_devices(
+        r#"<domain>
+  <os>
+    <type>hvm</type>
+    <boot dev='hd'/>
+  </os>
+  <devices>
+    <disk type='file' device='disk'>
+      <boot order='1'/>
+      <target dev='vda'/>
+    </disk>
+  </devices>
+</domain>"#,
+        &["cdrom".to_string(), "hd".to_string()],
+    );
+    let doc = Document::parse(&xml).unwrap();
+    let boots: Vec<(&str, Option<&str>)> = doc
+        .descendants()
+        .filter(|it| it.has_tag_name("boot"))
+        .map(|it| (it.parent().unwrap().tag_name().name(), it.attribute("dev")))
+        .collect();
+    assert_eq!(boots, [("os", Some("cdrom")), ("os", Some("hd"))]);
+    assert!(doc
+        .descendants()
+        .any(|it| it.has_tag_name("target") && it.attribute("dev") == Some("vda")));
+}
+
+#[test]
+fn parse_size() {
+    use crate::virt::utils::parse_size;
+
+    assert_eq!(parse_size("20G"), Some(20 << 30));
+    assert_eq!(parse_size("512MiB"), Some(512 << 20));
+    assert_eq!(parse_size(" 4 kb "), Some(4096));
+    assert_eq!(parse_size("1073741824"), Some(1 << 30));
+    assert_eq!(parse_size(""), None);
+    assert_eq!(parse_size("12X"), None);
+    assert_eq!(parse_size("G"), None);
+    assert_eq!(parse_size("99999999999T"), None);
+}
@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     sync::{
         mpsc::{self, Receiver, Sender, SyncSender},
         Mutex,
@@ -10,13 +11,38 @@ use sysinfo::System;
 use thiserror::Error;
 use virt::connect::Connect;
 
+use self::archive::export_plan;
 use self::conn::*;
+use self::disk::*;
+use self::hardware::*;
+use self::interface::*;
+pub use self::journal::pending as pending_operations;
+use self::metadata::*;
+use self::network::*;
+use self::power::*;
+use self::snapshot::*;
+use self::storage::*;
 use self::sys::*;
+pub use self::sys::{HostMonitor, HostStatus};
 
+pub mod archive;
+pub mod backup;
 mod conn;
+mod disk;
+mod hardware;
+mod interface;
+mod journal;
+mod metadata;
+mod network;
+mod power;
 pub mod shell;
+pub(crate) mod snapshot;
+mod storage;
 mod sys;
-mod utils;
+pub mod utils;
+
+pub const DEFAULT_POOL: &str = "default";
+pub const DEFAULT_NETWORK: &str = "default";
 
 pub struct VirtCommand {
     cmd: VirtCommandType,
@@ -32,8 +58,22 @@ pub enum VirtError {
         dom_name: String,
         snapshot_name: String,
     },
+    #[error("Storage pool {0} not found")]
+    PoolNotFound(String),
+    #[error("No Volume named {vol_name:?} in Storage pool {pool_name:?}")]
+    VolumeNotFound { pool_name: String, vol_name: String },
+    #[error("Network {0} not found")]
+    NetworkNotFound(String),
+    #[error("Domain {dom_name} is {state}, can not {action:?} it")]
+    InvalidState {
+        dom_name: String,
+        state: String,
+        action: DomainAction,
+    },
     #[error("Input Invalid")]
     InvalidInput,
+    #[error("Input Invalid: {0}")]
+    InvalidConfig(String),
     #[error("System Internal Error")]
     VirtInternalError(#[from] virt::error::Error),
     #[error("Error: {0}")]
@@ -46,17 +86,45 @@ pub enum VirtCommandType {
     ListAll,
     ListSnapshot,
     ListSnapshotTree,
-    SysInfo,
+    HostVersions,
+    CountSnapshots,
     EditSnapshot,
+    DiffSnapshots,
+    PreviewRevert,
+    ExportDomain,
+    EditHardware,
+    ListDisks,
+    AttachDisk,
+    DetachDisk,
+    ResizeDisk,
+    ListBackingChains,
+    ListPools,
+    CreatePool,
+    RefreshPool,
+    GetPoolPath,
+    ListVolumes,
+    CreateVolume,
+    GetVolumePath,
+    DeleteVolume,
+    ListNetworks,
+    CreateNetwork,
+    DestroyNetwork,
+    UndefineNetwork,
+    ListInterfaces,
+    AttachInterface,
+    DetachInterface,
+    SetDomainState,
+    GetDomainState,
+    DeleteDomain,
+    GetDomainMetadata,
+    SetDomainMetadata,
+    SelectDomains,
+    RenameDomain,
+    GetAutostart,
+    SetAutostart,
 }
 
 impl VirtCommand {
-    pub fn create(cmd: VirtCommandType) -> Self {
-        VirtCommand {
-            cmd,
-            params: Vec::new(),
-        }
-    }
     pub fn create_with_params(cmd: VirtCommandType, params: Vec<String>) -> Self {
         VirtCommand { cmd, params }
     }
@@ -78,13 +146,69 @@ impl VirtConnect {
             loop {
                 if let Ok(VirtCommand { cmd, params }) = virt_rx.recv() {
                     match cmd {
-                        VirtCommandType::ListAll => list_all(&conn, &main_tx),
+                        VirtCommandType::ListAll => list_all(&conn, &main_tx, &params),
                         VirtCommandType::ListSnapshot => list_snapshot(&conn, &main_tx, &params),
                         VirtCommandType::ListSnapshotTree => {
-                            list_snapshot_tree(&conn, &main_tx, &params)
+                            snapshot_tree(&conn, &main_tx, &params)
                         }
-                        VirtCommandType::SysInfo => get_sysinfo(&main_tx, &mut sys),
+                        VirtCommandType::HostVersions => get_host_versions(&conn, &main_tx),
+                        VirtCommandType::CountSnapshots => count_snapshots(&conn, &main_tx),
                         VirtCommandType::EditSnapshot => edit_snapshot(&conn, &main_tx, &params),
+                        VirtCommandType::DiffSnapshots => diff_snapshots(&conn, &main_tx, &params),
+                        VirtCommandType::PreviewRevert => preview_revert(&conn, &main_tx, &params),
+                        VirtCommandType::ExportDomain => export_plan(&conn, &main_tx, &params),
+                        VirtCommandType::EditHardware => {
+                            edit_hardware(&conn, &main_tx, &params, &mut sys)
+                        }
+                        VirtCommandType::ListDisks => list_disks(&conn, &main_tx, &params),
+                        VirtCommandType::AttachDisk => attach_disk(&conn, &main_tx, &params),
+                        VirtCommandType::DetachDisk => detach_disk(&conn, &main_tx, &params),
+                        VirtCommandType::ResizeDisk => resize_disk(&conn, &main_tx, &params),
+                        VirtCommandType::ListBackingChains => {
+                            list_backing_chains(&conn, &main_tx, &params)
+                        }
+                        VirtCommandType::ListPools => list_pools(&conn, &main_tx),
+                        VirtCommandType::CreatePool => create_pool(&conn, &main_tx, &params),
+                        VirtCommandType::RefreshPool => refresh_pool(&conn, &main_tx, &params),
+                        VirtCommandType::GetPoolPath => get_pool_path(&conn, &main_tx, &params),
+                        VirtCommandType::ListVolumes => list_volumes(&conn, &main_tx, &params),
+                        VirtCommandType::CreateVolume => create_volume(&conn, &main_tx, &params),
+                        VirtCommandType::GetVolumePath => get_volume_path(&conn, &main_tx, &params),
+                        VirtCommandType::DeleteVolume => delete_volume(&conn, &main_tx, &params),
+                        VirtCommandType::ListNetworks => list_networks(&conn, &main_tx),
+                        VirtCommandType::CreateNetwork => create_network(&conn, &main_tx, &params),
+                        VirtCommandType::DestroyNetwork => {
+                            destroy_network(&conn, &main_tx, &params)
+                        }
+                        VirtCommandType::UndefineNetwork => {
+                            undefine_network(&conn, &main_tx, &params)
+                        }
+                        VirtCommandType::ListInterfaces => {
+                            list_interfaces(&conn, &main_tx, &params)
+                        }
+                        VirtCommandType::AttachInterface => {
+                            attach_interface(&conn, &main_tx, &params)
+                        }
+                        VirtCommandType::DetachInterface => {
+                            detach_interface(&conn, &main_tx, &params)
+                        }
+                        VirtCommandType::SetDomainState => {
+                            set_domain_state(&conn, &main_tx, &params)
+                        }
+                        VirtCommandType::GetDomainState => {
+                            get_domain_state(&conn, &main_tx, &params)
+                        }
+                        VirtCommandType::DeleteDomain => delete_domain(&conn, &main_tx, &params),
+                        VirtCommandType::GetDomainMetadata => {
+                            get_domain_metadata(&conn, &main_tx, &params)
+                        }
+                        VirtCommandType::SetDomainMetadata => {
+                            set_domain_metadata(&conn, &main_tx, &params)
+                        }
+                        VirtCommandType::SelectDomains => select_domains(&conn, &main_tx, &params),
+                        VirtCommandType::RenameDomain => rename_domain(&conn, &main_tx, &params),
+                        VirtCommandType::GetAutostart => get_autostart(&conn, &main_tx, &params),
+                        VirtCommandType::SetAutostart => set_autostart(&conn, &main_tx, &params),
                     }
                 } else {
                     conn.close().unwrap();
@@ -108,17 +232,185 @@ pub struct SnapShotConfig {
     pub is_live: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExternalSnapshotConfig {
+    pub dom_name: String,
+    pub snapshot_name: String,
+    pub description: Option<String>,
+    // overlays are created here, next to each disk's current image when unset
+    pub directory: Option<String>,
+    // disk targets like vda, all hard disks when unset
+    pub disks: Option<Vec<String>>,
+    // freeze guest filesystems through the qemu guest agent while snapshotting
+    pub quiesce: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockJobConfig {
+    pub dom_name: String,
+    pub target: String,
+    // image of the chain to commit into or pull down to, the bottom one when unset
+    pub base: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SnapShotEditConfig {
     pub dom_name: String,
     pub snapshot_name: String,
-    pub description: String,
+    pub description: Option<String>,
+    // new parent snapshot, empty makes it a root; internal snapshots only
+    pub parent: Option<String>,
+    // kept in the database, libvirt has no place for them; empty clears it
+    pub display_name: Option<String>,
+    // replaces all of the snapshot's labels
+    pub labels: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RevertConfig {
+    pub dom_name: String,
+    pub snapshot_name: String,
+    // save the state being left as a pre-revert snapshot first, on when unset
+    pub save_current: Option<bool>,
+    // only report what the revert would throw away
+    pub dry_run: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotDiffConfig {
+    pub dom_name: String,
+    // snapshot names, the diff reads as going from `from` to `to`
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DomHardwareConfig {
+    pub dom_name: String,
+    pub vcpu: Option<u32>,
+    // KiB, same unit as the memory reported by list_all
+    pub memory: Option<u64>,
+    pub boot_order: Option<Vec<String>>,
+    // also apply to the running domain where libvirt allows hot changes,
+    // the persistent config is always updated
+    pub live: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateDiskConfig {
+    pub name: String,
+    pub size: String,
+    // qcow2 or raw, defaults to qcow2
+    pub format: Option<String>,
+    // storage pool the volume is created in, defaults to DEFAULT_POOL
+    pub pool: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoragePoolConfig {
+    pub name: String,
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StorageVolumeConfig {
+    pub pool: String,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiskConfig {
+    pub dom_name: String,
+    // target device like vdb, picked automatically when attaching without one
+    pub target: Option<String>,
+    pub path: Option<String>,
+    pub format: Option<String>,
+    pub size: Option<String>,
+    pub force: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    pub name: String,
+    // nat or isolated
+    pub mode: String,
+    // gateway address of the bridge, e.g. 192.168.100.1
+    pub address: String,
+    pub netmask: Option<String>,
+    pub dhcp_start: Option<String>,
+    pub dhcp_end: Option<String>,
+    pub autostart: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InterfaceConfig {
+    pub dom_name: String,
+    // network or bridge, defaults to network
+    pub source_type: Option<String>,
+    // network name or host bridge name
+    pub source: Option<String>,
+    // virtio or e1000, defaults to virtio
+    pub model: Option<String>,
+    // generated by libvirt when attaching without one, required to detach
+    pub mac: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DomainAction {
+    Start,
+    Shutdown,
+    Reboot,
+    Reset,
+    Suspend,
+    Resume,
+    Destroy,
+    ManagedSave,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AltDomStateCommand {
     pub dom_name: String,
+    pub state: DomainAction,
+    // seconds to wait for a graceful shutdown before destroying the domain
+    pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteDomainConfig {
+    pub dom_name: String,
+    // must repeat the domain name or uuid
+    pub confirm: String,
+    pub remove_snapshots: Option<bool>,
+    pub remove_storage: Option<bool>,
+    pub remove_nvram: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RenameDomainConfig {
+    pub dom_name: String,
+    pub new_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AutostartConfig {
+    pub dom_name: String,
+    pub autostart: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DomMetadataConfig {
+    pub dom_name: String,
+    pub description: Option<String>,
+    // replaces every label of the domain, an empty map clears them
+    pub labels: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DomStateResult {
     pub state: String,
+    // the guest ignored the shutdown request and was destroyed
+    pub forced: bool,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -129,10 +421,12 @@ enum SystemType {
 
 #[derive(Deserialize, Serialize)]
 pub struct CreateVirtConfig {
-    virt_name: String,
+    pub virt_name: String,
     memory: String,
     vcpu: String,
     system: SystemType,
     iso_filename: String,
     disk_size: String,
+    pool: Option<String>,
+    network: Option<String>,
 }
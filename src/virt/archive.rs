@@ -0,0 +1,402 @@
+use chrono::Utc;
+use roxmltree::Document;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    os::unix::fs::symlink,
+    path::{Component, Path},
+    process::Command,
+    sync::mpsc::Sender,
+};
+use virt::{connect::Connect, sys::VIR_DOMAIN_XML_INACTIVE};
+
+use super::conn::lookup_domain;
+use super::shell::{image_info, run_virsh};
+use super::utils::{relocate_domain_xml, sha256_file};
+
+use super::VirtError::{self, *};
+use super::VirtResult;
+
+// an archive is a tar of
+//   manifest.json         Manifest, with the sha256 of every other file
+//   domain.xml            the inactive definition
+//   snapshots/000.xml     snapshot xmls, parents before children
+//   disks/vda.qcow2       one image per disk, named by target
+
+const ARCHIVE_VERSION: u32 = 1;
+const MANIFEST_FILE: &str = "manifest.json";
+const DOMAIN_FILE: &str = "domain.xml";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchiveDisk {
+    pub target: String,
+    // path on the exporting host, to find the disk in the xmls again on import
+    pub source: String,
+    pub format: String,
+}
+
+impl ArchiveDisk {
+    fn file(&self) -> String {
+        format!("disks/{}.{}", self.target, self.format)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedSnapshot {
+    pub name: String,
+    pub parent: Option<String>,
+    pub current: bool,
+    pub xml: String,
+}
+
+// what the libvirt thread gathers for an export
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportPlan {
+    pub dom_name: String,
+    pub domain_xml: String,
+    pub snapshots: Vec<PlannedSnapshot>,
+    pub disks: Vec<ArchiveDisk>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestSnapshot {
+    pub name: String,
+    pub parent: Option<String>,
+    pub current: bool,
+    pub file: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub version: u32,
+    pub dom_name: String,
+    pub created_at: i64,
+    pub snapshots: Vec<ManifestSnapshot>,
+    pub disks: Vec<ArchiveDisk>,
+    // archive path to sha256
+    pub checksums: BTreeMap<String, String>,
+}
+
+pub fn export_plan(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<ExportPlan, VirtError> {
+        let dom_name = params.first().ok_or(InvalidInput)?;
+        let dom = lookup_domain(conn, dom_name)?;
+        // the disks of a running domain keep changing under the copy
+        if dom.is_active()? {
+            return Err(InvalidConfig(format!(
+                "Domain {} must be shut off to export it",
+                dom_name
+            )));
+        }
+        let domain_xml = dom.get_xml_desc(VIR_DOMAIN_XML_INACTIVE)?;
+        let doc = Document::parse(&domain_xml).map_err(|e| OtherError(e.to_string()))?;
+        let disks = doc
+            .descendants()
+            .filter(|it| it.has_tag_name("disk") && it.attribute("device") == Some("disk"))
+            .filter_map(|disk| {
+                let child = |tag: &str| disk.children().find(|it| it.has_tag_name(tag));
+                Some(ArchiveDisk {
+                    target: child("target")?.attribute("dev")?.to_string(),
+                    source: child("source")?.attribute("file")?.to_string(),
+                    format: child("driver")
+                        .and_then(|it| it.attribute("type"))
+                        .unwrap_or("raw")
+                        .to_string(),
+                })
+            })
+            .collect();
+
+        let mut pending = Vec::new();
+        for snapshot in dom.list_all_snapshots(0)? {
+            let xml = snapshot.get_xml_desc(0)?;
+            let parent = Document::parse(&xml).ok().and_then(|doc| {
+                let parent = doc
+                    .root_element()
+                    .children()
+                    .find(|it| it.has_tag_name("parent"))?;
+                let name = parent.children().find(|it| it.has_tag_name("name"))?;
+                name.text().map(String::from)
+            });
+            pending.push(PlannedSnapshot {
+                name: snapshot.get_name()?,
+                parent,
+                current: snapshot.is_current(0)?,
+                xml,
+            });
+        }
+        // parents first, --redefine wants a snapshot's parent to exist already
+        let mut snapshots: Vec<PlannedSnapshot> = Vec::new();
+        while !pending.is_empty() {
+            let (ready, rest): (Vec<_>, Vec<_>) = pending.into_iter().partition(|it| {
+                it.parent
+                    .as_ref()
+                    .is_none_or(|parent| snapshots.iter().any(|s| &s.name == parent))
+            });
+            if ready.is_empty() {
+                snapshots.extend(rest);
+                break;
+            }
+            snapshots.extend(ready);
+            pending = rest;
+        }
+        Ok(ExportPlan {
+            dom_name: dom_name.clone(),
+            domain_xml,
+            snapshots,
+            disks,
+        })
+    };
+    match res() {
+        Ok(plan) => main_tx
+            .send(VirtResult::Ok(serde_json::to_string(&plan).unwrap()))
+            .unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub(super) fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.into())
+}
+
+pub(super) fn run(cmd: &mut Command) -> Result<(), io::Error> {
+    let output = cmd.output()?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(invalid(String::from_utf8_lossy(&output.stderr).trim())),
+    }
+}
+
+// lays the archive out in `staging`: the xmls, the disks symlinked in (tar -h
+// follows them, so images aren't copied twice) and the manifest
+pub fn stage_export(plan: &ExportPlan, staging: &Path) -> Result<(), io::Error> {
+    fs::create_dir_all(staging.join("snapshots"))?;
+    fs::create_dir_all(staging.join("disks"))?;
+    let mut checksums = BTreeMap::new();
+    fs::write(staging.join(DOMAIN_FILE), &plan.domain_xml)?;
+    checksums.insert(
+        DOMAIN_FILE.to_string(),
+        sha256_file(&staging.join(DOMAIN_FILE))?,
+    );
+    let mut snapshots = Vec::new();
+    for (index, snapshot) in plan.snapshots.iter().enumerate() {
+        let file = format!("snapshots/{:03}.xml", index);
+        fs::write(staging.join(&file), &snapshot.xml)?;
+        checksums.insert(file.clone(), sha256_file(&staging.join(&file))?);
+        snapshots.push(ManifestSnapshot {
+            name: snapshot.name.clone(),
+            parent: snapshot.parent.clone(),
+            current: snapshot.current,
+            file,
+        });
+    }
+    for disk in &plan.disks {
+        // an overlay only holds changes on top of its backing file, the chain has
+        // to be flattened first
+        if let Some(backing) = image_info(&disk.source)?["backing-filename"].as_str() {
+            return Err(invalid(format!(
+                "disk {} has backing file {}, flatten it before exporting",
+                disk.target, backing
+            )));
+        }
+        symlink(&disk.source, staging.join(disk.file()))?;
+        checksums.insert(disk.file(), sha256_file(Path::new(&disk.source))?);
+    }
+    let manifest = Manifest {
+        version: ARCHIVE_VERSION,
+        dom_name: plan.dom_name.clone(),
+        created_at: Utc::now().timestamp(),
+        snapshots,
+        disks: plan.disks.clone(),
+        checksums,
+    };
+    fs::write(
+        staging.join(MANIFEST_FILE),
+        serde_json::to_vec_pretty(&manifest).unwrap(),
+    )
+}
+
+// arguments for `tar -C staging`, the manifest goes first
+pub fn archive_members() -> [&'static str; 4] {
+    [MANIFEST_FILE, DOMAIN_FILE, "snapshots", "disks"]
+}
+
+// just the manifest, to settle the name before anything is unpacked
+pub fn read_manifest(archive: &Path) -> Result<Manifest, io::Error> {
+    let output = Command::new("tar")
+        .arg("-xOf")
+        .arg(archive)
+        .arg(MANIFEST_FILE)
+        .output()?;
+    if !output.status.success() {
+        return Err(invalid(String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    let manifest: Manifest = serde_json::from_slice(&output.stdout)
+        .map_err(|e| invalid(format!("invalid manifest: {}", e)))?;
+    if manifest.version != ARCHIVE_VERSION {
+        return Err(invalid(format!(
+            "unsupported archive version {}",
+            manifest.version
+        )));
+    }
+    Ok(manifest)
+}
+
+fn is_relative(file: &str) -> bool {
+    Path::new(file)
+        .components()
+        .all(|it| matches!(it, Component::Normal(_)))
+}
+
+fn verify(manifest: &Manifest, staging: &Path) -> Result<(), io::Error> {
+    let referenced = [DOMAIN_FILE.to_string()]
+        .into_iter()
+        .chain(manifest.snapshots.iter().map(|it| it.file.clone()))
+        .chain(manifest.disks.iter().map(|it| it.file()));
+    for file in referenced {
+        if !manifest.checksums.contains_key(&file) {
+            return Err(invalid(format!("no checksum for {}", file)));
+        }
+    }
+    for (file, checksum) in &manifest.checksums {
+        if !is_relative(file) {
+            return Err(invalid(format!("invalid path {} in manifest", file)));
+        }
+        if sha256_file(&staging.join(file))? != *checksum {
+            return Err(invalid(format!("checksum mismatch for {}", file)));
+        }
+    }
+    Ok(())
+}
+
+// the archive comes from the client: a symlink (or device, fifo) among its members
+// would make verify and move_file work on whatever host file it points to
+fn check_members(dir: &Path) -> Result<(), io::Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            check_members(&entry.path())?;
+        } else if !file_type.is_file() {
+            return Err(invalid(format!(
+                "{} in the archive is not a regular file",
+                entry.file_name().to_string_lossy()
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn move_file(from: &Path, to: &Path) -> Result<(), io::Error> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    // staging and pool on different filesystems
+    run(Command::new("cp").arg("--sparse=always").arg(from).arg(to))
+}
+
+// recreates the archived domain as `name` with its disks in the pool and its
+// snapshots redefined; on failure nothing of it is left behind
+pub fn import(
+    archive: &Path,
+    pool: &str,
+    pool_path: &Path,
+    name: &str,
+) -> Result<String, io::Error> {
+    let staging = archive.with_extension("d");
+    let mut disks = Vec::new();
+    let res = import_staged(archive, &staging, pool_path, name, &mut disks);
+    if res.is_err() {
+        for disk in disks {
+            let _ = fs::remove_file(disk);
+        }
+    }
+    let _ = fs::remove_dir_all(&staging);
+    let _ = fs::remove_file(archive);
+    if res.is_ok() {
+        // make libvirt pick up the new files as volumes
+        let _ = run_virsh(&["pool-refresh", pool]);
+    }
+    res
+}
+
+fn import_staged(
+    archive: &Path,
+    staging: &Path,
+    pool_path: &Path,
+    name: &str,
+    disks: &mut Vec<String>,
+) -> Result<String, io::Error> {
+    fs::create_dir_all(staging)?;
+    run(Command::new("tar")
+        .arg("-xSf")
+        .arg(archive)
+        .arg("-C")
+        .arg(staging))?;
+    check_members(staging)?;
+    let manifest: Manifest = serde_json::from_slice(&fs::read(staging.join(MANIFEST_FILE))?)
+        .map_err(|e| invalid(format!("invalid manifest: {}", e)))?;
+    verify(&manifest, staging)?;
+
+    let mut sources = HashMap::new();
+    for disk in &manifest.disks {
+        let dest = pool_path.join(format!("{}-{}.{}", name, disk.target, disk.format));
+        if dest.exists() {
+            return Err(invalid(format!("{} already exists", dest.display())));
+        }
+        move_file(&staging.join(disk.file()), &dest)?;
+        let dest = dest.to_string_lossy().into_owned();
+        disks.push(dest.clone());
+        sources.insert(disk.source.clone(), dest);
+    }
+    // keeping the macs of a renamed copy would clash with the original
+    let drop_macs = name != manifest.dom_name;
+    let xml = relocate_domain_xml(
+        &fs::read_to_string(staging.join(DOMAIN_FILE))?,
+        name,
+        None,
+        &sources,
+        drop_macs,
+    )
+    .map_err(invalid)?;
+    let xml_path = staging.join("import.xml");
+    let xml_path_str = xml_path.to_string_lossy().into_owned();
+    fs::write(&xml_path, xml)?;
+    run_virsh(&["define", &xml_path_str])?;
+
+    let redefine = || -> Result<(), io::Error> {
+        // the snapshots must carry the uuid libvirt gave the new domain
+        let uuid = run_virsh(&["domuuid", name])?.trim().to_string();
+        for snapshot in &manifest.snapshots {
+            let xml = relocate_domain_xml(
+                &fs::read_to_string(staging.join(&snapshot.file))?,
+                name,
+                Some(&uuid),
+                &sources,
+                drop_macs,
+            )
+            .map_err(invalid)?;
+            fs::write(&xml_path, xml)?;
+            let mut args = vec!["snapshot-create", name, &xml_path_str, "--redefine"];
+            if snapshot.current {
+                args.push("--current");
+            }
+            run_virsh(&args)?;
+        }
+        Ok(())
+    };
+    if let Err(e) = redefine() {
+        let _ = run_virsh(&["undefine", name, "--snapshots-metadata"]);
+        return Err(e);
+    }
+    Ok(format!(
+        "Imported {} with {} snapshots",
+        name,
+        manifest.snapshots.len()
+    ))
+}
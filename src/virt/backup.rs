@@ -0,0 +1,508 @@
+use chrono::Utc;
+use quick_xml::escape::escape;
+use roxmltree::Document;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::Duration,
+};
+
+use super::archive::{invalid, run};
+use super::shell::{image_info, run_virsh};
+use super::utils::{relocate_domain_xml, sha256_file};
+
+// a backup is a directory <target>/<domain>/<stamp> holding
+//   domain.xml     the inactive definition, to restore as a new domain
+//   vda.qcow2      one image per disk, named by target. an incremental one only
+//                  holds what changed since its parent and has the parent's
+//                  image as backing file
+
+const DOMAIN_FILE: &str = "domain.xml";
+const JOB_POLL_INTERVAL: u64 = 2;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupDisk {
+    pub target: String,
+    // path on the domain when it was backed up, to find the disk in domain.xml again
+    pub source: String,
+    // format of the source, a restore writes the disk back in it
+    pub format: String,
+}
+
+impl BackupDisk {
+    // always qcow2 whatever the source is, increments need a backing file
+    pub fn file(&self) -> String {
+        format!("{}.qcow2", self.target)
+    }
+}
+
+// an earlier backup the next one can be incremental on
+pub struct BackupBase {
+    pub dir: PathBuf,
+    pub checkpoint: String,
+    pub disks: Vec<BackupDisk>,
+}
+
+pub struct BackupOutcome {
+    pub incremental: bool,
+    // libvirt for a backup job, copy for qemu-img copies
+    pub method: &'static str,
+    // what the next incremental backup can start from
+    pub checkpoint: Option<String>,
+    pub disks: Vec<BackupDisk>,
+    // file in the backup directory to sha256
+    pub checksums: BTreeMap<String, String>,
+    pub size_bytes: u64,
+}
+
+fn stamp() -> String {
+    Utc::now().format("%Y%m%d-%H%M%S").to_string()
+}
+
+// file backed hard disks, and the targets of those without a file (network or
+// block devices), which are left out
+fn domain_disks(dom_name: &str) -> Result<(Vec<BackupDisk>, Vec<String>), io::Error> {
+    let xml = run_virsh(&["dumpxml", dom_name])?;
+    let doc = Document::parse(&xml).map_err(|e| invalid(e.to_string()))?;
+    let mut disks = Vec::new();
+    let mut skipped = Vec::new();
+    for disk in doc
+        .descendants()
+        .filter(|it| it.has_tag_name("disk") && it.attribute("device") == Some("disk"))
+    {
+        let child = |tag: &str| disk.children().find(|it| it.has_tag_name(tag));
+        let Some(target) = child("target").and_then(|it| it.attribute("dev")) else {
+            continue;
+        };
+        match child("source").and_then(|it| it.attribute("file")) {
+            Some(source) => disks.push(BackupDisk {
+                target: target.to_string(),
+                source: source.to_string(),
+                format: child("driver")
+                    .and_then(|it| it.attribute("type"))
+                    .unwrap_or("raw")
+                    .to_string(),
+            }),
+            None => skipped.push(target.to_string()),
+        }
+    }
+    Ok((disks, skipped))
+}
+
+fn is_shut_off(dom_name: &str) -> Result<bool, io::Error> {
+    Ok(run_virsh(&["domstate", dom_name])?.trim() == "shut off")
+}
+
+fn convert(from: &Path, to: &Path, format: &str) -> Result<(), io::Error> {
+    run(Command::new("qemu-img")
+        .args(["convert", "-O", format])
+        .arg(from)
+        .arg(to))
+}
+
+pub fn drop_checkpoint(dom_name: &str, checkpoint: &str) -> Result<String, io::Error> {
+    run_virsh(&["checkpoint-delete", dom_name, checkpoint])
+}
+
+// backs the domain's disks up into `dir`: with a libvirt backup job while it runs,
+// incremental on `base` when its checkpoint is still there, and as full qemu-img
+// copies when it's shut off or libvirt can't do it. a failed backup leaves nothing
+pub fn backup(
+    dom_name: &str,
+    dir: &Path,
+    base: Option<&BackupBase>,
+) -> Result<BackupOutcome, io::Error> {
+    if dir.exists() {
+        return Err(invalid(format!("{} already exists", dir.display())));
+    }
+    fs::create_dir_all(dir)?;
+    let res = backup_into(dom_name, dir, base);
+    if res.is_err() {
+        let _ = fs::remove_dir_all(dir);
+    }
+    res
+}
+
+fn backup_into(
+    dom_name: &str,
+    dir: &Path,
+    base: Option<&BackupBase>,
+) -> Result<BackupOutcome, io::Error> {
+    fs::write(
+        dir.join(DOMAIN_FILE),
+        run_virsh(&["dumpxml", dom_name, "--inactive"])?,
+    )?;
+    let (disks, skipped) = domain_disks(dom_name)?;
+    if disks.is_empty() {
+        return Err(invalid(format!("{} has no disk to back up", dom_name)));
+    }
+    let (incremental, method, checkpoint) = match is_shut_off(dom_name)? {
+        true => {
+            for disk in &disks {
+                convert(Path::new(&disk.source), &dir.join(disk.file()), "qcow2")?;
+            }
+            (false, "copy", None)
+        }
+        false => match libvirt_backup(dom_name, dir, &disks, &skipped, base)? {
+            Some((incremental, checkpoint)) => (incremental, "libvirt", checkpoint),
+            // no backup jobs in this libvirt or qemu
+            None => {
+                copy_running(dom_name, dir, &disks, &skipped)?;
+                (false, "copy", None)
+            }
+        },
+    };
+    let mut checksums = BTreeMap::new();
+    let mut size_bytes = 0;
+    for file in [DOMAIN_FILE.to_string()]
+        .into_iter()
+        .chain(disks.iter().map(|it| it.file()))
+    {
+        let path = dir.join(&file);
+        size_bytes += fs::metadata(&path)?.len();
+        checksums.insert(file, sha256_file(&path)?);
+    }
+    Ok(BackupOutcome {
+        incremental,
+        method,
+        checkpoint,
+        disks,
+        checksums,
+        size_bytes,
+    })
+}
+
+fn backup_xml(dir: &Path, disks: &[BackupDisk], skipped: &[String], base: Option<&str>) -> String {
+    let mut xml = String::from("<domainbackup mode='push'>\n");
+    if let Some(checkpoint) = base {
+        xml += &format!("  <incremental>{}</incremental>\n", escape(checkpoint));
+    }
+    xml += "  <disks>\n";
+    for disk in disks {
+        xml += &format!(
+            "    <disk name='{}' backup='yes' type='file'>\n      <target file='{}'/>\n      <driver type='qcow2'/>\n    </disk>\n",
+            escape(&disk.target),
+            escape(&dir.join(disk.file()).to_string_lossy())
+        );
+    }
+    for target in skipped {
+        xml += &format!("    <disk name='{}' backup='no'/>\n", escape(target));
+    }
+    xml + "  </disks>\n</domainbackup>\n"
+}
+
+fn checkpoint_xml(name: &str, disks: &[BackupDisk], skipped: &[String]) -> String {
+    let mut xml = format!(
+        "<domaincheckpoint>\n  <name>{}</name>\n  <disks>\n",
+        escape(name)
+    );
+    for disk in disks {
+        xml += &format!(
+            "    <disk name='{}' checkpoint='bitmap'/>\n",
+            escape(&disk.target)
+        );
+    }
+    for target in skipped {
+        xml += &format!("    <disk name='{}' checkpoint='no'/>\n", escape(target));
+    }
+    xml + "  </disks>\n</domaincheckpoint>\n"
+}
+
+fn job_type(info: &str) -> String {
+    info.lines()
+        .find_map(|line| line.strip_prefix("Job type:"))
+        .unwrap_or("")
+        .trim()
+        .to_string()
+}
+
+// backup-begin returns as soon as the job started; it's over once the domain has
+// no job left, and --completed tells how it ended
+fn wait_for_job(dom_name: &str) -> Result<(), io::Error> {
+    loop {
+        thread::sleep(Duration::from_secs(JOB_POLL_INTERVAL));
+        if job_type(&run_virsh(&["domjobinfo", dom_name])?) == "None" {
+            break;
+        }
+    }
+    match job_type(&run_virsh(&["domjobinfo", dom_name, "--completed"])?).as_str() {
+        "Completed" => Ok(()),
+        state => Err(invalid(format!(
+            "backup job of {} ended {}",
+            dom_name,
+            state.to_lowercase()
+        ))),
+    }
+}
+
+// none when libvirt won't start the job at all
+fn libvirt_backup(
+    dom_name: &str,
+    dir: &Path,
+    disks: &[BackupDisk],
+    skipped: &[String],
+    base: Option<&BackupBase>,
+) -> Result<Option<(bool, Option<String>)>, io::Error> {
+    // incremental only while the base's checkpoint is there and no disk came or went
+    let base = base.filter(|base| {
+        base.disks.len() == disks.len()
+            && disks
+                .iter()
+                .all(|disk| base.disks.iter().any(|it| it.target == disk.target))
+            && run_virsh(&["checkpoint-list", dom_name, "--name"])
+                .is_ok_and(|out| out.lines().any(|it| it.trim() == base.checkpoint))
+    });
+    let checkpoint = format!("backup-{}", stamp());
+    let backup_file = dir.join("backup.xml");
+    let checkpoint_file = dir.join("checkpoint.xml");
+    fs::write(
+        &backup_file,
+        backup_xml(dir, disks, skipped, base.map(|it| it.checkpoint.as_str())),
+    )?;
+    fs::write(
+        &checkpoint_file,
+        checkpoint_xml(&checkpoint, disks, skipped),
+    )?;
+    let (backup_path, checkpoint_path) = (
+        backup_file.to_string_lossy().into_owned(),
+        checkpoint_file.to_string_lossy().into_owned(),
+    );
+    // raw disks can't carry the dirty bitmap a checkpoint needs, a full backup
+    // goes without one then
+    let started = if run_virsh(&["backup-begin", dom_name, &backup_path, &checkpoint_path]).is_ok()
+    {
+        Some(Some(checkpoint))
+    } else if base.is_none() && run_virsh(&["backup-begin", dom_name, &backup_path]).is_ok() {
+        Some(None)
+    } else {
+        None
+    };
+    let _ = fs::remove_file(&backup_file);
+    let _ = fs::remove_file(&checkpoint_file);
+    let Some(checkpoint) = started else {
+        return Ok(None);
+    };
+
+    let finish = || -> Result<(), io::Error> {
+        wait_for_job(dom_name)?;
+        // every increment points at its parent's image, so converting the newest
+        // one reads the whole chain
+        if let Some(base) = base {
+            for disk in disks {
+                run(Command::new("qemu-img")
+                    .args(["rebase", "-u", "-F", "qcow2", "-b"])
+                    .arg(base.dir.join(disk.file()))
+                    .arg(dir.join(disk.file())))?;
+            }
+        }
+        Ok(())
+    };
+    if let Err(e) = finish() {
+        if let Some(checkpoint) = &checkpoint {
+            let _ = drop_checkpoint(dom_name, checkpoint);
+        }
+        return Err(e);
+    }
+    Ok(Some((base.is_some(), checkpoint)))
+}
+
+// parks the guest's writes in temporary overlays, copies the images while nothing
+// writes to them and commits the overlays back
+fn copy_running(
+    dom_name: &str,
+    dir: &Path,
+    disks: &[BackupDisk],
+    skipped: &[String],
+) -> Result<(), io::Error> {
+    let name = format!("backup-{}", stamp());
+    let overlays: Vec<(String, PathBuf)> = disks
+        .iter()
+        .map(|disk| {
+            let file = format!("{}-{}-{}.qcow2", dom_name, name, disk.target);
+            (
+                disk.target.clone(),
+                Path::new(&disk.source).with_file_name(file),
+            )
+        })
+        .collect();
+    let specs: Vec<String> = overlays
+        .iter()
+        .map(|(target, overlay)| {
+            format!(
+                "{},snapshot=external,file={}",
+                target,
+                overlay.to_string_lossy().replace(',', ",,")
+            )
+        })
+        .chain(
+            skipped
+                .iter()
+                .map(|target| format!("{},snapshot=no", target)),
+        )
+        .collect();
+    let mut args = vec![
+        "snapshot-create-as",
+        dom_name,
+        "--name",
+        &name,
+        "--disk-only",
+        "--atomic",
+        "--no-metadata",
+    ];
+    for spec in &specs {
+        args.extend(["--diskspec", spec.as_str()]);
+    }
+    run_virsh(&args)?;
+    let copied = disks
+        .iter()
+        .try_for_each(|disk| convert(Path::new(&disk.source), &dir.join(disk.file()), "qcow2"));
+    // the guest goes back onto its images whether the copy worked or not, one
+    // disk failing to commit doesn't leave the others on their overlays
+    let mut failed = Vec::new();
+    for (target, overlay) in &overlays {
+        match run_virsh(&[
+            "blockcommit",
+            dom_name,
+            target,
+            "--active",
+            "--pivot",
+            "--wait",
+        ]) {
+            Ok(_) => {
+                let _ = fs::remove_file(overlay);
+            }
+            Err(e) => failed.push(format!("{}: {}", target, e)),
+        }
+    }
+    if !failed.is_empty() {
+        return Err(io::Error::other(format!(
+            "{} is still running on backup overlays, commit failed for {}",
+            dom_name,
+            failed.join("; ")
+        )));
+    }
+    copied
+}
+
+// checks one backup's files against the checksums the catalog keeps for it
+pub fn verify(dir: &Path, checksums: &BTreeMap<String, String>) -> Result<(), io::Error> {
+    for (file, checksum) in checksums {
+        let path = dir.join(file);
+        if sha256_file(&path)? != *checksum {
+            return Err(invalid(format!("checksum mismatch for {}", path.display())));
+        }
+    }
+    Ok(())
+}
+
+// defines a new domain `name` from the backup with its disks in the pool; on
+// failure nothing of it is left behind
+pub fn restore_new(
+    dir: &Path,
+    disks: &[BackupDisk],
+    dom_name: &str,
+    pool: &str,
+    pool_path: &Path,
+    name: &str,
+) -> Result<String, io::Error> {
+    let mut restored = Vec::new();
+    let res = (|| -> Result<String, io::Error> {
+        let mut sources = HashMap::new();
+        for disk in disks {
+            let dest = pool_path.join(format!("{}-{}.{}", name, disk.target, disk.format));
+            if dest.exists() {
+                return Err(invalid(format!("{} already exists", dest.display())));
+            }
+            convert(&dir.join(disk.file()), &dest, &disk.format)?;
+            restored.push(dest.clone());
+            sources.insert(disk.source.clone(), dest.to_string_lossy().into_owned());
+        }
+        // the original may still be around, its macs would clash
+        let xml = relocate_domain_xml(
+            &fs::read_to_string(dir.join(DOMAIN_FILE))?,
+            name,
+            None,
+            &sources,
+            name != dom_name,
+        )
+        .map_err(invalid)?;
+        let xml_path = env::temp_dir().join(format!("restore-{}-{}.xml", name, stamp()));
+        fs::write(&xml_path, xml)?;
+        let defined = run_virsh(&["define", &xml_path.to_string_lossy()]);
+        let _ = fs::remove_file(&xml_path);
+        defined?;
+        Ok(format!("Restored {} as new domain {}", dom_name, name))
+    })();
+    match &res {
+        Ok(_) => {
+            let _ = run_virsh(&["pool-refresh", pool]);
+        }
+        Err(_) => {
+            for disk in restored {
+                let _ = fs::remove_file(disk);
+            }
+        }
+    }
+    res
+}
+
+// writes the backup over the disks of an existing, shut off domain. its images
+// are replaced as a whole, so snapshots or overlays on them would be lost and
+// such a domain is refused
+pub fn restore_existing(
+    dir: &Path,
+    disks: &[BackupDisk],
+    dom_name: &str,
+) -> Result<String, io::Error> {
+    if !is_shut_off(dom_name)? {
+        return Err(invalid(format!(
+            "Domain {} must be shut off to restore into it",
+            dom_name
+        )));
+    }
+    if !run_virsh(&["snapshot-list", dom_name, "--name"])?
+        .trim()
+        .is_empty()
+    {
+        return Err(invalid(format!(
+            "Domain {} has snapshots, delete them before restoring over its disks",
+            dom_name
+        )));
+    }
+    let (current, _) = domain_disks(dom_name)?;
+    let mut staged = Vec::new();
+    let res = (|| -> Result<(), io::Error> {
+        for disk in disks {
+            let existing = current
+                .iter()
+                .find(|it| it.target == disk.target)
+                .ok_or(invalid(format!("{} has no disk {}", dom_name, disk.target)))?;
+            if let Some(backing) = image_info(&existing.source)?["backing-filename"].as_str() {
+                return Err(invalid(format!(
+                    "disk {} has backing file {}, flatten it before restoring",
+                    disk.target, backing
+                )));
+            }
+            let temp = PathBuf::from(format!("{}.restore", existing.source));
+            convert(&dir.join(disk.file()), &temp, &existing.format)?;
+            staged.push((temp, PathBuf::from(&existing.source)));
+        }
+        Ok(())
+    })();
+    if let Err(e) = res {
+        for (temp, _) in staged {
+            let _ = fs::remove_file(temp);
+        }
+        return Err(e);
+    }
+    // every disk converted, only now are the old images replaced
+    for (temp, source) in &staged {
+        fs::rename(temp, source)?;
+    }
+    Ok(format!("Restored {} disks of {}", staged.len(), dom_name))
+}
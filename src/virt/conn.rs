@@ -1,29 +1,73 @@
 use roxmltree::Document;
-use std::env;
-use std::fs::File;
-use std::io::Write;
-use std::process::Command;
+use serde_json::{json, Value};
 use std::{collections::HashMap, sync::mpsc::Sender};
-use virt::{connect::Connect, domain::Domain, domain_snapshot::DomainSnapshot};
+use virt::{
+    connect::Connect,
+    domain::Domain,
+    sys::{VIR_CONNECT_LIST_DOMAINS_PERSISTENT, VIR_DOMAIN_AFFECT_CONFIG, VIR_DOMAIN_AFFECT_LIVE},
+};
 
-use super::utils::{edit_xml_text, sha256_hash};
+use super::interface::domain_interfaces;
+use super::metadata::{domain_metadata, matches_selectors};
 
 use super::VirtError::{self, *};
-use super::{SnapShotEditConfig, VirtResult};
+use super::VirtResult;
 
-pub fn list_all(conn: &Connect, main_tx: &Sender<VirtResult>) {
+pub fn lookup_domain(conn: &Connect, dom_name: &str) -> Result<Domain, VirtError> {
+    Domain::lookup_by_name(conn, dom_name).map_err(|_| DomainNotFound(dom_name.to_string()))
+}
+
+// device changes go to the persistent config, and to the running domain as well when it's up
+pub fn modify_flags(dom: &Domain) -> Result<u32, VirtError> {
+    if dom.is_active()? {
+        Ok(VIR_DOMAIN_AFFECT_CONFIG | VIR_DOMAIN_AFFECT_LIVE)
+    } else {
+        Ok(VIR_DOMAIN_AFFECT_CONFIG)
+    }
+}
+
+// params are label selectors, only domains matching all of them are listed
+pub fn list_all(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let persistent = match conn.list_all_domains(VIR_CONNECT_LIST_DOMAINS_PERSISTENT) {
+        Ok(doms) => doms
+            .iter()
+            .filter_map(|dom| dom.get_uuid_string().ok())
+            .collect::<Vec<String>>(),
+        Err(e) => return main_tx.send(VirtResult::Err(VirtInternalError(e))).unwrap(),
+    };
     match conn.list_all_domains(0) {
         Ok(doms) => {
-            let t: Vec<HashMap<&str, String>> = doms
+            let t: Vec<HashMap<&str, Value>> = doms
                 .into_iter()
-                .map(|dom| {
+                .filter_map(|dom| {
+                    // unreadable metadata lists the domain without labels rather than hiding it
+                    let metadata = domain_metadata(&dom).unwrap_or_default();
+                    if !matches_selectors(&metadata.labels, params) {
+                        return None;
+                    }
                     let mut map = HashMap::new();
-                    map.insert("name", dom.get_name().expect("Domain must have name!"));
+                    map.insert(
+                        "name",
+                        json!(dom.get_name().expect("Domain must have name!")),
+                    );
                     let dom_info = dom.get_info().expect("Domain must have Info!");
-                    map.insert("vcpu", dom_info.nr_virt_cpu.to_string());
-                    map.insert("memory", dom_info.memory.to_string());
-                    map.insert("state", dom_info.state.to_string());
-                    map
+                    map.insert("vcpu", json!(dom_info.nr_virt_cpu.to_string()));
+                    map.insert("memory", json!(dom_info.memory.to_string()));
+                    map.insert("state", json!(dom_info.state.to_string()));
+                    map.insert(
+                        "interfaces",
+                        json!(domain_interfaces(&dom).unwrap_or_default()),
+                    );
+                    map.insert("description", json!(metadata.description));
+                    map.insert("labels", json!(metadata.labels));
+                    map.insert("autostart", json!(dom.get_autostart().unwrap_or(false)));
+                    map.insert(
+                        "persistent",
+                        json!(dom
+                            .get_uuid_string()
+                            .is_ok_and(|uuid| persistent.contains(&uuid))),
+                    );
+                    Some(map)
                 })
                 .collect();
             main_tx
@@ -34,7 +78,7 @@ pub fn list_all(conn: &Connect, main_tx: &Sender<VirtResult>) {
     }
 }
 
-pub fn list_snapshot(conn: &Connect, main_tx: &Sender<VirtResult>, params: &Vec<String>) {
+pub fn list_snapshot(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
     // create json_obj like
     // {
     //     "domain_name":[
@@ -57,7 +101,7 @@ pub fn list_snapshot(conn: &Connect, main_tx: &Sender<VirtResult>, params: &Vec<
     let mut t: HashMap<&String, Vec<HashMap<&str, String>>> = HashMap::new();
 
     let res = params
-        .into_iter()
+        .iter()
         .try_for_each(|dom_name| -> Result<(), VirtError> {
             match Domain::lookup_by_name(conn, dom_name) {
                 Err(_) => Err(DomainNotFound(dom_name.clone())),
@@ -98,74 +142,19 @@ pub fn list_snapshot(conn: &Connect, main_tx: &Sender<VirtResult>, params: &Vec<
     };
 }
 
-pub fn list_snapshot_tree(conn: &Connect, main_tx: &Sender<VirtResult>, params: &Vec<String>) {
-    let dom_name = &params[0];
-    match Domain::lookup_by_name(conn, dom_name) {
-        Ok(dom) => {
-            let snapshots = dom.list_all_snapshots(0).unwrap();
-            let snapshots: HashMap<String, Vec<String>> = snapshots
-                .iter()
-                .map(|it| {
-                    let childs: Vec<String> = it
-                        .list_all_children(0)
-                        .unwrap()
-                        .iter()
-                        .map(|child| child.get_name().unwrap())
-                        .collect();
-                    (it.get_name().unwrap(), childs)
-                })
-                .collect();
-            main_tx
-                .send(VirtResult::Ok(serde_json::to_string(&snapshots).unwrap()))
-                .unwrap();
-        }
-        Err(_) => main_tx
-            .send(VirtResult::Err(VirtError::DomainNotFound(dom_name.clone())))
-            .unwrap(),
-    }
-}
-
-pub fn edit_snapshot(conn: &Connect, main_tx: &Sender<VirtResult>, params: &Vec<String>) {
-    match serde_json::from_str::<SnapShotEditConfig>(&params[0]) {
-        Ok(config) => {
-            match Domain::lookup_by_name(conn, &config.dom_name) {
-                Ok(dom) => match DomainSnapshot::lookup_by_name(&dom, &config.snapshot_name, 0) {
-                    Ok(snapshot) => {
-                        let xml_str = snapshot.get_xml_desc(0).unwrap();
-                        let mut new_xml = xml_str;
-                        new_xml = edit_xml_text(&new_xml, "description", &config.description, 1);
-                        // println!("{}", new_xml);
-                        let tmp_dir = env::temp_dir();
-                        let path = tmp_dir.join(sha256_hash(&new_xml) + ".xml");
-                        let mut file = File::create(path.clone()).unwrap();
-                        write!(file, "{}", new_xml).unwrap();
-                        drop(file);
-                        let mut cmd = Command::new("virsh");
-                        cmd.arg("snapshot-create")
-                            .arg(&config.dom_name)
-                            .arg(path.to_str().unwrap())
-                            .arg("--redefine");
-                        let _ = cmd.spawn().unwrap().wait();
-                        main_tx
-                            .send(VirtResult::Ok("Edit snapshot successfully".to_string()))
-                            .unwrap();
-                    }
-                    Err(_) => main_tx
-                        .send(VirtResult::Err(VirtError::SnapShotNotFound {
-                            dom_name: config.dom_name.to_string(),
-                            snapshot_name: config.snapshot_name.to_string(),
-                        }))
-                        .unwrap(),
-                },
-                Err(_) => main_tx
-                    .send(VirtResult::Err(VirtError::DomainNotFound(
-                        config.dom_name.to_string(),
-                    )))
-                    .unwrap(),
-            }
+// snapshot count of every domain
+pub fn count_snapshots(conn: &Connect, main_tx: &Sender<VirtResult>) {
+    let res = || -> Result<HashMap<String, usize>, VirtError> {
+        let mut counts = HashMap::new();
+        for dom in conn.list_all_domains(0)? {
+            counts.insert(dom.get_name()?, dom.list_all_snapshots(0)?.len());
         }
-        Err(_) => main_tx
-            .send(VirtResult::Err(VirtError::InvalidInput))
+        Ok(counts)
+    };
+    match res() {
+        Ok(t) => main_tx
+            .send(VirtResult::Ok(serde_json::to_string(&t).unwrap()))
             .unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
     }
 }
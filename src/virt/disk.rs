@@ -0,0 +1,310 @@
+use quick_xml::escape::escape;
+use roxmltree::{Document, Node};
+use serde::Serialize;
+use std::process::Command;
+use std::sync::mpsc::Sender;
+use virt::{connect::Connect, sys::VIR_DOMAIN_BLOCK_RESIZE_BYTES};
+
+use super::conn::{lookup_domain, modify_flags};
+use super::shell::image_info;
+use super::utils::parse_size;
+
+use super::VirtError::{self, *};
+use super::{DiskConfig, VirtResult};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DiskInfo {
+    target: String,
+    device: String,
+    bus: String,
+    source: String,
+    format: String,
+    capacity: u64,
+    allocation: u64,
+    is_boot: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ChainImage {
+    path: String,
+    format: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BackingChain {
+    target: String,
+    // the active image first, its backing file next and so on down to the base
+    images: Vec<ChainImage>,
+}
+
+// guards against images that end up backing themselves
+pub(super) const MAX_CHAIN_DEPTH: usize = 64;
+
+fn parse_config(params: &[String]) -> Result<DiskConfig, VirtError> {
+    serde_json::from_str::<DiskConfig>(&params[0]).map_err(|_| InvalidInput)
+}
+
+fn disk_target(disk: &Node) -> String {
+    disk.children()
+        .find(|it| it.has_tag_name("target"))
+        .and_then(|it| it.attribute("dev"))
+        .unwrap_or("")
+        .to_string()
+}
+
+fn disk_source(disk: &Node) -> String {
+    disk.children()
+        .find(|it| it.has_tag_name("source"))
+        .and_then(|it| it.attribute("file").or(it.attribute("dev")))
+        .unwrap_or("")
+        .to_string()
+}
+
+// a disk with <boot order='1'/>, or the first hard disk when no device sets an order
+fn is_boot_disk(doc: &Document, disk: &Node) -> bool {
+    let has_device_order = doc
+        .descendants()
+        .any(|it| it.has_tag_name("boot") && it.attribute("order").is_some());
+    if has_device_order {
+        return disk
+            .children()
+            .any(|it| it.has_tag_name("boot") && it.attribute("order") == Some("1"));
+    }
+    doc.descendants()
+        .find(|it| it.has_tag_name("disk") && it.attribute("device") == Some("disk"))
+        .is_some_and(|first| first == *disk)
+}
+
+fn find_disk<'a, 'input>(doc: &'a Document<'input>, target: &str) -> Option<Node<'a, 'input>> {
+    doc.descendants()
+        .find(|it| it.has_tag_name("disk") && disk_target(it) == target)
+}
+
+pub fn list_disks(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<Vec<DiskInfo>, VirtError> {
+        let dom = lookup_domain(conn, &params[0])?;
+        let xml = dom.get_xml_desc(0)?;
+        let doc = Document::parse(&xml).expect("XML from LibVirt can't be parsed");
+        let disks = doc
+            .descendants()
+            .filter(|it| it.has_tag_name("disk"))
+            .map(|disk| {
+                let target = disk_target(&disk);
+                // cdroms without media have no block info
+                let (capacity, allocation) = match dom.get_block_info(&target, 0) {
+                    Ok(info) => (info.capacity, info.allocation),
+                    Err(_) => (0, 0),
+                };
+                DiskInfo {
+                    device: disk.attribute("device").unwrap_or("disk").to_string(),
+                    bus: disk
+                        .children()
+                        .find(|it| it.has_tag_name("target"))
+                        .and_then(|it| it.attribute("bus"))
+                        .unwrap_or("")
+                        .to_string(),
+                    source: disk_source(&disk),
+                    format: disk
+                        .children()
+                        .find(|it| it.has_tag_name("driver"))
+                        .and_then(|it| it.attribute("type"))
+                        .unwrap_or("")
+                        .to_string(),
+                    is_boot: is_boot_disk(&doc, &disk),
+                    target,
+                    capacity,
+                    allocation,
+                }
+            })
+            .collect();
+        Ok(disks)
+    };
+    match res() {
+        Ok(t) => main_tx
+            .send(VirtResult::Ok(serde_json::to_string(&t).unwrap()))
+            .unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn list_backing_chains(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<Vec<BackingChain>, VirtError> {
+        let dom = lookup_domain(conn, &params[0])?;
+        let xml = dom.get_xml_desc(0)?;
+        let doc = Document::parse(&xml).expect("XML from LibVirt can't be parsed");
+        let chains = doc
+            .descendants()
+            .filter(|it| it.has_tag_name("disk") && it.attribute("device") == Some("disk"))
+            .map(|disk| {
+                let mut images = Vec::new();
+                // the active image is on <disk> itself, every <backingStore> nests the
+                // next one down and an empty one ends the chain
+                let mut layer = Some(disk);
+                while let Some(node) = layer {
+                    let path = disk_source(&node);
+                    if path.is_empty() {
+                        break;
+                    }
+                    let format = node
+                        .children()
+                        .find(|it| it.has_tag_name("driver") || it.has_tag_name("format"))
+                        .and_then(|it| it.attribute("type"))
+                        .unwrap_or("")
+                        .to_string();
+                    images.push(ChainImage { path, format });
+                    layer = node.children().find(|it| it.has_tag_name("backingStore"));
+                }
+                // inactive domains usually leave the chain out, the images know it
+                if layer.is_none() {
+                    while let Some(info) = images
+                        .last()
+                        .filter(|_| images.len() < MAX_CHAIN_DEPTH)
+                        .and_then(|it| image_info(&it.path).ok())
+                    {
+                        let Some(path) = info["full-backing-filename"].as_str() else {
+                            break;
+                        };
+                        images.push(ChainImage {
+                            path: path.to_string(),
+                            format: info["backing-filename-format"]
+                                .as_str()
+                                .unwrap_or("")
+                                .to_string(),
+                        });
+                    }
+                }
+                BackingChain {
+                    target: disk_target(&disk),
+                    images,
+                }
+            })
+            .collect();
+        Ok(chains)
+    };
+    match res() {
+        Ok(t) => main_tx
+            .send(VirtResult::Ok(serde_json::to_string(&t).unwrap()))
+            .unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn attach_disk(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<String, VirtError> {
+        let config = parse_config(params)?;
+        let path = config
+            .path
+            .ok_or(InvalidConfig("path is required".to_string()))?;
+        let format = config.format.unwrap_or("qcow2".to_string());
+        if format != "qcow2" && format != "raw" {
+            return Err(InvalidConfig(format!("unsupported disk format {}", format)));
+        }
+        let dom = lookup_domain(conn, &config.dom_name)?;
+        let xml = dom.get_xml_desc(0)?;
+        let doc = Document::parse(&xml).expect("XML from LibVirt can't be parsed");
+        let used: Vec<String> = doc
+            .descendants()
+            .filter(|it| it.has_tag_name("disk"))
+            .map(|it| disk_target(&it))
+            .collect();
+        let target = match config.target {
+            Some(target) => {
+                if used.contains(&target) {
+                    return Err(InvalidConfig(format!("target {} already in use", target)));
+                }
+                target
+            }
+            None => ('b'..='z')
+                .map(|c| format!("vd{}", c))
+                .find(|it| !used.contains(it))
+                .ok_or(InvalidConfig("no free virtio target left".to_string()))?,
+        };
+        let disk_xml = format!(
+            "<disk type='file' device='disk'><driver name='qemu' type='{}'/><source file='{}'/><target dev='{}' bus='virtio'/></disk>",
+            format,
+            escape(&path),
+            escape(&target)
+        );
+        dom.attach_device_flags(&disk_xml, modify_flags(&dom)?)?;
+        Ok(target)
+    };
+    match res() {
+        Ok(target) => main_tx.send(VirtResult::Ok(target)).unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn detach_disk(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<String, VirtError> {
+        let config = parse_config(params)?;
+        let target = config
+            .target
+            .ok_or(InvalidConfig("target is required".to_string()))?;
+        let dom = lookup_domain(conn, &config.dom_name)?;
+        let xml = dom.get_xml_desc(0)?;
+        let doc = Document::parse(&xml).expect("XML from LibVirt can't be parsed");
+        let disk = find_disk(&doc, &target)
+            .ok_or(InvalidConfig(format!("no disk with target {}", target)))?;
+        if is_boot_disk(&doc, &disk) && !config.force.unwrap_or(false) {
+            return Err(InvalidConfig(format!(
+                "{} is the boot disk, set force to detach it",
+                target
+            )));
+        }
+        dom.detach_device_flags(&xml[disk.range()], modify_flags(&dom)?)?;
+        Ok("Detach disk successfully".to_string())
+    };
+    match res() {
+        Ok(output) => main_tx.send(VirtResult::Ok(output)).unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn resize_disk(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<String, VirtError> {
+        let config = parse_config(params)?;
+        let target = config
+            .target
+            .ok_or(InvalidConfig("target is required".to_string()))?;
+        let size = config
+            .size
+            .as_deref()
+            .and_then(parse_size)
+            .ok_or(InvalidConfig("size is missing or invalid".to_string()))?;
+        let dom = lookup_domain(conn, &config.dom_name)?;
+        let info = dom.get_block_info(&target, 0)?;
+        if size < info.capacity {
+            return Err(InvalidConfig(format!(
+                "shrinking {} from {} to {} bytes is not supported",
+                target, info.capacity, size
+            )));
+        }
+        if dom.is_active()? {
+            dom.block_resize(&target, size, VIR_DOMAIN_BLOCK_RESIZE_BYTES)?;
+        } else {
+            let xml = dom.get_xml_desc(0)?;
+            let doc = Document::parse(&xml).expect("XML from LibVirt can't be parsed");
+            let disk = find_disk(&doc, &target)
+                .ok_or(InvalidConfig(format!("no disk with target {}", target)))?;
+            let output = Command::new("qemu-img")
+                .arg("resize")
+                .arg(disk_source(&disk))
+                .arg(size.to_string())
+                .output()
+                .map_err(|e| OtherError(e.to_string()))?;
+            if !output.status.success() {
+                return Err(OtherError(
+                    String::from_utf8(output.stderr).unwrap().trim().to_string(),
+                ));
+            }
+        }
+        Ok("Resize disk successfully".to_string())
+    };
+    match res() {
+        Ok(output) => main_tx.send(VirtResult::Ok(output)).unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
@@ -0,0 +1,166 @@
+use roxmltree::Document;
+use serde::Serialize;
+use std::sync::mpsc::Sender;
+use sysinfo::System;
+use virt::{
+    connect::Connect,
+    domain::Domain,
+    sys::{
+        VIR_DOMAIN_AFFECT_CONFIG, VIR_DOMAIN_AFFECT_LIVE, VIR_DOMAIN_MEM_MAXIMUM,
+        VIR_DOMAIN_VCPU_MAXIMUM, VIR_DOMAIN_XML_INACTIVE,
+    },
+};
+
+use super::utils::replace_boot_devices;
+
+use super::VirtError::{self, *};
+use super::{DomHardwareConfig, VirtResult};
+
+const BOOT_DEVICES: [&str; 4] = ["hd", "cdrom", "network", "fd"];
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct EditHardwareResult {
+    restart_required: bool,
+    // settings already active in the running domain
+    applied_live: Vec<&'static str>,
+    // settings only written to the persistent config
+    pending_restart: Vec<&'static str>,
+}
+
+pub fn edit_hardware(
+    conn: &Connect,
+    main_tx: &Sender<VirtResult>,
+    params: &[String],
+    sys: &mut System,
+) {
+    let res = match serde_json::from_str::<DomHardwareConfig>(&params[0]) {
+        Ok(config) => validate_hardware_config(&config, sys)
+            .and_then(|_| apply_hardware_config(conn, &config)),
+        Err(_) => Err(InvalidInput),
+    };
+    match res {
+        Ok(t) => main_tx
+            .send(VirtResult::Ok(serde_json::to_string(&t).unwrap()))
+            .unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+fn validate_hardware_config(config: &DomHardwareConfig, sys: &mut System) -> Result<(), VirtError> {
+    sys.refresh_cpu();
+    sys.refresh_memory();
+    if let Some(vcpu) = config.vcpu {
+        let host_cpus = sys.cpus().len() as u32;
+        if vcpu == 0 || vcpu > host_cpus {
+            return Err(InvalidConfig(format!(
+                "vcpu must be between 1 and {} (host cpus)",
+                host_cpus
+            )));
+        }
+    }
+    if let Some(memory) = config.memory {
+        let host_memory = sys.total_memory() / 1024;
+        if memory == 0 || memory > host_memory {
+            return Err(InvalidConfig(format!(
+                "memory must be between 1 and {} KiB (host memory)",
+                host_memory
+            )));
+        }
+    }
+    if let Some(boot_order) = &config.boot_order {
+        if boot_order.is_empty() {
+            return Err(InvalidConfig("boot_order can not be empty".to_string()));
+        }
+        for (i, dev) in boot_order.iter().enumerate() {
+            if !BOOT_DEVICES.contains(&dev.as_str()) {
+                return Err(InvalidConfig(format!(
+                    "unknown boot device {}, expected one of {:?}",
+                    dev, BOOT_DEVICES
+                )));
+            }
+            if boot_order[..i].contains(dev) {
+                return Err(InvalidConfig(format!("boot device {} listed twice", dev)));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn apply_hardware_config(
+    conn: &Connect,
+    config: &DomHardwareConfig,
+) -> Result<EditHardwareResult, VirtError> {
+    let dom = Domain::lookup_by_name(conn, &config.dom_name)
+        .map_err(|_| DomainNotFound(config.dom_name.clone()))?;
+    let is_active = dom.is_active()?;
+    let apply_live = is_active && config.live.unwrap_or(true);
+    let mut result = EditHardwareResult::default();
+
+    // boot order only lives in the XML, redefine before the setters below so
+    // the inactive XML we edit doesn't overwrite their changes
+    if let Some(boot_order) = &config.boot_order {
+        let xml = dom.get_xml_desc(VIR_DOMAIN_XML_INACTIVE)?;
+        Domain::define_xml(conn, &replace_boot_devices(&xml, boot_order))?;
+        if is_active {
+            result.pending_restart.push("bootOrder");
+        }
+    }
+
+    if let Some(vcpu) = config.vcpu {
+        let config_max = dom.get_vcpus_flags(VIR_DOMAIN_AFFECT_CONFIG | VIR_DOMAIN_VCPU_MAXIMUM)?;
+        // the current count can never exceed the maximum, so order the calls
+        // depending on whether we grow or shrink
+        if vcpu > config_max {
+            dom.set_vcpus_flags(vcpu, VIR_DOMAIN_AFFECT_CONFIG | VIR_DOMAIN_VCPU_MAXIMUM)?;
+            dom.set_vcpus_flags(vcpu, VIR_DOMAIN_AFFECT_CONFIG)?;
+        } else {
+            dom.set_vcpus_flags(vcpu, VIR_DOMAIN_AFFECT_CONFIG)?;
+            dom.set_vcpus_flags(vcpu, VIR_DOMAIN_AFFECT_CONFIG | VIR_DOMAIN_VCPU_MAXIMUM)?;
+        }
+        if is_active {
+            let live_max = dom.get_vcpus_flags(VIR_DOMAIN_AFFECT_LIVE | VIR_DOMAIN_VCPU_MAXIMUM)?;
+            if apply_live
+                && vcpu <= live_max
+                && dom.set_vcpus_flags(vcpu, VIR_DOMAIN_AFFECT_LIVE).is_ok()
+            {
+                result.applied_live.push("vcpu");
+            } else {
+                result.pending_restart.push("vcpu");
+            }
+        }
+    }
+
+    if let Some(memory) = config.memory {
+        let xml = dom.get_xml_desc(VIR_DOMAIN_XML_INACTIVE)?;
+        let info = Document::parse(&xml).expect("XML from LibVirt can't be parsed");
+        let config_max = info
+            .root_element()
+            .children()
+            .find(|it| it.has_tag_name("memory"))
+            .and_then(|it| it.text())
+            .and_then(|it| it.parse::<u64>().ok())
+            .unwrap_or(0);
+        if memory > config_max {
+            dom.set_memory_flags(memory, VIR_DOMAIN_AFFECT_CONFIG | VIR_DOMAIN_MEM_MAXIMUM)?;
+            dom.set_memory_flags(memory, VIR_DOMAIN_AFFECT_CONFIG)?;
+        } else {
+            dom.set_memory_flags(memory, VIR_DOMAIN_AFFECT_CONFIG)?;
+            dom.set_memory_flags(memory, VIR_DOMAIN_AFFECT_CONFIG | VIR_DOMAIN_MEM_MAXIMUM)?;
+        }
+        if is_active {
+            // only the balloon can change at runtime, the maximum is fixed until restart
+            if apply_live
+                && memory <= dom.get_max_memory()?
+                && dom.set_memory_flags(memory, VIR_DOMAIN_AFFECT_LIVE).is_ok()
+            {
+                result.applied_live.push("memory");
+            } else {
+                result.pending_restart.push("memory");
+            }
+        }
+    }
+
+    result.restart_required = !result.pending_restart.is_empty();
+    Ok(result)
+}
@@ -0,0 +1,197 @@
+use quick_xml::escape::escape;
+use roxmltree::{Document, Node};
+use serde::Serialize;
+use std::sync::mpsc::Sender;
+use virt::{
+    connect::Connect,
+    domain::Domain,
+    sys::{VIR_DOMAIN_INTERFACE_ADDRESSES_SRC_AGENT, VIR_DOMAIN_INTERFACE_ADDRESSES_SRC_LEASE},
+};
+
+use super::conn::{lookup_domain, modify_flags};
+
+use super::VirtError::{self, *};
+use super::{InterfaceConfig, VirtResult};
+
+const NIC_MODELS: [&str; 2] = ["virtio", "e1000"];
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InterfaceInfo {
+    mac: String,
+    // network or bridge
+    source_type: String,
+    source: String,
+    model: String,
+    // host side tap device, only set while the domain is running
+    target: String,
+    addresses: Vec<String>,
+}
+
+fn child_attr(node: &Node, tag_name: &str, attr: &str) -> String {
+    node.children()
+        .find(|it| it.has_tag_name(tag_name))
+        .and_then(|it| it.attribute(attr))
+        .unwrap_or("")
+        .to_string()
+}
+
+fn find_interface<'a, 'input>(doc: &'a Document<'input>, mac: &str) -> Option<Node<'a, 'input>> {
+    doc.descendants().find(|it| {
+        it.has_tag_name("interface") && child_attr(it, "mac", "address").eq_ignore_ascii_case(mac)
+    })
+}
+
+fn is_valid_mac(mac: &str) -> bool {
+    let parts: Vec<&str> = mac.split(':').collect();
+    parts.len() == 6
+        && parts
+            .iter()
+            .all(|it| it.len() == 2 && it.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+pub fn domain_interfaces(dom: &Domain) -> Result<Vec<InterfaceInfo>, VirtError> {
+    let xml = dom.get_xml_desc(0)?;
+    let doc = Document::parse(&xml).expect("XML from LibVirt can't be parsed");
+    let mut ifaces: Vec<InterfaceInfo> = doc
+        .descendants()
+        .filter(|it| it.has_tag_name("interface"))
+        .map(|it| {
+            let source_type = it.attribute("type").unwrap_or("").to_string();
+            InterfaceInfo {
+                mac: child_attr(&it, "mac", "address"),
+                source: child_attr(&it, "source", &source_type),
+                source_type,
+                model: child_attr(&it, "model", "type"),
+                target: child_attr(&it, "target", "dev"),
+                addresses: Vec::new(),
+            }
+        })
+        .collect();
+    if dom.is_active()? {
+        // leases only cover libvirt managed networks, the guest agent also sees
+        // bridged nics; either source may be unavailable so failures are skipped
+        for source in [
+            VIR_DOMAIN_INTERFACE_ADDRESSES_SRC_LEASE,
+            VIR_DOMAIN_INTERFACE_ADDRESSES_SRC_AGENT,
+        ] {
+            let Ok(reported) = dom.interface_addresses(source, 0) else {
+                continue;
+            };
+            for iface in reported {
+                let Some(info) = ifaces
+                    .iter_mut()
+                    .find(|it| it.mac.eq_ignore_ascii_case(&iface.hwaddr))
+                else {
+                    continue;
+                };
+                for addr in iface.addrs {
+                    let addr = format!("{}/{}", addr.addr, addr.prefix);
+                    if !info.addresses.contains(&addr) {
+                        info.addresses.push(addr);
+                    }
+                }
+            }
+        }
+    }
+    Ok(ifaces)
+}
+
+pub fn list_interfaces(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    match lookup_domain(conn, &params[0]).and_then(|dom| domain_interfaces(&dom)) {
+        Ok(t) => main_tx
+            .send(VirtResult::Ok(serde_json::to_string(&t).unwrap()))
+            .unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn attach_interface(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<String, VirtError> {
+        let config =
+            serde_json::from_str::<InterfaceConfig>(&params[0]).map_err(|_| InvalidInput)?;
+        let source_type = config.source_type.unwrap_or("network".to_string());
+        if source_type != "network" && source_type != "bridge" {
+            return Err(InvalidConfig(format!(
+                "unknown source type {}, expected network or bridge",
+                source_type
+            )));
+        }
+        let source = config
+            .source
+            .ok_or(InvalidConfig("source is required".to_string()))?;
+        let model = config.model.unwrap_or("virtio".to_string());
+        if !NIC_MODELS.contains(&model.as_str()) {
+            return Err(InvalidConfig(format!(
+                "unknown nic model {}, expected one of {:?}",
+                model, NIC_MODELS
+            )));
+        }
+        let dom = lookup_domain(conn, &config.dom_name)?;
+        let macs = |dom: &Domain| -> Result<Vec<String>, VirtError> {
+            let xml = dom.get_xml_desc(0)?;
+            let doc = Document::parse(&xml).expect("XML from LibVirt can't be parsed");
+            Ok(doc
+                .descendants()
+                .filter(|it| it.has_tag_name("interface"))
+                .map(|it| child_attr(&it, "mac", "address").to_ascii_lowercase())
+                .collect())
+        };
+        let before = macs(&dom)?;
+        let mac = match &config.mac {
+            Some(mac) => {
+                if !is_valid_mac(mac) {
+                    return Err(InvalidConfig(format!("invalid mac address {}", mac)));
+                }
+                if before.contains(&mac.to_ascii_lowercase()) {
+                    return Err(InvalidConfig(format!("mac address {} already in use", mac)));
+                }
+                format!("<mac address='{}'/>", mac)
+            }
+            None => "".to_string(),
+        };
+        let iface_xml = format!(
+            "<interface type='{}'>{}<source {}='{}'/><model type='{}'/></interface>",
+            source_type,
+            mac,
+            source_type,
+            escape(&source),
+            model
+        );
+        dom.attach_device_flags(&iface_xml, modify_flags(&dom)?)?;
+        if let Some(mac) = config.mac {
+            return Ok(mac);
+        }
+        // libvirt doesn't always append the new nic, the mac it generated is the
+        // one that wasn't there before
+        Ok(macs(&dom)?
+            .into_iter()
+            .find(|it| !before.contains(it))
+            .unwrap_or_default())
+    };
+    match res() {
+        Ok(mac) => main_tx.send(VirtResult::Ok(mac)).unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn detach_interface(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<String, VirtError> {
+        let config =
+            serde_json::from_str::<InterfaceConfig>(&params[0]).map_err(|_| InvalidInput)?;
+        let mac = config
+            .mac
+            .ok_or(InvalidConfig("mac is required".to_string()))?;
+        let dom = lookup_domain(conn, &config.dom_name)?;
+        let xml = dom.get_xml_desc(0)?;
+        let doc = Document::parse(&xml).expect("XML from LibVirt can't be parsed");
+        let iface = find_interface(&doc, &mac)
+            .ok_or(InvalidConfig(format!("no interface with mac {}", mac)))?;
+        dom.detach_device_flags(&xml[iface.range()], modify_flags(&dom)?)?;
+        Ok("Detach interface successfully".to_string())
+    };
+    match res() {
+        Ok(output) => main_tx.send(VirtResult::Ok(output)).unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+use std::{env, fs, io, path::PathBuf};
+
+// multi-step snapshot workflows write a record here before touching the domain,
+// so a crash halfway leaves enough behind for the startup recovery pass
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OperationStep {
+    // the temp snapshot may or may not exist yet, the domain is untouched
+    CreateTemp,
+    // the domain may sit on another snapshot, the temp one holds its real state
+    Run,
+    // going back to the temp snapshot and removing it
+    Restore,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Operation {
+    pub dom_name: String,
+    pub kind: String,
+    pub temp_snapshot: String,
+    pub step: OperationStep,
+    pub started_at: i64,
+}
+
+fn journal_dir() -> PathBuf {
+    PathBuf::from(env::var("JOURNAL_DIR").unwrap_or("journal".to_string()))
+}
+
+// two domains may start an operation in the same millisecond
+fn record_path(op: &Operation) -> PathBuf {
+    journal_dir().join(format!("{}-{}.json", op.dom_name, op.temp_snapshot))
+}
+
+// written to a temp file and renamed, a crash never leaves half a record
+pub fn save(op: &Operation) -> Result<(), io::Error> {
+    fs::create_dir_all(journal_dir())?;
+    let path = record_path(op);
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec(op).unwrap())?;
+    fs::rename(tmp, path)
+}
+
+pub fn finish(op: &Operation) {
+    let _ = fs::remove_file(record_path(op));
+    // records written before they were keyed by domain as well
+    let _ = fs::remove_file(journal_dir().join(format!("{}.json", op.temp_snapshot)));
+}
+
+pub fn pending() -> Vec<Operation> {
+    let Ok(entries) = fs::read_dir(journal_dir()) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|it| it.ok())
+        .filter(|it| it.path().extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|it| serde_json::from_slice(&fs::read(it.path()).ok()?).ok())
+        .collect()
+}
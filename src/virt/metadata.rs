@@ -0,0 +1,143 @@
+use quick_xml::escape::escape;
+use roxmltree::Document;
+use serde::Serialize;
+use std::{collections::HashMap, sync::mpsc::Sender};
+use virt::{
+    connect::Connect,
+    domain::Domain,
+    sys::{VIR_DOMAIN_AFFECT_CONFIG, VIR_DOMAIN_METADATA_ELEMENT, VIR_DOMAIN_XML_INACTIVE},
+};
+
+use super::conn::{lookup_domain, modify_flags};
+use super::utils::edit_xml_text;
+
+use super::VirtError::{self, *};
+use super::{DomMetadataConfig, VirtResult};
+
+// labels live in the domain's own <metadata>, so they follow renames and go
+// away with the domain when it is undefined
+const LABELS_URI: &str = "https://github.com/yukkodesu/virt-backend/labels";
+const LABELS_PREFIX: &str = "vb";
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DomMetadata {
+    pub description: String,
+    pub labels: HashMap<String, String>,
+}
+
+pub fn domain_metadata(dom: &Domain) -> Result<DomMetadata, VirtError> {
+    let xml = dom.get_xml_desc(VIR_DOMAIN_XML_INACTIVE)?;
+    let doc = Document::parse(&xml).expect("XML from LibVirt can't be parsed");
+    let description = doc
+        .root_element()
+        .children()
+        .find(|it| it.has_tag_name("description"))
+        .and_then(|it| it.text())
+        .unwrap_or("")
+        .to_string();
+    // libvirt reports an error when the element was never set
+    let labels = match dom.get_metadata(
+        VIR_DOMAIN_METADATA_ELEMENT as i32,
+        LABELS_URI,
+        VIR_DOMAIN_AFFECT_CONFIG,
+    ) {
+        Ok(labels_xml) => {
+            let labels = Document::parse(&labels_xml).expect("XML from LibVirt can't be parsed");
+            labels
+                .descendants()
+                .filter(|it| it.has_tag_name("label"))
+                .filter_map(|it| {
+                    Some((
+                        it.attribute("key")?.to_string(),
+                        it.text().unwrap_or("").to_string(),
+                    ))
+                })
+                .collect()
+        }
+        Err(_) => HashMap::new(),
+    };
+    Ok(DomMetadata {
+        description,
+        labels,
+    })
+}
+
+// a selector is "key=value", or just "key" to match any value
+pub fn matches_selectors(labels: &HashMap<String, String>, selectors: &[String]) -> bool {
+    selectors
+        .iter()
+        .all(|selector| match selector.split_once('=') {
+            Some((key, value)) => labels.get(key).is_some_and(|it| it == value),
+            None => labels.contains_key(selector),
+        })
+}
+
+fn is_valid_label_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./".contains(c))
+}
+
+pub fn get_domain_metadata(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    match lookup_domain(conn, &params[0]).and_then(|dom| domain_metadata(&dom)) {
+        Ok(t) => main_tx
+            .send(VirtResult::Ok(serde_json::to_string(&t).unwrap()))
+            .unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn set_domain_metadata(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<String, VirtError> {
+        let config =
+            serde_json::from_str::<DomMetadataConfig>(&params[0]).map_err(|_| InvalidInput)?;
+        let dom = lookup_domain(conn, &config.dom_name)?;
+        if let Some(description) = &config.description {
+            let xml = dom.get_xml_desc(VIR_DOMAIN_XML_INACTIVE)?;
+            Domain::define_xml(conn, &edit_xml_text(&xml, "description", description, 1))?;
+        }
+        if let Some(labels) = &config.labels {
+            if let Some(key) = labels.keys().find(|it| !is_valid_label_key(it)) {
+                return Err(InvalidConfig(format!("invalid label key {}", key)));
+            }
+            let labels_xml = labels
+                .iter()
+                .map(|(key, value)| {
+                    format!("<label key='{}'>{}</label>", escape(key), escape(value))
+                })
+                .collect::<String>();
+            dom.set_metadata(
+                VIR_DOMAIN_METADATA_ELEMENT as i32,
+                &format!("<labels>{}</labels>", labels_xml),
+                LABELS_PREFIX,
+                LABELS_URI,
+                modify_flags(&dom)?,
+            )?;
+        }
+        Ok("Set metadata successfully".to_string())
+    };
+    match res() {
+        Ok(output) => main_tx.send(VirtResult::Ok(output)).unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn select_domains(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<Vec<String>, VirtError> {
+        let mut names = Vec::new();
+        for dom in conn.list_all_domains(0)? {
+            if matches_selectors(&domain_metadata(&dom)?.labels, params) {
+                names.push(dom.get_name()?);
+            }
+        }
+        Ok(names)
+    };
+    match res() {
+        Ok(t) => main_tx
+            .send(VirtResult::Ok(serde_json::to_string(&t).unwrap()))
+            .unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
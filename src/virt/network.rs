@@ -0,0 +1,199 @@
+use quick_xml::escape::escape;
+use roxmltree::Document;
+use serde::Serialize;
+use std::{net::Ipv4Addr, sync::mpsc::Sender};
+use virt::{connect::Connect, network::Network};
+
+use super::VirtError::{self, *};
+use super::{NetworkConfig, VirtResult};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NetworkInfo {
+    name: String,
+    bridge: String,
+    // nat, route, bridge... or isolated when there is no <forward>
+    forward_mode: String,
+    address: String,
+    netmask: String,
+    dhcp_start: String,
+    dhcp_end: String,
+    is_active: bool,
+    autostart: bool,
+}
+
+fn lookup_network(conn: &Connect, net_name: &str) -> Result<Network, VirtError> {
+    Network::lookup_by_name(conn, net_name).map_err(|_| NetworkNotFound(net_name.to_string()))
+}
+
+fn network_info(net: &Network) -> Result<NetworkInfo, VirtError> {
+    let xml = net.get_xml_desc(0)?;
+    let doc = Document::parse(&xml).expect("XML from LibVirt can't be parsed");
+    let attr_of = |tag_name: &str, attr: &str| -> String {
+        doc.descendants()
+            .find(|it| it.has_tag_name(tag_name))
+            .and_then(|it| it.attribute(attr))
+            .unwrap_or("")
+            .to_string()
+    };
+    let forward_mode = match doc.descendants().find(|it| it.has_tag_name("forward")) {
+        Some(forward) => forward.attribute("mode").unwrap_or("nat").to_string(),
+        None => "isolated".to_string(),
+    };
+    Ok(NetworkInfo {
+        name: net.get_name()?,
+        bridge: attr_of("bridge", "name"),
+        forward_mode,
+        address: attr_of("ip", "address"),
+        netmask: attr_of("ip", "netmask"),
+        dhcp_start: attr_of("range", "start"),
+        dhcp_end: attr_of("range", "end"),
+        is_active: net.is_active()?,
+        autostart: net.get_autostart()?,
+    })
+}
+
+// name of the first domain, running or not, with an interface on `net_name`
+fn network_used_by(conn: &Connect, net_name: &str) -> Result<Option<String>, VirtError> {
+    for dom in conn.list_all_domains(0)? {
+        let xml = dom.get_xml_desc(0)?;
+        let doc = Document::parse(&xml).expect("XML from LibVirt can't be parsed");
+        let in_use = doc
+            .descendants()
+            .filter(|it| it.has_tag_name("interface") && it.attribute("type") == Some("network"))
+            .flat_map(|it| it.children())
+            .any(|it| it.has_tag_name("source") && it.attribute("network") == Some(net_name));
+        if in_use {
+            return Ok(Some(dom.get_name()?));
+        }
+    }
+    Ok(None)
+}
+
+pub fn list_networks(conn: &Connect, main_tx: &Sender<VirtResult>) {
+    let res = || -> Result<Vec<NetworkInfo>, VirtError> {
+        conn.list_all_networks(0)?
+            .iter()
+            .map(network_info)
+            .collect()
+    };
+    match res() {
+        Ok(t) => main_tx
+            .send(VirtResult::Ok(serde_json::to_string(&t).unwrap()))
+            .unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+fn parse_ip(field: &str, value: &str) -> Result<Ipv4Addr, VirtError> {
+    value
+        .parse::<Ipv4Addr>()
+        .map_err(|_| InvalidConfig(format!("{} {} is not an ipv4 address", field, value)))
+}
+
+pub fn create_network(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<String, VirtError> {
+        let config = serde_json::from_str::<NetworkConfig>(&params[0]).map_err(|_| InvalidInput)?;
+        let forward = match config.mode.as_str() {
+            "nat" => "<forward mode='nat'/>",
+            "isolated" => "",
+            mode => {
+                return Err(InvalidConfig(format!(
+                    "unknown network mode {}, expected nat or isolated",
+                    mode
+                )))
+            }
+        };
+        if Network::lookup_by_name(conn, &config.name).is_ok() {
+            return Err(InvalidConfig(format!(
+                "network {} already exists",
+                config.name
+            )));
+        }
+        let address = parse_ip("address", &config.address)?;
+        let netmask = match &config.netmask {
+            Some(netmask) => parse_ip("netmask", netmask)?,
+            None => Ipv4Addr::new(255, 255, 255, 0),
+        };
+        let dhcp_start = config
+            .dhcp_start
+            .as_deref()
+            .map(|it| parse_ip("dhcp_start", it));
+        let dhcp_end = config
+            .dhcp_end
+            .as_deref()
+            .map(|it| parse_ip("dhcp_end", it));
+        let dhcp = match (dhcp_start.transpose()?, dhcp_end.transpose()?) {
+            (Some(start), Some(end)) => {
+                if start > end {
+                    return Err(InvalidConfig(
+                        "dhcp_start must not be after dhcp_end".to_string(),
+                    ));
+                }
+                format!("<dhcp><range start='{}' end='{}'/></dhcp>", start, end)
+            }
+            (None, None) => "".to_string(),
+            _ => {
+                return Err(InvalidConfig(
+                    "dhcp_start and dhcp_end must be set together".to_string(),
+                ))
+            }
+        };
+        // libvirt picks a free virbrN when the bridge has no name
+        let xml = format!(
+            "<network><name>{}</name>{}<bridge stp='on' delay='0'/><ip address='{}' netmask='{}'>{}</ip></network>",
+            escape(&config.name),
+            forward,
+            address,
+            netmask,
+            dhcp
+        );
+        let net = Network::define_xml(conn, &xml)?;
+        // a network that can't start isn't left defined
+        if let Err(e) = net.create() {
+            let _ = net.undefine();
+            return Err(e.into());
+        }
+        net.set_autostart(config.autostart.unwrap_or(true))?;
+        Ok("Create network successfully".to_string())
+    };
+    match res() {
+        Ok(output) => main_tx.send(VirtResult::Ok(output)).unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn destroy_network(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<String, VirtError> {
+        lookup_network(conn, &params[0])?.destroy()?;
+        Ok("Destroy network successfully".to_string())
+    };
+    match res() {
+        Ok(output) => main_tx.send(VirtResult::Ok(output)).unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn undefine_network(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<String, VirtError> {
+        let net = lookup_network(conn, &params[0])?;
+        if net.is_active()? {
+            return Err(InvalidConfig(format!(
+                "network {} is active, destroy it first",
+                params[0]
+            )));
+        }
+        if let Some(dom_name) = network_used_by(conn, &params[0])? {
+            return Err(InvalidConfig(format!(
+                "network {} is used by domain {}",
+                params[0], dom_name
+            )));
+        }
+        net.undefine()?;
+        Ok("Undefine network successfully".to_string())
+    };
+    match res() {
+        Ok(output) => main_tx.send(VirtResult::Ok(output)).unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
@@ -0,0 +1,246 @@
+use roxmltree::Document;
+use serde::Serialize;
+use std::{fs, sync::mpsc::Sender};
+use virt::{
+    connect::Connect,
+    domain::Domain,
+    storage_vol::StorageVol,
+    sys::{
+        VIR_DOMAIN_BLOCKED, VIR_DOMAIN_CRASHED, VIR_DOMAIN_NOSTATE, VIR_DOMAIN_PAUSED,
+        VIR_DOMAIN_PMSUSPENDED, VIR_DOMAIN_RUNNING, VIR_DOMAIN_SHUTDOWN, VIR_DOMAIN_SHUTOFF,
+        VIR_DOMAIN_UNDEFINE_KEEP_NVRAM, VIR_DOMAIN_UNDEFINE_MANAGED_SAVE,
+        VIR_DOMAIN_UNDEFINE_NVRAM, VIR_DOMAIN_UNDEFINE_SNAPSHOTS_METADATA, VIR_DOMAIN_XML_INACTIVE,
+    },
+};
+
+use super::conn::lookup_domain;
+use super::storage::volume_used_by;
+
+use super::VirtError::{self, *};
+use super::{
+    AltDomStateCommand, AutostartConfig, DeleteDomainConfig, DomStateResult, DomainAction,
+    RenameDomainConfig, VirtResult,
+};
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct DeleteDomainResult {
+    deleted_volumes: Vec<String>,
+    // volumes still used by another domain or that failed to delete
+    kept_volumes: Vec<String>,
+}
+
+pub fn state_name(state: u32) -> &'static str {
+    match state {
+        VIR_DOMAIN_NOSTATE => "nostate",
+        VIR_DOMAIN_RUNNING => "running",
+        VIR_DOMAIN_BLOCKED => "blocked",
+        VIR_DOMAIN_PAUSED => "paused",
+        VIR_DOMAIN_SHUTDOWN => "shutdown",
+        VIR_DOMAIN_SHUTOFF => "shutoff",
+        VIR_DOMAIN_CRASHED => "crashed",
+        VIR_DOMAIN_PMSUSPENDED => "pmsuspended",
+        _ => "unknown",
+    }
+}
+
+// states each action can be issued from
+fn allowed_states(action: DomainAction) -> &'static [u32] {
+    match action {
+        DomainAction::Start => &[VIR_DOMAIN_SHUTOFF, VIR_DOMAIN_CRASHED],
+        DomainAction::Shutdown | DomainAction::Reboot | DomainAction::Suspend => {
+            &[VIR_DOMAIN_RUNNING, VIR_DOMAIN_BLOCKED]
+        }
+        DomainAction::Reset => &[VIR_DOMAIN_RUNNING, VIR_DOMAIN_BLOCKED, VIR_DOMAIN_PAUSED],
+        DomainAction::Resume => &[VIR_DOMAIN_PAUSED],
+        DomainAction::ManagedSave => &[VIR_DOMAIN_RUNNING, VIR_DOMAIN_BLOCKED, VIR_DOMAIN_PAUSED],
+        DomainAction::Destroy => &[
+            VIR_DOMAIN_RUNNING,
+            VIR_DOMAIN_BLOCKED,
+            VIR_DOMAIN_PAUSED,
+            VIR_DOMAIN_SHUTDOWN,
+            VIR_DOMAIN_CRASHED,
+            VIR_DOMAIN_PMSUSPENDED,
+        ],
+    }
+}
+
+fn run_action(dom: &Domain, action: DomainAction) -> Result<(), VirtError> {
+    match action {
+        DomainAction::Start => dom.create().map(|_| ()),
+        DomainAction::Shutdown => dom.shutdown().map(|_| ()),
+        DomainAction::Reboot => dom.reboot(0),
+        DomainAction::Reset => dom.reset().map(|_| ()),
+        DomainAction::Suspend => dom.suspend().map(|_| ()),
+        DomainAction::Resume => dom.resume().map(|_| ()),
+        DomainAction::Destroy => dom.destroy(),
+        DomainAction::ManagedSave => dom.managed_save(0).map(|_| ()),
+    }?;
+    Ok(())
+}
+
+pub fn set_domain_state(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<DomStateResult, VirtError> {
+        let config =
+            serde_json::from_str::<AltDomStateCommand>(&params[0]).map_err(|_| InvalidInput)?;
+        let dom = lookup_domain(conn, &config.dom_name)?;
+        let (state, _) = dom.get_state()?;
+        if !allowed_states(config.state).contains(&state) {
+            return Err(InvalidState {
+                dom_name: config.dom_name,
+                state: state_name(state).to_string(),
+                action: config.state,
+            });
+        }
+        run_action(&dom, config.state)?;
+        Ok(DomStateResult {
+            state: state_name(dom.get_state()?.0).to_string(),
+            forced: false,
+        })
+    };
+    match res() {
+        Ok(t) => main_tx
+            .send(VirtResult::Ok(serde_json::to_string(&t).unwrap()))
+            .unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn get_domain_state(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    match lookup_domain(conn, &params[0]).and_then(|dom| Ok(dom.get_state()?)) {
+        Ok((state, _)) => main_tx
+            .send(VirtResult::Ok(state_name(state).to_string()))
+            .unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn delete_domain(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<DeleteDomainResult, VirtError> {
+        let config =
+            serde_json::from_str::<DeleteDomainConfig>(&params[0]).map_err(|_| InvalidInput)?;
+        let dom = lookup_domain(conn, &config.dom_name)?;
+        if config.confirm != config.dom_name && config.confirm != dom.get_uuid_string()? {
+            return Err(InvalidConfig(
+                "confirm must repeat the domain name or uuid".to_string(),
+            ));
+        }
+        let (state, _) = dom.get_state()?;
+        if state != VIR_DOMAIN_SHUTOFF && state != VIR_DOMAIN_CRASHED {
+            return Err(InvalidConfig(format!(
+                "domain {} is {}, shut it down first",
+                config.dom_name,
+                state_name(state)
+            )));
+        }
+        let xml = dom.get_xml_desc(VIR_DOMAIN_XML_INACTIVE)?;
+        let doc = Document::parse(&xml).expect("XML from LibVirt can't be parsed");
+        // cdroms are usually shared install media, never delete them. block devices
+        // (passed through disks, lvs) are host storage and are never touched either
+        let disks: Vec<String> = doc
+            .descendants()
+            .filter(|it| {
+                it.has_tag_name("disk")
+                    && it.attribute("device") == Some("disk")
+                    && it.attribute("type") == Some("file")
+            })
+            .filter_map(|it| {
+                it.children()
+                    .find(|it| it.has_tag_name("source"))
+                    .and_then(|it| it.attribute("file"))
+                    .map(|it| it.to_string())
+            })
+            .collect();
+
+        let mut flags = VIR_DOMAIN_UNDEFINE_MANAGED_SAVE;
+        if config.remove_snapshots.unwrap_or(false) {
+            flags |= VIR_DOMAIN_UNDEFINE_SNAPSHOTS_METADATA;
+        }
+        if config.remove_nvram.unwrap_or(false) {
+            flags |= VIR_DOMAIN_UNDEFINE_NVRAM;
+        } else {
+            flags |= VIR_DOMAIN_UNDEFINE_KEEP_NVRAM;
+        }
+        dom.undefine_flags(flags)?;
+
+        let mut result = DeleteDomainResult::default();
+        if config.remove_storage.unwrap_or(false) {
+            for path in disks {
+                if volume_used_by(conn, &path)?.is_some() {
+                    result.kept_volumes.push(path);
+                    continue;
+                }
+                // files outside any pool are removed directly
+                let deleted = match StorageVol::lookup_by_path(conn, &path) {
+                    Ok(vol) => vol.delete(0).is_ok(),
+                    Err(_) => fs::remove_file(&path).is_ok(),
+                };
+                if deleted {
+                    result.deleted_volumes.push(path);
+                } else {
+                    result.kept_volumes.push(path);
+                }
+            }
+        }
+        Ok(result)
+    };
+    match res() {
+        Ok(t) => main_tx
+            .send(VirtResult::Ok(serde_json::to_string(&t).unwrap()))
+            .unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn rename_domain(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<String, VirtError> {
+        let config =
+            serde_json::from_str::<RenameDomainConfig>(&params[0]).map_err(|_| InvalidInput)?;
+        if config.new_name.is_empty() || config.new_name.contains('/') {
+            return Err(InvalidConfig(format!(
+                "invalid domain name {}",
+                config.new_name
+            )));
+        }
+        let dom = lookup_domain(conn, &config.dom_name)?;
+        if Domain::lookup_by_name(conn, &config.new_name).is_ok() {
+            return Err(InvalidConfig(format!(
+                "domain {} already exists",
+                config.new_name
+            )));
+        }
+        // libvirt only renames inactive domains
+        if dom.is_active()? {
+            return Err(InvalidConfig(format!(
+                "domain {} is active, shut it down first",
+                config.dom_name
+            )));
+        }
+        dom.rename(&config.new_name, 0)?;
+        Ok("Rename domain successfully".to_string())
+    };
+    match res() {
+        Ok(output) => main_tx.send(VirtResult::Ok(output)).unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn get_autostart(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    match lookup_domain(conn, &params[0]).and_then(|dom| Ok(dom.get_autostart()?)) {
+        Ok(autostart) => main_tx.send(VirtResult::Ok(autostart.to_string())).unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn set_autostart(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<String, VirtError> {
+        let config =
+            serde_json::from_str::<AutostartConfig>(&params[0]).map_err(|_| InvalidInput)?;
+        lookup_domain(conn, &config.dom_name)?.set_autostart(config.autostart)?;
+        Ok(config.autostart.to_string())
+    };
+    match res() {
+        Ok(output) => main_tx.send(VirtResult::Ok(output)).unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
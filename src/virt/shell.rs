@@ -1,110 +1,414 @@
 use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
     process::Command,
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use super::{AltDomStateCommand, CreateVirtConfig, SnapShotConfig};
+use roxmltree::Document;
+
+use super::journal::{self, Operation, OperationStep};
+use super::utils::parse_size;
+use super::{
+    BlockJobConfig, CreateVirtConfig, ExternalSnapshotConfig, RevertConfig, SnapShotConfig,
+    DEFAULT_NETWORK, DEFAULT_POOL,
+};
 
 pub fn create_snapshot(configure: SnapShotConfig) -> Result<String, std::io::Error> {
-    let now = SystemTime::now();
-    let unix_timestamp = now.duration_since(UNIX_EPOCH).unwrap().as_millis();
-    let temp_snapshot_str = "temp_snapshot".to_string() + &unix_timestamp.to_string();
-    let mut is_temp_create = false;
-    // create tmp snapshot to save current change, so that we can revert to parent which user defined
-    if let Some(parent) = &configure.parent {
-        if parent.to_ascii_lowercase().as_str() != "current" {
-            // create tmp snapshot to store latest change
-            let mut cmd = Command::new("virsh");
-            cmd.arg("snapshot-create-as")
-                .arg(&configure.dom_name)
-                .arg("--name")
-                .arg(&temp_snapshot_str);
-            let _ = cmd.status()?;
+    let snapshot_as = |dom_name: &str| -> Result<(), std::io::Error> {
+        let mut args = vec![
+            "snapshot-create-as",
+            dom_name,
+            "--name",
+            &configure.snapshot_name,
+        ];
+        if let Some(des) = &configure.description {
+            args.extend(["--description", des.as_str()]);
+        }
+        if configure.is_live.as_deref() == Some("yes") {
+            args.push("--live");
+        }
+        run_virsh(&args).map(|_| ())
+    };
+    match &configure.parent {
+        // park the latest state in a temp snapshot, snapshot the parent, come back
+        Some(parent) if parent.to_ascii_lowercase().as_str() != "current" => {
+            with_temp_snapshot(&configure.dom_name, "create-snapshot", TEMP_PREFIX, || {
+                run_virsh(&["snapshot-revert", &configure.dom_name, parent])?;
+                snapshot_as(&configure.dom_name)
+            })?
+        }
+        _ => snapshot_as(&configure.dom_name)?,
+    }
+    Ok("Success".to_string())
+}
 
-            // revert to parent
-            let mut cmd = Command::new("virsh");
-            cmd.arg("snapshot-revert")
-                .arg(&configure.dom_name)
-                .arg(&parent);
-            let _ = cmd.status()?;
-            is_temp_create = true;
+// disk-only snapshot: every selected disk gets a fresh qcow2 overlay and its current
+// image becomes the read-only backing file. quick on large disks and works for raw
+// disks and uefi guests, where internal snapshots don't
+pub fn create_external_snapshot(
+    configure: ExternalSnapshotConfig,
+) -> Result<String, std::io::Error> {
+    let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+    if configure.snapshot_name.is_empty() || configure.snapshot_name.contains('/') {
+        return Err(invalid(format!(
+            "invalid snapshot name {}",
+            configure.snapshot_name
+        )));
+    }
+    let disks = disk_targets(&configure.dom_name)?;
+    if let Some(wanted) = &configure.disks {
+        if let Some(target) = wanted
+            .iter()
+            .find(|target| !disks.iter().any(|(it, _)| it == *target))
+        {
+            return Err(invalid(format!(
+                "{} has no disk {}",
+                configure.dom_name, target
+            )));
         }
     }
-    let mut cmd = Command::new("virsh");
-    cmd.arg("snapshot-create-as")
-        .arg(&configure.dom_name)
-        .arg("--name")
-        .arg(&configure.snapshot_name);
-    if let Some(des) = configure.description {
-        cmd.arg("--description").arg(des);
+    let selected = |target: &str| {
+        configure
+            .disks
+            .as_ref()
+            .is_none_or(|it| it.iter().any(|t| t == target))
+    };
+    if !disks.iter().any(|(target, _)| selected(target)) {
+        return Err(invalid(format!(
+            "{} has no disk to snapshot",
+            configure.dom_name
+        )));
     }
-    if let Some(is_live) = configure.is_live {
-        if is_live == "yes" {
-            cmd.arg("--live");
+    if let Some(dir) = &configure.directory {
+        if !Path::new(dir).is_dir() {
+            return Err(invalid(format!("{} is not a directory", dir)));
         }
     }
-    let output = cmd.output()?;
-    match output.status.code() {
-        Some(code) => {
-            if code == 0 {
-                ()
-            } else {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidInput,
-                    String::from_utf8(output.stderr).unwrap().trim(),
-                ));
-            }
+    let mut specs = Vec::new();
+    for (target, source) in &disks {
+        if !selected(target) {
+            specs.push(format!("{},snapshot=no", target));
+            continue;
         }
-        None => {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "No status code".to_string(),
-            ))
+        let dir = match &configure.directory {
+            Some(dir) => PathBuf::from(dir),
+            None => Path::new(source)
+                .parent()
+                .map(Path::to_path_buf)
+                .ok_or(invalid(format!("{} has no file behind it", target)))?,
+        };
+        let overlay = dir.join(format!(
+            "{}-{}-{}.qcow2",
+            configure.dom_name, configure.snapshot_name, target
+        ));
+        if overlay.exists() {
+            return Err(invalid(format!("{} already exists", overlay.display())));
         }
+        // diskspec fields are comma separated, a literal comma is written twice
+        specs.push(format!(
+            "{},snapshot=external,file={}",
+            target,
+            overlay.to_string_lossy().replace(',', ",,")
+        ));
+    }
+    let mut args = vec![
+        "snapshot-create-as",
+        &configure.dom_name,
+        "--name",
+        &configure.snapshot_name,
+        "--disk-only",
+        "--atomic",
+    ];
+    if let Some(des) = &configure.description {
+        args.extend(["--description", des.as_str()]);
+    }
+    if configure.quiesce == Some(true) {
+        args.push("--quiesce");
+    }
+    for spec in &specs {
+        args.extend(["--diskspec", spec.as_str()]);
+    }
+    run_virsh(&args)?;
+    Ok("Success".to_string())
+}
+
+// hard disks of the domain as (target, source), cdroms and floppies left out
+fn disk_targets(dom_name: &str) -> Result<Vec<(String, String)>, std::io::Error> {
+    // " file   disk   vda   /var/lib/libvirt/images/vm.qcow2" below a two line header
+    let stdout = run_virsh(&["domblklist", dom_name, "--details"])?;
+    Ok(stdout
+        .lines()
+        .skip(2)
+        .filter_map(|line| {
+            let cols: Vec<&str> = line.split_whitespace().collect();
+            (cols.len() >= 4 && cols[1] == "disk")
+                .then(|| (cols[2].to_string(), cols[3..].join(" ")))
+        })
+        .collect())
+}
+
+// an active commit needs qemu behind the domain
+pub fn is_active(dom_name: &str) -> Result<bool, std::io::Error> {
+    let state = run_virsh(&["domstate", dom_name])?;
+    Ok(!matches!(state.trim(), "shut off" | "crashed"))
+}
+
+// merges the disk's overlays down into `base` (the bottom image when unset) and
+// pivots the domain onto it; --wait keeps virsh around until the job is done.
+// the overlays and the snapshots that made them are dropped afterwards, nothing
+// can use them once the domain has moved off
+pub fn block_commit(configure: BlockJobConfig) -> Result<String, std::io::Error> {
+    let dom_name = &configure.dom_name;
+    let (_, mut image) = disk_targets(dom_name)?
+        .into_iter()
+        .find(|(target, _)| *target == configure.target)
+        .ok_or(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} has no disk {}", dom_name, configure.target),
+        ))?;
+    let mut overlays = Vec::new();
+    while configure.base.as_deref() != Some(image.as_str()) {
+        let info = image_info(&image)?;
+        let Some(backing) = info["full-backing-filename"].as_str() else {
+            break;
+        };
+        overlays.push(image);
+        image = backing.to_string();
+    }
+    let mut args = vec![
+        "blockcommit",
+        dom_name,
+        &configure.target,
+        "--active",
+        "--pivot",
+        "--wait",
+    ];
+    if let Some(base) = &configure.base {
+        args.extend(["--base", base.as_str()]);
+    }
+    run_virsh(&args)?;
+    for name in snapshot_names(dom_name)? {
+        let xml = run_virsh(&["snapshot-dumpxml", dom_name, &name])?;
+        if snapshot_overlays(&xml, &configure.target)
+            .iter()
+            .any(|it| overlays.contains(it))
+        {
+            run_virsh(&[
+                "snapshot-delete",
+                dom_name,
+                "--snapshotname",
+                &name,
+                "--metadata",
+            ])?;
+        }
+    }
+    for overlay in &overlays {
+        std::fs::remove_file(overlay)?;
+    }
+    Ok("Success".to_string())
+}
+
+// files an external snapshot moved `target` onto
+fn snapshot_overlays(xml: &str, target: &str) -> Vec<String> {
+    let Ok(doc) = Document::parse(xml) else {
+        return Vec::new();
     };
-    if is_temp_create {
-        //create complete, revert to latest tmp snapshot
-        let mut cmd = Command::new("virsh");
-        cmd.arg("snapshot-revert")
-            .arg(&configure.dom_name)
-            .arg(&temp_snapshot_str);
-        let _ = cmd.status()?;
+    doc.descendants()
+        .filter(|it| {
+            it.has_tag_name("disk")
+                && it.attribute("name") == Some(target)
+                && it.attribute("snapshot") == Some("external")
+        })
+        .filter_map(|it| it.children().find(|it| it.has_tag_name("source")))
+        .filter_map(|it| it.attribute("file").map(str::to_string))
+        .collect()
+}
 
-        // delete tmp snapshot
-        let mut cmd = Command::new("virsh");
-        cmd.arg("snapshot-delete")
-            .arg(&configure.dom_name)
-            .arg(&temp_snapshot_str);
-        let _ = cmd.status()?;
+// copies the backing chain (down to `base` when set) into the active image so the
+// disk no longer depends on it
+pub fn block_pull(configure: BlockJobConfig) -> Result<String, std::io::Error> {
+    let mut args = vec![
+        "blockpull",
+        &configure.dom_name,
+        &configure.target,
+        "--wait",
+    ];
+    if let Some(base) = &configure.base {
+        args.extend(["--base", base.as_str()]);
     }
+    run_virsh(&args)?;
     Ok("Success".to_string())
 }
 
-pub fn delete_snapshot(configure: SnapShotConfig) -> Result<String, std::io::Error> {
-    let mut cmd = Command::new("virsh");
-    cmd.arg("snapshot-delete")
-        .arg(configure.dom_name)
-        .arg("--snapshotname")
-        .arg(configure.snapshot_name);
-    let output = cmd.output()?;
+// runs virsh and turns a non-zero exit into an error carrying its stderr
+pub fn run_virsh(args: &[&str]) -> Result<String, std::io::Error> {
+    let output = Command::new("virsh").args(args).output()?;
     match output.status.code() {
-        Some(code) => {
-            if code == 0 {
-                Ok("".to_string())
-            } else {
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidInput,
-                    String::from_utf8(output.stderr).unwrap().trim(),
-                ))
+        Some(0) => Ok(String::from_utf8(output.stdout).unwrap()),
+        Some(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            String::from_utf8(output.stderr).unwrap().trim(),
+        )),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "No status code".to_string(),
+        )),
+    }
+}
+
+// temp snapshots are named prefix + unix millis
+const TEMP_PREFIX: &str = "temp_snapshot";
+const CLONE_TEMP_PREFIX: &str = "temp_snapshot_for_clone";
+
+// only names of exactly that form, a user's temp_snapshot_before_upgrade isn't one
+fn is_temp_snapshot(name: &str) -> bool {
+    [CLONE_TEMP_PREFIX, TEMP_PREFIX].iter().any(|prefix| {
+        name.strip_prefix(prefix)
+            .is_some_and(|it| !it.is_empty() && it.bytes().all(|b| b.is_ascii_digit()))
+    })
+}
+
+// runs `body` with the domain's current state parked in a temp snapshot; success
+// and failure both end by reverting to it, so a failed step rolls back for free.
+// the journal record only goes away once the temp snapshot is gone again
+fn with_temp_snapshot<F>(
+    dom_name: &str,
+    kind: &str,
+    prefix: &str,
+    body: F,
+) -> Result<(), std::io::Error>
+where
+    F: FnOnce() -> Result<(), std::io::Error>,
+{
+    let unix_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let mut op = Operation {
+        dom_name: dom_name.to_string(),
+        kind: kind.to_string(),
+        temp_snapshot: format!("{}{}", prefix, unix_timestamp),
+        step: OperationStep::CreateTemp,
+        started_at: (unix_timestamp / 1000) as i64,
+    };
+    journal::save(&op)?;
+    if let Err(e) = run_virsh(&["snapshot-create-as", dom_name, "--name", &op.temp_snapshot]) {
+        journal::finish(&op);
+        return Err(e);
+    }
+    op.step = OperationStep::Run;
+    if let Err(e) = journal::save(&op) {
+        // nothing ran yet, the temp snapshot is just dropped again
+        let _ = run_virsh(&["snapshot-delete", dom_name, &op.temp_snapshot]);
+        journal::finish(&op);
+        return Err(e);
+    }
+    let res = body();
+    op.step = OperationStep::Restore;
+    // the domain must get back to the temp snapshot either way, the Run record
+    // already tells the recovery pass to revert
+    if let Err(e) = journal::save(&op) {
+        println!("journal {} for {}: {}", op.temp_snapshot, dom_name, e);
+    }
+    // on failure the record stays behind for the recovery pass
+    if let Err(e) = run_virsh(&["snapshot-revert", dom_name, &op.temp_snapshot])
+        .and_then(|_| run_virsh(&["snapshot-delete", dom_name, &op.temp_snapshot]))
+    {
+        return Err(std::io::Error::new(
+            e.kind(),
+            format!(
+                "{}; domain state is kept in snapshot {}",
+                res.err()
+                    .map_or(e.to_string(), |res| format!("{}, then {}", res, e)),
+                op.temp_snapshot
+            ),
+        ));
+    }
+    journal::finish(&op);
+    res
+}
+
+fn snapshot_names(dom_name: &str) -> Result<Vec<String>, std::io::Error> {
+    Ok(run_virsh(&["snapshot-list", dom_name, "--name"])?
+        .lines()
+        .map(|it| it.trim().to_string())
+        .filter(|it| !it.is_empty())
+        .collect())
+}
+
+// brings every domain back to the state parked in a temp snapshot that an
+// interrupted operation left behind; returns what was done
+pub fn recover_operations() -> Vec<String> {
+    let mut report = Vec::new();
+    for op in journal::pending() {
+        // libvirtd may not be up yet, the record stays for the next start
+        let names = match snapshot_names(&op.dom_name) {
+            Ok(names) => names,
+            Err(e) => {
+                report.push(format!(
+                    "{}: {} kept for the next start, {}",
+                    op.dom_name, op.temp_snapshot, e
+                ));
+                continue;
             }
+        };
+        if !names.contains(&op.temp_snapshot) {
+            journal::finish(&op);
+            continue;
+        }
+        let current = run_virsh(&["snapshot-current", &op.dom_name, "--name"]).unwrap_or_default();
+        let res = if op.step == OperationStep::CreateTemp || current.trim() == op.temp_snapshot {
+            run_virsh(&["snapshot-delete", &op.dom_name, &op.temp_snapshot])
+        } else {
+            run_virsh(&["snapshot-revert", &op.dom_name, &op.temp_snapshot])
+                .and_then(|_| run_virsh(&["snapshot-delete", &op.dom_name, &op.temp_snapshot]))
+        };
+        match res {
+            Ok(_) => {
+                report.push(format!("{}: resolved {}", op.dom_name, op.temp_snapshot));
+                journal::finish(&op);
+            }
+            Err(e) => report.push(format!(
+                "{}: {} left in place, {}",
+                op.dom_name, op.temp_snapshot, e
+            )),
         }
-        None => Ok("".to_string()),
     }
+    // temp snapshots with no record predate the journal, there's no telling what
+    // happened since they were taken so they're never reverted to
+    let recorded: Vec<(String, String)> = journal::pending()
+        .into_iter()
+        .map(|op| (op.dom_name, op.temp_snapshot))
+        .collect();
+    let dom_names = run_virsh(&["list", "--all", "--name"]).unwrap_or_default();
+    for dom_name in dom_names
+        .lines()
+        .map(|it| it.trim())
+        .filter(|it| !it.is_empty())
+    {
+        let current = run_virsh(&["snapshot-current", dom_name, "--name"]).unwrap_or_default();
+        for name in snapshot_names(dom_name).unwrap_or_default() {
+            if !is_temp_snapshot(&name) || recorded.contains(&(dom_name.to_string(), name.clone()))
+            {
+                continue;
+            }
+            if current.trim() != name {
+                report.push(format!("{}: unrecorded {} left in place", dom_name, name));
+                continue;
+            }
+            match run_virsh(&["snapshot-delete", dom_name, &name]) {
+                Ok(_) => report.push(format!("{}: deleted unrecorded {}", dom_name, name)),
+                Err(e) => report.push(format!("{}: {} left in place, {}", dom_name, name, e)),
+            }
+        }
+    }
+    report
 }
 
-pub fn set_current_snapshot(configure: SnapShotConfig) -> Result<String, std::io::Error> {
+pub fn delete_snapshot(configure: SnapShotConfig) -> Result<String, std::io::Error> {
     let mut cmd = Command::new("virsh");
-    cmd.arg("snapshot-revert")
+    cmd.arg("snapshot-delete")
         .arg(configure.dom_name)
         .arg("--snapshotname")
         .arg(configure.snapshot_name);
@@ -120,36 +424,23 @@ pub fn set_current_snapshot(configure: SnapShotConfig) -> Result<String, std::io
                 ))
             }
         }
-        None => Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            "No status code".to_string(),
-        )),
+        None => Ok("".to_string()),
     }
 }
 
 pub fn create_virt(configure: CreateVirtConfig) -> Result<String, std::io::Error> {
-    let mut create_qcow2_cmd = Command::new("qemu-img");
-    create_qcow2_cmd
-        .arg("create")
-        .arg("-f")
-        .arg("qcow2")
-        .arg("disk.qcow2")
-        .arg(configure.disk_size)
-        .current_dir("/data_disk/create_test");
-    let output = create_qcow2_cmd.output()?;
-    match output.status.code() {
-        Some(code) => {
-            if code == 0 {
-                ()
-            } else {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidInput,
-                    String::from_utf8(output.stderr).unwrap().trim(),
-                ));
-            }
+    // virt-install allocates the disk in the pool, it takes the size in GiB
+    let disk_size = match parse_size(&configure.disk_size) {
+        Some(size) => size.div_ceil(1 << 30),
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid disk size {}", configure.disk_size),
+            ))
         }
-        None => (),
     };
+    let pool = configure.pool.unwrap_or(DEFAULT_POOL.to_string());
+    let network = configure.network.unwrap_or(DEFAULT_NETWORK.to_string());
     let mut cmd = Command::new("virt-install");
     cmd.arg("--name")
         .arg(configure.virt_name)
@@ -158,16 +449,16 @@ pub fn create_virt(configure: CreateVirtConfig) -> Result<String, std::io::Error
         .arg("--vcpu")
         .arg(configure.vcpu)
         .arg("--disk")
-        .arg("/data_disk/create_test/disk.qcow2")
+        .arg(format!("pool={},size={},format=qcow2", pool, disk_size))
         .arg("--cdrom")
-        .arg(format!("/data_disk/create_test/cdrom.iso"))
+        .arg("/data_disk/create_test/cdrom.iso")
         .arg("--graphics")
         .arg(format!(
             "vnc,port={},password={},listen=0.0.0.0",
             "5903", "abc123"
         ))
         .arg("--network")
-        .arg("default")
+        .arg(format!("network={}", network))
         .arg("--wait")
         .arg("0");
     let output = cmd.output()?;
@@ -189,86 +480,274 @@ pub fn create_virt(configure: CreateVirtConfig) -> Result<String, std::io::Error
     }
 }
 
-pub fn clone_snapshot_as_vm(configure: SnapShotConfig) -> Result<String, std::io::Error> {
-    let now = SystemTime::now();
-    let unix_timestamp = now.duration_since(UNIX_EPOCH).unwrap().as_millis();
-    let temp_snapshot_str = "temp_snapshot_for_clone".to_string() + &unix_timestamp.to_string();
+// state left behind by a revert, newest last when sorted by name
+const PRE_REVERT_PREFIX: &str = "pre-revert-";
+// older pre-revert snapshots are deleted, each one holds a whole domain state
+const PRE_REVERT_KEEP: usize = 5;
 
-    // create tmp snapshot to store latest change
-    let mut cmd = Command::new("virsh");
-    cmd.arg("snapshot-create-as")
-        .arg(&configure.dom_name)
-        .arg("--name")
-        .arg(&temp_snapshot_str);
-    let _ = cmd.status()?;
+fn pre_revert_snapshots(dom_name: &str) -> Result<Vec<String>, std::io::Error> {
+    let mut names: Vec<String> = snapshot_names(dom_name)?
+        .into_iter()
+        .filter(|it| it.starts_with(PRE_REVERT_PREFIX))
+        .collect();
+    names.sort();
+    Ok(names)
+}
 
-    //revert to old snapshot need to clone
-    let mut cmd = Command::new("virsh");
-    cmd.arg("snapshot-revert")
-        .arg(&configure.dom_name)
-        .arg(&configure.snapshot_name);
-    let _ = cmd.status()?;
-    let mut cmd = Command::new("virt-clone");
-    cmd.arg("--original")
-        .arg(&configure.dom_name)
-        .arg("--auto-clone");
-    let _ = cmd.status()?;
+// reverts to the snapshot, saving the state being left as a pre-revert snapshot
+// first unless asked not to, so undo_revert can come back to it
+pub fn revert_snapshot(configure: RevertConfig) -> Result<String, std::io::Error> {
+    let dom_name = &configure.dom_name;
+    if configure.save_current == Some(false) {
+        run_virsh(&[
+            "snapshot-revert",
+            dom_name,
+            "--snapshotname",
+            &configure.snapshot_name,
+        ])?;
+        return Ok(format!("Reverted to {}", configure.snapshot_name));
+    }
+    let unix_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let saved = format!("{}{}", PRE_REVERT_PREFIX, unix_timestamp);
+    run_virsh(&[
+        "snapshot-create-as",
+        dom_name,
+        "--name",
+        &saved,
+        "--description",
+        &format!("Saved before reverting to {}", configure.snapshot_name),
+    ])?;
+    if let Err(e) = run_virsh(&[
+        "snapshot-revert",
+        dom_name,
+        "--snapshotname",
+        &configure.snapshot_name,
+    ]) {
+        // nothing was lost, the saved state isn't needed
+        let _ = run_virsh(&["snapshot-delete", dom_name, "--snapshotname", &saved]);
+        return Err(e);
+    }
+    let names = pre_revert_snapshots(dom_name).unwrap_or_default();
+    for name in names
+        .iter()
+        .take(names.len().saturating_sub(PRE_REVERT_KEEP))
+    {
+        let _ = run_virsh(&["snapshot-delete", dom_name, "--snapshotname", name]);
+    }
+    Ok(format!(
+        "Reverted to {}, the previous state is saved as {}",
+        configure.snapshot_name, saved
+    ))
+}
 
-    //clone complete, revert to latest tmp snapshot
-    let mut cmd = Command::new("virsh");
-    cmd.arg("snapshot-revert")
-        .arg(&configure.dom_name)
-        .arg(&temp_snapshot_str);
-    let _ = cmd.status()?;
+// goes back to the state the last revert saved and drops its snapshot, so undoing
+// again steps back through the earlier reverts; returns the snapshot's name
+pub fn undo_revert(dom_name: &str) -> Result<String, std::io::Error> {
+    let saved = pre_revert_snapshots(dom_name)?
+        .pop()
+        .ok_or(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("Domain {} has no revert to undo", dom_name),
+        ))?;
+    run_virsh(&["snapshot-revert", dom_name, "--snapshotname", &saved])?;
+    run_virsh(&["snapshot-delete", dom_name, "--snapshotname", &saved])?;
+    Ok(saved)
+}
 
-    // delete tmp snapshot
-    let mut cmd = Command::new("virsh");
-    cmd.arg("snapshot-delete")
-        .arg(&configure.dom_name)
-        .arg(&temp_snapshot_str);
-    let _ = cmd.status()?;
+pub fn clone_snapshot_as_vm(configure: SnapShotConfig) -> Result<String, std::io::Error> {
+    with_temp_snapshot(
+        &configure.dom_name,
+        "clone-snapshot",
+        CLONE_TEMP_PREFIX,
+        || {
+            run_virsh(&[
+                "snapshot-revert",
+                &configure.dom_name,
+                &configure.snapshot_name,
+            ])?;
+            let output = Command::new("virt-clone")
+                .arg("--original")
+                .arg(&configure.dom_name)
+                .arg("--auto-clone")
+                .output()?;
+            if !output.status.success() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    String::from_utf8(output.stderr).unwrap().trim(),
+                ));
+            }
+            Ok(())
+        },
+    )?;
     Ok("Success".to_string())
 }
 
-pub fn alt_vm_state(config: AltDomStateCommand) -> Result<String, std::io::Error> {
+pub fn list_dhcp_leases(net_name: &str) -> Result<String, std::io::Error> {
     let mut cmd = Command::new("virsh");
-    match config.state.as_str() {
-        "start" => {
-            cmd.arg("start");
-        }
-        "shutdown" => {
-            cmd.arg("shutdown");
+    cmd.arg("net-dhcp-leases").arg(net_name);
+    let output = cmd.output()?;
+    match output.status.code() {
+        Some(0) => (),
+        Some(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                String::from_utf8(output.stderr).unwrap().trim(),
+            ))
         }
-        "suspend" => {
-            cmd.arg("suspend");
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "No status code".to_string(),
+            ))
         }
-        "destroy" => {
-            cmd.arg("destroy");
+    };
+    // rows look like
+    // 2024-03-01 12:00:00   52:54:00:aa:bb:cc   ipv4   192.168.122.10/24   debian   01:52:54:00:aa:bb:cc
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let leases: Vec<HashMap<&str, String>> = stdout
+        .lines()
+        .skip_while(|line| !line.trim_start().starts_with("---"))
+        .skip(1)
+        .filter_map(|line| {
+            let cols: Vec<&str> = line.split_whitespace().collect();
+            if cols.len() < 6 {
+                return None;
+            }
+            let mut lease = HashMap::new();
+            lease.insert("expiryTime", format!("{} {}", cols[0], cols[1]));
+            lease.insert("mac", cols[2].to_string());
+            lease.insert("protocol", cols[3].to_string());
+            lease.insert("ipAddress", cols[4].to_string());
+            lease.insert("hostname", cols[5].to_string());
+            lease.insert("clientId", cols.get(6).unwrap_or(&"-").to_string());
+            Some(lease)
+        })
+        .collect();
+    Ok(serde_json::to_string(&leases).unwrap())
+}
+
+// raw counters of every domain, keyed by domain name then stat name;
+// inactive domains only report their state
+pub fn domain_stats() -> Result<HashMap<String, HashMap<String, String>>, std::io::Error> {
+    let mut cmd = Command::new("virsh");
+    cmd.arg("domstats")
+        .arg("--raw")
+        .arg("--state")
+        .arg("--cpu-total")
+        .arg("--balloon")
+        .arg("--vcpu")
+        .arg("--block")
+        .arg("--interface");
+    let output = cmd.output()?;
+    match output.status.code() {
+        Some(0) => (),
+        Some(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                String::from_utf8(output.stderr).unwrap().trim(),
+            ))
         }
-        "undefine" => {
-            cmd.arg("undefine");
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "No status code".to_string(),
+            ))
         }
-        "resume" => {
-            cmd.arg("resume");
+    };
+    // output looks like
+    // Domain: 'vm1'
+    //   cpu.time=1234567
+    //   block.0.name=vda
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut stats: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut current = None;
+    for line in stdout.lines() {
+        if let Some(dom_name) = line.strip_prefix("Domain: ") {
+            let dom_name = dom_name.trim().trim_matches('\'').to_string();
+            stats.insert(dom_name.clone(), HashMap::new());
+            current = Some(dom_name);
+        } else if let (Some(dom_name), Some((key, value))) = (&current, line.trim().split_once('='))
+        {
+            stats
+                .get_mut(dom_name)
+                .unwrap()
+                .insert(key.to_string(), value.to_string());
         }
-        _ => (),
+    }
+    Ok(stats)
+}
+
+// percent done of the domain's running libvirt job, none when there's no job or
+// it doesn't report totals (e.g. virt-install or virt-clone copying volumes)
+pub fn job_progress(dom_name: &str) -> Option<u32> {
+    let percent = |processed: u64, total: u64| (processed * 100 / total).min(100) as u32;
+    // --rawstats adds lines like "data_processed: 1048576" after the table
+    let domain_job = || -> Option<u32> {
+        let stdout = run_virsh(&["domjobinfo", dom_name, "--rawstats"]).ok()?;
+        let stat = |name: &str| -> Option<u64> {
+            stdout.lines().find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                (key.trim() == name).then(|| value.trim().parse().ok())?
+            })
+        };
+        let total = stat("data_total").filter(|it| *it > 0)?;
+        Some(percent(stat("data_processed")?, total))
     };
-    cmd.arg(&config.dom_name);
-    let output = cmd.output()?;
-    match output.status.code() {
-        Some(code) => {
-            if code == 0 {
-                Ok("Success".to_string())
-            } else {
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidInput,
-                    String::from_utf8(output.stderr).unwrap().trim(),
-                ))
-            }
+    domain_job().or_else(|| {
+        block_jobs(dom_name)
+            .into_iter()
+            .find(|(_, _, end)| *end > 0)
+            .map(|(_, cur, end)| percent(cur, end))
+    })
+}
+
+// running block jobs (commit, pull, copy) as (target, cur, end); they don't show up
+// in domjobinfo, every disk has its own
+fn block_jobs(dom_name: &str) -> Vec<(String, u64, u64)> {
+    disk_targets(dom_name)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(target, _)| {
+            // " type=Active Block Commit bandwidth=0 cur=1048576 end=2097152"
+            let stdout = run_virsh(&["blockjob", dom_name, &target, "--raw"]).ok()?;
+            let field = |name: &str| -> Option<u64> {
+                stdout
+                    .split_whitespace()
+                    .find_map(|it| it.strip_prefix(name)?.parse().ok())
+            };
+            Some((target.clone(), field("cur=")?, field("end=")?))
+        })
+        .collect()
+}
+
+// aborts the domain job, or the block jobs when there is none
+pub fn abort_job(dom_name: &str) -> Result<String, std::io::Error> {
+    run_virsh(&["domjobabort", dom_name]).or_else(|e| {
+        let jobs = block_jobs(dom_name);
+        if jobs.is_empty() {
+            return Err(e);
         }
-        None => Err(std::io::Error::new(
+        for (target, _, _) in jobs {
+            run_virsh(&["blockjob", dom_name, &target, "--abort"])?;
+        }
+        Ok("".to_string())
+    })
+}
+
+// `qemu-img info` as json; -U so images of running domains can be read too
+pub fn image_info(path: &str) -> Result<serde_json::Value, std::io::Error> {
+    let output = Command::new("qemu-img")
+        .args(["info", "-U", "--output=json", path])
+        .output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::new(
             std::io::ErrorKind::InvalidInput,
-            "No status code".to_string(),
-        )),
+            String::from_utf8(output.stderr).unwrap().trim(),
+        ));
     }
+    serde_json::from_slice(&output.stdout)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
 }
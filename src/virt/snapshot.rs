@@ -0,0 +1,577 @@
+use chrono::Utc;
+use roxmltree::{Document, Node};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::mpsc::Sender,
+};
+use virt::{
+    connect::Connect,
+    domain::Domain,
+    domain_snapshot::DomainSnapshot,
+    sys::{
+        VIR_DOMAIN_SNAPSHOT_CREATE_CURRENT, VIR_DOMAIN_SNAPSHOT_CREATE_REDEFINE,
+        VIR_DOMAIN_SNAPSHOT_XML_SECURE,
+    },
+};
+
+use super::conn::lookup_domain;
+use super::power::state_name;
+use super::shell::image_info;
+use super::utils::edit_snapshot_xml;
+
+use super::VirtError::{self, *};
+use super::{RevertConfig, SnapShotEditConfig, SnapshotDiffConfig, VirtResult};
+
+type Field<'a> = (&'a str, &'a [&'a str], Option<&'a str>);
+
+// fields of the snapshot itself, as (name, element path, attribute)
+const METADATA_FIELDS: [Field; 5] = [
+    ("description", &["description"], None),
+    ("state", &["state"], None),
+    ("creationTime", &["creationTime"], None),
+    ("parent", &["parent", "name"], None),
+    ("memory", &["memory"], Some("snapshot")),
+];
+
+// fields of the domain definition the snapshot captured
+const DOMAIN_FIELDS: [Field; 8] = [
+    ("vcpu", &["vcpu"], None),
+    ("memory", &["memory"], None),
+    ("memoryUnit", &["memory"], Some("unit")),
+    ("currentMemory", &["currentMemory"], None),
+    ("cpu.mode", &["cpu"], Some("mode")),
+    ("cpu.model", &["cpu", "model"], None),
+    ("os.arch", &["os", "type"], Some("arch")),
+    ("os.machine", &["os", "type"], Some("machine")),
+];
+
+// runtime names that differ between any two captures of a running domain
+const VOLATILE_TAGS: [&str; 1] = ["alias"];
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldChange {
+    pub field: String,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceChange {
+    // added, removed or changed
+    pub change: String,
+    pub device: String,
+    // target dev for disks, mac for interfaces, position among its kind otherwise
+    pub key: String,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskSizeChange {
+    pub target: String,
+    // internal or external, "internal/external" when the two snapshots differ
+    pub snapshot: String,
+    // allocation of external overlays, null for internal snapshots
+    pub from_bytes: Option<u64>,
+    pub to_bytes: Option<u64>,
+    pub delta_bytes: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotNode {
+    pub name: String,
+    pub description: String,
+    pub state: String,
+    pub creation_time: i64,
+    pub is_current: bool,
+    pub parent: Option<String>,
+    // summed over the snapshot's external disks, see disk_bytes
+    pub disk_size: Option<u64>,
+    // a checkpoint with guest memory rather than just disks
+    pub has_memory: bool,
+    // oldest first
+    pub children: Vec<SnapshotNode>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotTree {
+    pub dom_name: String,
+    pub roots: Vec<String>,
+    pub current: Option<String>,
+    pub tree: Vec<SnapshotNode>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotDiff {
+    pub dom_name: String,
+    pub from: String,
+    pub to: String,
+    pub metadata: Vec<FieldChange>,
+    pub domain: Vec<FieldChange>,
+    pub devices: Vec<DeviceChange>,
+    pub disks: Vec<DiskSizeChange>,
+}
+
+pub fn lookup_snapshot(
+    dom: &Domain,
+    dom_name: &str,
+    snapshot_name: &str,
+) -> Result<DomainSnapshot, VirtError> {
+    DomainSnapshot::lookup_by_name(dom, snapshot_name, 0).map_err(|_| SnapShotNotFound {
+        dom_name: dom_name.to_string(),
+        snapshot_name: snapshot_name.to_string(),
+    })
+}
+
+fn child<'a, 'i>(node: Node<'a, 'i>, tag: &str) -> Option<Node<'a, 'i>> {
+    node.children().find(|it| it.has_tag_name(tag))
+}
+
+// follows `path` down from `node`, reading the text or the attribute at the end
+fn value_at(node: Node, path: &[&str], attr: Option<&str>) -> Option<String> {
+    let node = path.iter().try_fold(node, |node, tag| child(node, tag))?;
+    match attr {
+        Some(attr) => node.attribute(attr).map(String::from),
+        None => node.text().map(|it| it.trim().to_string()),
+    }
+}
+
+fn compare_fields(fields: &[Field], from: Option<Node>, to: Option<Node>) -> Vec<FieldChange> {
+    fields
+        .iter()
+        .filter_map(|(field, path, attr)| {
+            let from = from.and_then(|it| value_at(it, path, *attr));
+            let to = to.and_then(|it| value_at(it, path, *attr));
+            (from != to).then(|| FieldChange {
+                field: field.to_string(),
+                from,
+                to,
+            })
+        })
+        .collect()
+}
+
+// one line per element like disk[device=disk,type=file](source[file=/a.qcow2] target[dev=vda]),
+// attributes sorted so equal devices always compare equal
+pub(crate) fn canonical(node: Node) -> String {
+    let mut attrs: Vec<String> = node
+        .attributes()
+        .map(|it| format!("{}={}", it.name(), it.value()))
+        .collect();
+    attrs.sort();
+    let mut out = node.tag_name().name().to_string();
+    if !attrs.is_empty() {
+        out += &format!("[{}]", attrs.join(","));
+    }
+    if let Some(text) = node.text().map(str::trim).filter(|it| !it.is_empty()) {
+        out += &format!("\"{}\"", text);
+    }
+    let children: Vec<String> = node
+        .children()
+        .filter(|it| it.is_element() && !VOLATILE_TAGS.contains(&it.tag_name().name()))
+        .map(canonical)
+        .collect();
+    if !children.is_empty() {
+        out += &format!("({})", children.join(" "));
+    }
+    out
+}
+
+fn device_key(device: Node, position: usize) -> String {
+    match device.tag_name().name() {
+        "disk" => value_at(device, &["target"], Some("dev")),
+        "interface" => value_at(device, &["mac"], Some("address")),
+        "controller" => Some(format!(
+            "{}{}",
+            device.attribute("type").unwrap_or(""),
+            device.attribute("index").unwrap_or("")
+        )),
+        _ => None,
+    }
+    .unwrap_or(position.to_string())
+}
+
+// keyed by (device, key)
+fn devices(domain: Option<Node>) -> BTreeMap<(String, String), String> {
+    let mut devices = BTreeMap::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    if let Some(list) = domain.and_then(|it| child(it, "devices")) {
+        for device in list.children().filter(|it| it.is_element()) {
+            let tag = device.tag_name().name().to_string();
+            let position = positions.entry(tag.clone()).or_insert(0);
+            devices.insert((tag, device_key(device, *position)), canonical(device));
+            *position += 1;
+        }
+    }
+    devices
+}
+
+pub(crate) fn compare_devices(from: Option<Node>, to: Option<Node>) -> Vec<DeviceChange> {
+    let (from, to) = (devices(from), devices(to));
+    let change = |kind: &str,
+                  (device, key): &(String, String),
+                  from: Option<&String>,
+                  to: Option<&String>| DeviceChange {
+        change: kind.to_string(),
+        device: device.clone(),
+        key: key.clone(),
+        from: from.cloned(),
+        to: to.cloned(),
+    };
+    let mut changes = Vec::new();
+    for (key, before) in &from {
+        match to.get(key) {
+            None => changes.push(change("removed", key, Some(before), None)),
+            Some(after) if after != before => {
+                changes.push(change("changed", key, Some(before), Some(after)))
+            }
+            _ => (),
+        }
+    }
+    for (key, after) in &to {
+        if !from.contains_key(key) {
+            changes.push(change("added", key, None, Some(after)));
+        }
+    }
+    changes
+}
+
+fn snapshot_disks<'a, 'i>(snapshot: Node<'a, 'i>) -> Vec<Node<'a, 'i>> {
+    child(snapshot, "disks")
+        .map(|it| it.children().filter(|it| it.has_tag_name("disk")).collect())
+        .unwrap_or_default()
+}
+
+// qemu-img info by image path, so an overlay compared or listed twice is read once
+type ImageCache = HashMap<String, Option<Value>>;
+
+fn cached_info(images: &mut ImageCache, path: String) -> Option<&Value> {
+    images
+        .entry(path.clone())
+        .or_insert_with(|| image_info(&path).ok())
+        .as_ref()
+}
+
+// what a snapshot accounts for on one disk: an external overlay by its allocation.
+// internal snapshots share clusters and qcow2 keeps no allocation per snapshot, so
+// they have no size
+fn disk_bytes(snapshot: Node, target: &str, images: &mut ImageCache) -> (String, Option<u64>) {
+    let disk = snapshot_disks(snapshot)
+        .into_iter()
+        .find(|it| it.attribute("name") == Some(target));
+    let kind = disk
+        .and_then(|it| it.attribute("snapshot"))
+        .unwrap_or("no")
+        .to_string();
+    let bytes = match kind.as_str() {
+        "external" => disk
+            .and_then(|it| value_at(it, &["source"], Some("file")))
+            .and_then(|path| cached_info(images, path))
+            .and_then(|info| info["actual-size"].as_u64()),
+        _ => None,
+    };
+    (kind, bytes)
+}
+
+fn compare_disks(from: Node, to: Node) -> Vec<DiskSizeChange> {
+    let targets: BTreeSet<String> = snapshot_disks(from)
+        .into_iter()
+        .chain(snapshot_disks(to))
+        .filter_map(|it| it.attribute("name").map(String::from))
+        .collect();
+    let mut images = ImageCache::new();
+    targets
+        .into_iter()
+        .map(|target| {
+            let (from_kind, from_bytes) = disk_bytes(from, &target, &mut images);
+            let (to_kind, to_bytes) = disk_bytes(to, &target, &mut images);
+            DiskSizeChange {
+                snapshot: match from_kind == to_kind {
+                    true => from_kind,
+                    false => format!("{}/{}", from_kind, to_kind),
+                },
+                target,
+                from_bytes,
+                to_bytes,
+                delta_bytes: match (from_bytes, to_bytes) {
+                    (Some(from), Some(to)) => Some(to as i64 - from as i64),
+                    _ => None,
+                },
+            }
+        })
+        .filter(|it| it.snapshot != "no")
+        .collect()
+}
+
+pub fn diff_snapshots(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<SnapshotDiff, VirtError> {
+        let config =
+            serde_json::from_str::<SnapshotDiffConfig>(&params[0]).map_err(|_| InvalidInput)?;
+        let dom = lookup_domain(conn, &config.dom_name)?;
+        let from_xml = lookup_snapshot(&dom, &config.dom_name, &config.from)?.get_xml_desc(0)?;
+        let to_xml = lookup_snapshot(&dom, &config.dom_name, &config.to)?.get_xml_desc(0)?;
+        let from_doc = Document::parse(&from_xml).map_err(|e| OtherError(e.to_string()))?;
+        let to_doc = Document::parse(&to_xml).map_err(|e| OtherError(e.to_string()))?;
+        let (from, to) = (from_doc.root_element(), to_doc.root_element());
+        let (from_domain, to_domain) = (child(from, "domain"), child(to, "domain"));
+        Ok(SnapshotDiff {
+            metadata: compare_fields(&METADATA_FIELDS, Some(from), Some(to)),
+            domain: compare_fields(&DOMAIN_FIELDS, from_domain, to_domain),
+            devices: compare_devices(from_domain, to_domain),
+            disks: compare_disks(from, to),
+            dom_name: config.dom_name,
+            from: config.from,
+            to: config.to,
+        })
+    };
+    match res() {
+        Ok(diff) => main_tx
+            .send(VirtResult::Ok(serde_json::to_string(&diff).unwrap()))
+            .unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+fn attach_children(
+    mut node: SnapshotNode,
+    nodes: &mut HashMap<String, SnapshotNode>,
+    children: &HashMap<String, Vec<String>>,
+) -> SnapshotNode {
+    for name in children.get(&node.name).into_iter().flatten() {
+        if let Some(child) = nodes.remove(name) {
+            node.children.push(attach_children(child, nodes, children));
+        }
+    }
+    node
+}
+
+// every snapshot with its metadata, nested under its parent; one xml read each
+pub fn snapshot_tree(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<SnapshotTree, VirtError> {
+        let dom_name = params.first().ok_or(InvalidInput)?;
+        let dom = lookup_domain(conn, dom_name)?;
+        let mut images = ImageCache::new();
+        let mut nodes = HashMap::new();
+        for snapshot in dom.list_all_snapshots(0)? {
+            let xml = snapshot.get_xml_desc(0)?;
+            let doc = Document::parse(&xml).map_err(|e| OtherError(e.to_string()))?;
+            let root = doc.root_element();
+            let text = |tag: &str| value_at(root, &[tag], None).unwrap_or_default();
+            let name = text("name");
+            let disk_sizes: Vec<u64> = snapshot_disks(root)
+                .into_iter()
+                .filter_map(|it| it.attribute("name"))
+                .filter_map(|target| disk_bytes(root, target, &mut images).1)
+                .collect();
+            let node = SnapshotNode {
+                description: text("description"),
+                state: text("state"),
+                creation_time: text("creationTime").parse().unwrap_or(0),
+                is_current: snapshot.is_current(0)?,
+                parent: value_at(root, &["parent", "name"], None),
+                disk_size: (!disk_sizes.is_empty()).then(|| disk_sizes.iter().sum()),
+                has_memory: matches!(
+                    value_at(root, &["memory"], Some("snapshot")).as_deref(),
+                    Some("internal" | "external")
+                ),
+                children: Vec::new(),
+                name,
+            };
+            nodes.insert(node.name.clone(), node);
+        }
+        let mut by_age: Vec<(i64, String, Option<String>)> = nodes
+            .values()
+            .map(|it| (it.creation_time, it.name.clone(), it.parent.clone()))
+            .collect();
+        by_age.sort();
+        let mut roots = Vec::new();
+        let mut children: HashMap<String, Vec<String>> = HashMap::new();
+        for (_, name, parent) in by_age {
+            match parent.filter(|it| nodes.contains_key(it)) {
+                Some(parent) => children.entry(parent).or_default().push(name),
+                None => roots.push(name),
+            }
+        }
+        let current = nodes
+            .values()
+            .find(|it| it.is_current)
+            .map(|it| it.name.clone());
+        let tree = roots
+            .iter()
+            .filter_map(|name| {
+                let node = nodes.remove(name)?;
+                Some(attach_children(node, &mut nodes, &children))
+            })
+            .collect();
+        Ok(SnapshotTree {
+            dom_name: dom_name.clone(),
+            roots,
+            current,
+            tree,
+        })
+    };
+    match res() {
+        Ok(tree) => main_tx
+            .send(VirtResult::Ok(serde_json::to_string(&tree).unwrap()))
+            .unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RevertPreview {
+    dom_name: String,
+    snapshot_name: String,
+    // the domain now, a running one loses its memory state too
+    state: String,
+    running: bool,
+    // what the domain comes back as
+    snapshot_state: String,
+    // everything since the current snapshot is lost unless saved first
+    current_snapshot: Option<String>,
+    current_snapshot_time: Option<i64>,
+    seconds_since_current: Option<i64>,
+    save_current: bool,
+}
+
+pub fn preview_revert(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<RevertPreview, VirtError> {
+        let config = serde_json::from_str::<RevertConfig>(&params[0]).map_err(|_| InvalidInput)?;
+        let dom = lookup_domain(conn, &config.dom_name)?;
+        let snapshot = lookup_snapshot(&dom, &config.dom_name, &config.snapshot_name)?;
+        let xml = snapshot.get_xml_desc(0)?;
+        let doc = Document::parse(&xml).map_err(|e| OtherError(e.to_string()))?;
+        let snapshot_state = value_at(doc.root_element(), &["state"], None).unwrap_or_default();
+        let mut current = None;
+        for snapshot in dom.list_all_snapshots(0)? {
+            if snapshot.is_current(0)? {
+                let xml = snapshot.get_xml_desc(0)?;
+                let doc = Document::parse(&xml).map_err(|e| OtherError(e.to_string()))?;
+                let created = value_at(doc.root_element(), &["creationTime"], None)
+                    .and_then(|it| it.parse::<i64>().ok());
+                current = Some((snapshot.get_name()?, created));
+                break;
+            }
+        }
+        let (state, _) = dom.get_state()?;
+        let current_snapshot_time = current.as_ref().and_then(|it| it.1);
+        Ok(RevertPreview {
+            dom_name: config.dom_name,
+            snapshot_name: config.snapshot_name,
+            state: state_name(state).to_string(),
+            running: dom.is_active()?,
+            snapshot_state,
+            current_snapshot: current.map(|it| it.0),
+            current_snapshot_time,
+            seconds_since_current: current_snapshot_time.map(|it| Utc::now().timestamp() - it),
+            save_current: config.save_current != Some(false),
+        })
+    };
+    match res() {
+        Ok(t) => main_tx
+            .send(VirtResult::Ok(serde_json::to_string(&t).unwrap()))
+            .unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EditedSnapshot {
+    dom_name: String,
+    snapshot_name: String,
+    description: String,
+    parent: Option<String>,
+}
+
+fn parent_name(snapshot: Node) -> Option<String> {
+    value_at(snapshot, &["parent", "name"], None)
+}
+
+// external snapshots sit in the disks' backing chains, their parent follows from
+// the chain and can't be picked
+fn is_external(snapshot: Node) -> bool {
+    value_at(snapshot, &["memory"], Some("snapshot")).as_deref() == Some("external")
+        || snapshot_disks(snapshot)
+            .iter()
+            .any(|it| it.attribute("snapshot") == Some("external"))
+}
+
+// description and parent are redefined through libvirt, the rest of
+// SnapShotEditConfig is kept by the controller
+pub fn edit_snapshot(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<EditedSnapshot, VirtError> {
+        let config =
+            serde_json::from_str::<SnapShotEditConfig>(&params[0]).map_err(|_| InvalidInput)?;
+        let dom = lookup_domain(conn, &config.dom_name)?;
+        let snapshot = lookup_snapshot(&dom, &config.dom_name, &config.snapshot_name)?;
+        // secure so the redefined domain keeps its passwords
+        let mut xml = snapshot.get_xml_desc(VIR_DOMAIN_SNAPSHOT_XML_SECURE)?;
+        let doc = Document::parse(&xml).map_err(|e| OtherError(e.to_string()))?;
+        let parent = config
+            .parent
+            .as_deref()
+            .map(|it| Some(it).filter(|it| !it.is_empty()));
+        if let Some(new_parent) =
+            parent.filter(|it| *it != parent_name(doc.root_element()).as_deref())
+        {
+            if is_external(doc.root_element()) {
+                return Err(InvalidConfig(format!(
+                    "{} is an external snapshot, its parent can't be changed",
+                    config.snapshot_name
+                )));
+            }
+            if new_parent == Some(config.snapshot_name.as_str()) {
+                return Err(InvalidConfig(
+                    "a snapshot can't be its own parent".to_string(),
+                ));
+            }
+            // walking up from the new parent must not come back to this snapshot
+            let mut ancestor = new_parent.map(String::from);
+            while let Some(name) = ancestor {
+                if name == config.snapshot_name {
+                    return Err(InvalidConfig(format!(
+                        "{} is a descendant of {}, it can't become its parent",
+                        new_parent.unwrap(),
+                        config.snapshot_name
+                    )));
+                }
+                let xml = lookup_snapshot(&dom, &config.dom_name, &name)?.get_xml_desc(0)?;
+                let doc = Document::parse(&xml).map_err(|e| OtherError(e.to_string()))?;
+                ancestor = parent_name(doc.root_element());
+            }
+        }
+        if config.description.is_some() || config.parent.is_some() {
+            xml = edit_snapshot_xml(&xml, config.description.as_deref(), parent)
+                .map_err(OtherError)?;
+            let mut flags = VIR_DOMAIN_SNAPSHOT_CREATE_REDEFINE;
+            if snapshot.is_current(0)? {
+                flags |= VIR_DOMAIN_SNAPSHOT_CREATE_CURRENT;
+            }
+            DomainSnapshot::create_xml(&dom, &xml, flags)
+                .map_err(|e| InvalidConfig(e.to_string()))?;
+        }
+        let doc = Document::parse(&xml).map_err(|e| OtherError(e.to_string()))?;
+        Ok(EditedSnapshot {
+            dom_name: config.dom_name,
+            snapshot_name: config.snapshot_name,
+            description: value_at(doc.root_element(), &["description"], None).unwrap_or_default(),
+            parent: parent_name(doc.root_element()),
+        })
+    };
+    match res() {
+        Ok(t) => main_tx
+            .send(VirtResult::Ok(serde_json::to_string(&t).unwrap()))
+            .unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
@@ -0,0 +1,282 @@
+use quick_xml::escape::escape;
+use roxmltree::Document;
+use serde::Serialize;
+use std::sync::mpsc::Sender;
+use virt::{connect::Connect, storage_pool::StoragePool, storage_vol::StorageVol};
+
+use super::disk::MAX_CHAIN_DEPTH;
+use super::shell::image_info;
+use super::utils::parse_size;
+
+use super::VirtError::{self, *};
+use super::{CreateDiskConfig, StoragePoolConfig, VirtResult, DEFAULT_POOL};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PoolInfo {
+    name: String,
+    path: String,
+    is_active: bool,
+    autostart: bool,
+    capacity: u64,
+    allocation: u64,
+    available: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VolumeInfo {
+    name: String,
+    path: String,
+    format: String,
+    capacity: u64,
+    allocation: u64,
+    used_by: Option<String>,
+}
+
+pub fn lookup_pool(conn: &Connect, pool_name: &str) -> Result<StoragePool, VirtError> {
+    StoragePool::lookup_by_name(conn, pool_name).map_err(|_| PoolNotFound(pool_name.to_string()))
+}
+
+fn lookup_volume(pool: &StoragePool, vol_name: &str) -> Result<StorageVol, VirtError> {
+    StorageVol::lookup_by_name(pool, vol_name).map_err(|_| VolumeNotFound {
+        pool_name: pool.get_name().unwrap_or_default(),
+        vol_name: vol_name.to_string(),
+    })
+}
+
+pub fn pool_path(pool: &StoragePool) -> Result<String, VirtError> {
+    let xml = pool.get_xml_desc(0)?;
+    let doc = Document::parse(&xml).expect("XML from LibVirt can't be parsed");
+    Ok(doc
+        .descendants()
+        .find(|it| it.has_tag_name("path"))
+        .and_then(|it| it.text())
+        .unwrap_or("")
+        .to_string())
+}
+
+// name of the first domain with a disk backed by `path`, anywhere in its chain
+pub fn volume_used_by(conn: &Connect, path: &str) -> Result<Option<String>, VirtError> {
+    for dom in conn.list_all_domains(0)? {
+        let xml = dom.get_xml_desc(0)?;
+        let doc = Document::parse(&xml).expect("XML from LibVirt can't be parsed");
+        let in_use = doc
+            .descendants()
+            .filter(|it| it.has_tag_name("disk"))
+            .any(|disk| {
+                // running domains nest the chain in <backingStore>
+                let mut sources = disk
+                    .descendants()
+                    .filter(|it| it.has_tag_name("source"))
+                    .filter_map(|it| it.attribute("file").or(it.attribute("dev")));
+                if sources.any(|it| it == path) {
+                    return true;
+                }
+                if disk.children().any(|it| it.has_tag_name("backingStore")) {
+                    return false;
+                }
+                // inactive ones usually leave it out, the images know it
+                let mut image = disk
+                    .children()
+                    .find(|it| it.has_tag_name("source"))
+                    .and_then(|it| it.attribute("file"))
+                    .map(str::to_string);
+                for _ in 0..MAX_CHAIN_DEPTH {
+                    let Some(backing) =
+                        image.and_then(|it| image_info(&it).ok()).and_then(|info| {
+                            info["full-backing-filename"].as_str().map(str::to_string)
+                        })
+                    else {
+                        break;
+                    };
+                    if backing == path {
+                        return true;
+                    }
+                    image = Some(backing);
+                }
+                false
+            });
+        if in_use {
+            return Ok(Some(dom.get_name()?));
+        }
+    }
+    Ok(None)
+}
+
+pub fn list_pools(conn: &Connect, main_tx: &Sender<VirtResult>) {
+    let res = || -> Result<Vec<PoolInfo>, VirtError> {
+        conn.list_all_storage_pools(0)?
+            .iter()
+            .map(|pool| {
+                let info = pool.get_info()?;
+                Ok(PoolInfo {
+                    name: pool.get_name()?,
+                    path: pool_path(pool)?,
+                    is_active: pool.is_active()?,
+                    autostart: pool.get_autostart()?,
+                    capacity: info.capacity,
+                    allocation: info.allocation,
+                    available: info.available,
+                })
+            })
+            .collect()
+    };
+    match res() {
+        Ok(t) => main_tx
+            .send(VirtResult::Ok(serde_json::to_string(&t).unwrap()))
+            .unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn create_pool(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<String, VirtError> {
+        let config =
+            serde_json::from_str::<StoragePoolConfig>(&params[0]).map_err(|_| InvalidInput)?;
+        let path = config
+            .path
+            .ok_or(InvalidConfig("path is required".to_string()))?;
+        if !path.starts_with('/') {
+            return Err(InvalidConfig("path must be absolute".to_string()));
+        }
+        if StoragePool::lookup_by_name(conn, &config.name).is_ok() {
+            return Err(InvalidConfig(format!(
+                "pool {} already exists",
+                config.name
+            )));
+        }
+        let xml = format!(
+            "<pool type='dir'><name>{}</name><target><path>{}</path></target></pool>",
+            escape(&config.name),
+            escape(&path)
+        );
+        let pool = StoragePool::define_xml(conn, &xml, 0)?;
+        // build creates the target directory when it is missing; a pool that
+        // can't start isn't left defined
+        if let Err(e) = pool.build(0).and_then(|_| pool.create(0)) {
+            let _ = pool.undefine();
+            return Err(e.into());
+        }
+        pool.set_autostart(true)?;
+        Ok("Create pool successfully".to_string())
+    };
+    match res() {
+        Ok(output) => main_tx.send(VirtResult::Ok(output)).unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn refresh_pool(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<String, VirtError> {
+        lookup_pool(conn, &params[0])?.refresh(0)?;
+        Ok("Refresh pool successfully".to_string())
+    };
+    match res() {
+        Ok(output) => main_tx.send(VirtResult::Ok(output)).unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn get_pool_path(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    match lookup_pool(conn, &params[0]).and_then(|pool| pool_path(&pool)) {
+        Ok(path) => main_tx.send(VirtResult::Ok(path)).unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn list_volumes(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<Vec<VolumeInfo>, VirtError> {
+        lookup_pool(conn, &params[0])?
+            .list_all_volumes(0)?
+            .iter()
+            .map(|vol| {
+                let info = vol.get_info()?;
+                let xml = vol.get_xml_desc(0)?;
+                let doc = Document::parse(&xml).expect("XML from LibVirt can't be parsed");
+                let path = vol.get_path()?;
+                Ok(VolumeInfo {
+                    name: vol.get_name()?,
+                    format: doc
+                        .descendants()
+                        .find(|it| it.has_tag_name("format"))
+                        .and_then(|it| it.attribute("type"))
+                        .unwrap_or("")
+                        .to_string(),
+                    capacity: info.capacity,
+                    allocation: info.allocation,
+                    used_by: volume_used_by(conn, &path)?,
+                    path,
+                })
+            })
+            .collect()
+    };
+    match res() {
+        Ok(t) => main_tx
+            .send(VirtResult::Ok(serde_json::to_string(&t).unwrap()))
+            .unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn create_volume(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<String, VirtError> {
+        let config =
+            serde_json::from_str::<CreateDiskConfig>(&params[0]).map_err(|_| InvalidInput)?;
+        let format = config.format.unwrap_or("qcow2".to_string());
+        if format != "qcow2" && format != "raw" {
+            return Err(InvalidConfig(format!("unsupported disk format {}", format)));
+        }
+        if config.name.is_empty() || config.name.contains('/') || config.name.starts_with('.') {
+            return Err(InvalidConfig(format!("invalid disk name {}", config.name)));
+        }
+        let size = parse_size(&config.size)
+            .ok_or(InvalidConfig(format!("invalid disk size {}", config.size)))?;
+        let pool = lookup_pool(conn, config.pool.as_deref().unwrap_or(DEFAULT_POOL))?;
+        let name = format!("{}.{}", config.name, format);
+        if StorageVol::lookup_by_name(&pool, &name).is_ok() {
+            return Err(InvalidConfig(format!("volume {} already exists", name)));
+        }
+        let xml = format!(
+            "<volume><name>{}</name><capacity unit='bytes'>{}</capacity><target><format type='{}'/></target></volume>",
+            escape(&name),
+            size,
+            format
+        );
+        Ok(StorageVol::create_xml(&pool, &xml, 0)?.get_path()?)
+    };
+    match res() {
+        Ok(path) => main_tx.send(VirtResult::Ok(path)).unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn get_volume_path(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<String, VirtError> {
+        let pool = lookup_pool(conn, &params[0])?;
+        Ok(lookup_volume(&pool, &params[1])?.get_path()?)
+    };
+    match res() {
+        Ok(path) => main_tx.send(VirtResult::Ok(path)).unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
+
+pub fn delete_volume(conn: &Connect, main_tx: &Sender<VirtResult>, params: &[String]) {
+    let res = || -> Result<String, VirtError> {
+        let pool = lookup_pool(conn, &params[0])?;
+        let vol = lookup_volume(&pool, &params[1])?;
+        if let Some(dom_name) = volume_used_by(conn, &vol.get_path()?)? {
+            return Err(InvalidConfig(format!(
+                "volume {} is attached to domain {}",
+                params[1], dom_name
+            )));
+        }
+        vol.delete(0)?;
+        Ok("Delete volume successfully".to_string())
+    };
+    match res() {
+        Ok(output) => main_tx.send(VirtResult::Ok(output)).unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
+    }
+}
@@ -1,32 +1,209 @@
-use super::VirtResult;
+use super::storage::{lookup_pool, pool_path};
+use super::VirtError;
+use super::{VirtResult, DEFAULT_POOL};
+use serde::Serialize;
 use std::{
-    collections::HashMap,
     sync::mpsc::Sender,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
-use sysinfo::System;
-
-pub fn get_sysinfo(main_tx: &Sender<VirtResult>, sys: &mut System) {
-    sys.refresh_cpu();
-    sys.refresh_memory();
-
-    let mut t = HashMap::new();
-
-    let now = SystemTime::now();
-    let unix_timestamp = now.duration_since(UNIX_EPOCH).unwrap().as_millis();
-    t.insert("timestamp", unix_timestamp.to_string());
-    t.insert("total memory", sys.total_memory().to_string());
-    t.insert("used memory", sys.used_memory().to_string());
-    t.insert("total swap", sys.total_swap().to_string());
-    t.insert("used swap", sys.used_swap().to_string());
-    t.insert("cpu number", sys.cpus().len().to_string());
-    let mut cpu_usage = HashMap::new();
-    let cpus = sys.cpus();
-    for i in 0..sys.cpus().len() {
-        cpu_usage.insert(i, cpus[i].cpu_usage());
+use sysinfo::{Components, Disks, Networks, System};
+use virt::connect::Connect;
+
+const TOP_PROCESSES: usize = 5;
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LoadAverage {
+    pub one: f64,
+    pub five: f64,
+    pub fifteen: f64,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FilesystemStatus {
+    pub mount_point: String,
+    pub device: String,
+    pub file_system: String,
+    pub total_bytes: u64,
+    pub available_bytes: u64,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct NicStatus {
+    pub name: String,
+    pub rx_bps: f64,
+    pub tx_bps: f64,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Temperature {
+    pub label: String,
+    pub celsius: f32,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ProcessStatus {
+    pub pid: u32,
+    pub name: String,
+    pub cpu_usage: f32,
+    pub memory_bytes: u64,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HostStatus {
+    // unix millis
+    pub timestamp: u128,
+    pub uptime: u64,
+    pub load_average: LoadAverage,
+    pub cpu_usage: Vec<f32>,
+    pub total_memory: u64,
+    pub used_memory: u64,
+    pub total_swap: u64,
+    pub used_swap: u64,
+    pub filesystems: Vec<FilesystemStatus>,
+    pub networks: Vec<NicStatus>,
+    // empty when the host exposes no sensors
+    pub temperatures: Vec<Temperature>,
+    pub process_count: usize,
+    // busiest processes by cpu
+    pub top_processes: Vec<ProcessStatus>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HostVersions {
+    libvirt_version: String,
+    qemu_version: String,
+    // where the default pool keeps its volumes
+    storage_path: Option<String>,
+}
+
+// keeps the sysinfo handles around, throughput and cpu usage are measured
+// between two calls of `sample`
+pub struct HostMonitor {
+    sys: System,
+    disks: Disks,
+    networks: Networks,
+    components: Components,
+    last_sample: Instant,
+}
+
+impl HostMonitor {
+    pub fn new() -> Self {
+        HostMonitor {
+            sys: System::new(),
+            disks: Disks::new_with_refreshed_list(),
+            networks: Networks::new_with_refreshed_list(),
+            components: Components::new_with_refreshed_list(),
+            last_sample: Instant::now(),
+        }
+    }
+
+    pub fn sample(&mut self) -> HostStatus {
+        self.sys.refresh_cpu();
+        self.sys.refresh_memory();
+        self.sys.refresh_processes();
+        self.disks.refresh_list();
+        self.networks.refresh();
+        self.components.refresh();
+        let secs = self.last_sample.elapsed().as_secs_f64().max(1.0);
+        self.last_sample = Instant::now();
+
+        let load = System::load_average();
+        let mut processes: Vec<ProcessStatus> = self
+            .sys
+            .processes()
+            .iter()
+            .map(|(pid, process)| ProcessStatus {
+                pid: pid.as_u32(),
+                name: process.name().to_string(),
+                cpu_usage: process.cpu_usage(),
+                memory_bytes: process.memory(),
+            })
+            .collect();
+        let process_count = processes.len();
+        processes.sort_by(|a, b| b.cpu_usage.total_cmp(&a.cpu_usage));
+        processes.truncate(TOP_PROCESSES);
+
+        HostStatus {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis(),
+            uptime: System::uptime(),
+            load_average: LoadAverage {
+                one: load.one,
+                five: load.five,
+                fifteen: load.fifteen,
+            },
+            cpu_usage: self.sys.cpus().iter().map(|it| it.cpu_usage()).collect(),
+            total_memory: self.sys.total_memory(),
+            used_memory: self.sys.used_memory(),
+            total_swap: self.sys.total_swap(),
+            used_swap: self.sys.used_swap(),
+            filesystems: self
+                .disks
+                .iter()
+                .map(|disk| FilesystemStatus {
+                    mount_point: disk.mount_point().to_string_lossy().to_string(),
+                    device: disk.name().to_string_lossy().to_string(),
+                    file_system: disk.file_system().to_string_lossy().to_string(),
+                    total_bytes: disk.total_space(),
+                    available_bytes: disk.available_space(),
+                })
+                .collect(),
+            networks: self
+                .networks
+                .iter()
+                .map(|(name, data)| NicStatus {
+                    name: name.to_string(),
+                    rx_bps: data.received() as f64 / secs,
+                    tx_bps: data.transmitted() as f64 / secs,
+                })
+                .collect(),
+            temperatures: self
+                .components
+                .iter()
+                .map(|it| Temperature {
+                    label: it.label().to_string(),
+                    celsius: it.temperature(),
+                })
+                .collect(),
+            process_count,
+            top_processes: processes,
+        }
+    }
+}
+
+// libvirt packs versions as major * 1,000,000 + minor * 1,000 + release
+fn format_version(version: u32) -> String {
+    format!(
+        "{}.{}.{}",
+        version / 1_000_000,
+        version / 1_000 % 1_000,
+        version % 1_000
+    )
+}
+
+pub fn get_host_versions(conn: &Connect, main_tx: &Sender<VirtResult>) {
+    let res = || -> Result<HostVersions, VirtError> {
+        Ok(HostVersions {
+            libvirt_version: format_version(conn.get_lib_version()?),
+            qemu_version: format_version(conn.get_hyp_version()?),
+            storage_path: lookup_pool(conn, DEFAULT_POOL)
+                .and_then(|pool| pool_path(&pool))
+                .ok(),
+        })
+    };
+    match res() {
+        Ok(t) => main_tx
+            .send(VirtResult::Ok(serde_json::to_string(&t).unwrap()))
+            .unwrap(),
+        Err(e) => main_tx.send(VirtResult::Err(e)).unwrap(),
     }
-    t.insert("cpu usage", serde_json::to_string(&cpu_usage).unwrap());
-    main_tx
-        .send(VirtResult::Ok(serde_json::to_string(&t).unwrap()))
-        .unwrap();
 }
@@ -1,23 +1,28 @@
-use std::io::Cursor;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Cursor, Read},
+    path::Path,
+};
 
-use data_encoding::HEXUPPER;
+use data_encoding::HEXLOWER;
 use quick_xml::{
     events::{BytesEnd, BytesStart, BytesText, Event},
     Reader, Writer,
 };
 use ring::digest::{Context, SHA256};
 pub fn edit_xml_text(
-    input: &String,
+    input: &str,
     target_element: &str,
     new_text: &str,
     defined_depth: i64,
 ) -> String {
-    let mut reader = Reader::from_str(&input);
+    let mut reader = Reader::from_str(input);
     reader.trim_text(true);
     let mut writer = Writer::new_with_indent(Cursor::new(Vec::new()), b' ', 2);
     let mut in_target_element = false;
     let mut is_exist = false;
-    let mut depth = 0 as i64;
+    let mut depth = 0_i64;
     loop {
         match reader.read_event() {
             Ok(Event::Start(e)) => {
@@ -62,8 +67,244 @@ pub fn edit_xml_text(
     String::from_utf8(result).unwrap()
 }
 
-pub fn sha256_hash(str: &str) -> String {
+// lowercase hex like sha256sum prints, so archives can be checked by hand
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
     let mut context = Context::new(&SHA256);
-    context.update(str.as_bytes());
-    HEXUPPER.encode(context.finish().as_ref())
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        context.update(&buf[..n]);
+    }
+    Ok(HEXLOWER.encode(context.finish().as_ref()))
+}
+
+fn relocate_source(e: &BytesStart, sources: &HashMap<String, String>) -> BytesStart<'static> {
+    let mut elem = BytesStart::new(String::from_utf8_lossy(e.name().as_ref()).into_owned());
+    for attr in e.attributes().filter_map(|it| it.ok()) {
+        let key = String::from_utf8_lossy(attr.key.as_ref()).into_owned();
+        let value = attr.unescape_value().unwrap_or_default().into_owned();
+        let value = match key.as_str() {
+            "file" => sources.get(&value).cloned().unwrap_or(value),
+            _ => value,
+        };
+        elem.push_attribute((key.as_str(), value.as_str()));
+    }
+    elem
+}
+
+// moves a domain definition, or the one embedded in a snapshot, to another name:
+// the domain's <name> and <uuid> are replaced (the uuid dropped when there's none
+// yet), disk sources found in `sources` point to their new files, and interface
+// macs are dropped when asked so libvirt hands out fresh ones
+pub fn relocate_domain_xml(
+    input: &str,
+    name: &str,
+    uuid: Option<&str>,
+    sources: &HashMap<String, String>,
+    drop_macs: bool,
+) -> Result<String, String> {
+    let mut reader = Reader::from_str(input);
+    reader.trim_text(true);
+    let mut writer = Writer::new_with_indent(Cursor::new(Vec::new()), b' ', 2);
+    let mut path: Vec<Vec<u8>> = Vec::new();
+    let mut skip_depth = 0_i64;
+    let in_domain = |path: &[Vec<u8>]| path.last().is_some_and(|it| it == b"domain");
+    loop {
+        let event = reader.read_event().map_err(|e| e.to_string())?;
+        if skip_depth > 0 {
+            match event {
+                Event::Start(_) => skip_depth += 1,
+                Event::End(_) => skip_depth -= 1,
+                _ => (),
+            }
+            continue;
+        }
+        match event {
+            Event::Start(e) => {
+                if uuid.is_none() && e.name().as_ref() == b"uuid" && in_domain(&path) {
+                    skip_depth = 1;
+                    continue;
+                }
+                path.push(e.name().as_ref().to_vec());
+                match e.name().as_ref() {
+                    b"source" => writer.write_event(Event::Start(relocate_source(&e, sources))),
+                    _ => writer.write_event(Event::Start(e.to_owned())),
+                }
+                .unwrap();
+            }
+            Event::End(e) => {
+                path.pop();
+                writer.write_event(Event::End(e.to_owned())).unwrap();
+            }
+            Event::Empty(e) => {
+                let parent = path.last().map(|it| it.as_slice());
+                match e.name().as_ref() {
+                    b"mac" if drop_macs && parent == Some(b"interface") => continue,
+                    b"source" => writer.write_event(Event::Empty(relocate_source(&e, sources))),
+                    _ => writer.write_event(Event::Empty(e.to_owned())),
+                }
+                .unwrap();
+            }
+            Event::Text(e) => {
+                let (tag, parent) = match path.len() {
+                    0 | 1 => (None, None),
+                    n => (Some(path[n - 1].as_slice()), Some(path[n - 2].as_slice())),
+                };
+                let text = match (tag, parent, uuid) {
+                    (Some(b"name"), Some(b"domain"), _) => BytesText::new(name),
+                    (Some(b"uuid"), Some(b"domain"), Some(uuid)) => BytesText::new(uuid),
+                    _ => e.to_owned(),
+                };
+                writer.write_event(Event::Text(text)).unwrap();
+            }
+            Event::Eof => break,
+            e => writer.write_event(e).unwrap(),
+        }
+    }
+    Ok(String::from_utf8(writer.into_inner().into_inner()).unwrap())
+}
+
+// rewrites the snapshot's own <description> and <parent>, the captured domain is
+// left alone. None keeps an element as it is; for the parent, Some(None) drops it
+// and the snapshot becomes a root
+pub fn edit_snapshot_xml(
+    input: &str,
+    description: Option<&str>,
+    parent: Option<Option<&str>>,
+) -> Result<String, String> {
+    let mut reader = Reader::from_str(input);
+    reader.trim_text(true);
+    let mut writer = Writer::new_with_indent(Cursor::new(Vec::new()), b' ', 2);
+    let mut depth = 0_i64;
+    let mut skip_depth = 0_i64;
+    let replaced = |name: &[u8]| match name {
+        b"description" => description.is_some(),
+        b"parent" => parent.is_some(),
+        _ => false,
+    };
+    let write_text = |writer: &mut Writer<Cursor<Vec<u8>>>, tag: &str, text: &str| {
+        writer
+            .write_event(Event::Start(BytesStart::new(tag)))
+            .unwrap();
+        writer
+            .write_event(Event::Text(BytesText::new(text)))
+            .unwrap();
+        writer.write_event(Event::End(BytesEnd::new(tag))).unwrap();
+    };
+    loop {
+        let event = reader.read_event().map_err(|e| e.to_string())?;
+        if skip_depth > 0 {
+            match event {
+                Event::Start(_) => skip_depth += 1,
+                Event::End(_) => skip_depth -= 1,
+                _ => (),
+            }
+            continue;
+        }
+        match event {
+            Event::Start(e) => {
+                if depth == 1 && replaced(e.name().as_ref()) {
+                    skip_depth = 1;
+                    continue;
+                }
+                depth += 1;
+                writer.write_event(Event::Start(e.to_owned())).unwrap();
+            }
+            Event::Empty(e) if depth == 1 && replaced(e.name().as_ref()) => (),
+            Event::End(e) => {
+                depth -= 1;
+                if depth == 0 {
+                    if let Some(description) = description {
+                        write_text(&mut writer, "description", description);
+                    }
+                    if let Some(Some(parent)) = parent {
+                        writer
+                            .write_event(Event::Start(BytesStart::new("parent")))
+                            .unwrap();
+                        write_text(&mut writer, "name", parent);
+                        writer
+                            .write_event(Event::End(BytesEnd::new("parent")))
+                            .unwrap();
+                    }
+                }
+                writer.write_event(Event::End(e.to_owned())).unwrap();
+            }
+            Event::Eof => break,
+            e => writer.write_event(e).unwrap(),
+        }
+    }
+    Ok(String::from_utf8(writer.into_inner().into_inner()).unwrap())
+}
+
+pub fn replace_boot_devices(input: &str, devices: &[String]) -> String {
+    // drop every <boot> element (os level and per-device `order` entries, libvirt
+    // refuses to mix them) and write the new order right before </os>
+    let mut reader = Reader::from_str(input);
+    reader.trim_text(true);
+    let mut writer = Writer::new_with_indent(Cursor::new(Vec::new()), b' ', 2);
+    let mut skip_depth = 0_i64;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) => {
+                if skip_depth > 0 || e.name().as_ref() == b"boot" {
+                    skip_depth += 1;
+                    continue;
+                }
+                writer.write_event(Event::Start(e.to_owned())).unwrap();
+            }
+            Ok(Event::End(e)) => {
+                if skip_depth > 0 {
+                    skip_depth -= 1;
+                    continue;
+                }
+                if e.name().as_ref() == b"os" {
+                    for dev in devices {
+                        let mut elem = BytesStart::new("boot");
+                        elem.push_attribute(("dev", dev.as_str()));
+                        writer.write_event(Event::Empty(elem)).unwrap();
+                    }
+                }
+                writer.write_event(Event::End(e.to_owned())).unwrap();
+            }
+            Ok(Event::Empty(e)) => {
+                if skip_depth > 0 || e.name().as_ref() == b"boot" {
+                    continue;
+                }
+                writer.write_event(Event::Empty(e.to_owned())).unwrap();
+            }
+            Ok(Event::Eof) => break,
+            Ok(e) => {
+                if skip_depth == 0 {
+                    writer.write_event(e).unwrap();
+                }
+            }
+            Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
+        }
+    }
+
+    let result = writer.into_inner().into_inner();
+    String::from_utf8(result).unwrap()
+}
+
+// parse sizes like "20G", "512MiB" or "1073741824" into bytes
+pub fn parse_size(input: &str) -> Option<u64> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (num, unit) = input.split_at(split);
+    let num = num.parse::<u64>().ok()?;
+    let shift = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return None,
+    };
+    num.checked_mul(1u64 << shift)
 }